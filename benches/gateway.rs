@@ -0,0 +1,109 @@
+//! The hot paths of the request handling, measured one by one. The whole
+//! pipeline is measured with `xorc-gateway bench load`.
+
+#[macro_use] extern crate criterion;
+#[macro_use] extern crate serde_json;
+extern crate xorc_gateway;
+extern crate http;
+extern crate prost;
+extern crate ring;
+
+use criterion::Criterion;
+use http::HeaderMap;
+use prost::Message;
+use ring::{digest, hmac};
+use xorc_gateway::{
+    context::Context,
+    encryption::{Ciphertext, Cleartext},
+    events::input::{Platform, SDKEventBatch},
+};
+
+const APP_ID: &'static str = "22222222-0000-0000-0000-000000000000";
+const DEVICE_ID: &'static str = "8f7f5c07-5eb2-4695-870c-065d886cdc9e";
+const SECRET: [u8; 32] = [7; 32];
+
+/// A batch of ten custom events, like the SDKs send.
+fn payload() -> Vec<u8> {
+    let events: Vec<_> = (0..10).map(|i| json!({
+        "id": format!("{}", i + 1),
+        "timestamp": format!("{}", 1527092525607u64 + i),
+        "name": "view_item",
+        "session_id": "3a2b9d4e-0c1f-4a6b-8e7d-5f3c2b1a0e9d",
+        "properties": {
+            "price": 9.99,
+            "currency": "EUR",
+            "item": { "id": format!("item-{}", i), "in_stock": true },
+            "tags": ["sale", "summer"],
+        },
+    })).collect();
+
+    json!({
+        "environment": {
+            "app_id": APP_ID,
+            "sdk_version": "1.0.0",
+            "app_version": "2.3.1",
+        },
+        "device": {
+            "platform": "ios",
+            "ifa_tracking_enabled": true,
+            "manufacturer": "Apple",
+            "model": "iPhone10,6",
+            "os_name": "iOS",
+            "os_version": "12.0",
+            "locale": "fi_FI",
+            "time_zone": "Europe/Helsinki",
+        },
+        "events": events,
+    }).to_string().into_bytes()
+}
+
+fn json_parse(c: &mut Criterion) {
+    let payload = payload();
+
+    c.bench_function("json_parse", move |b| {
+        b.iter(|| serde_json::from_slice::<SDKEventBatch>(&payload).unwrap())
+    });
+}
+
+fn into_proto(c: &mut Criterion) {
+    let payload = payload();
+
+    c.bench_function("into_proto", move |b| {
+        let context = Context::new(&HeaderMap::new(), APP_ID, Platform::Ios);
+
+        b.iter_with_setup(
+            || serde_json::from_slice::<SDKEventBatch>(&payload).unwrap(),
+            |event| {
+                let mut buf = Vec::new();
+                event.into_proto(&context).encode(&mut buf).unwrap();
+                buf
+            },
+        )
+    });
+}
+
+fn hmac_verify(c: &mut Criterion) {
+    let payload = payload();
+    let signing_key = hmac::SigningKey::new(&digest::SHA512, &SECRET);
+    let signature = hmac::sign(&signing_key, &payload).as_ref().to_vec();
+
+    c.bench_function("hmac_verify", move |b| {
+        let key = hmac::VerificationKey::new(&digest::SHA512, &SECRET);
+        b.iter(|| hmac::verify(&key, &payload, &signature).is_ok())
+    });
+}
+
+fn device_id(c: &mut Criterion) {
+    c.bench_function("device_id_encrypt", |b| {
+        let cleartext = Cleartext::from(DEVICE_ID);
+        b.iter(|| Ciphertext::encrypt(&cleartext))
+    });
+
+    c.bench_function("device_id_decrypt", |b| {
+        let ciphertext = Ciphertext::encrypt(&Cleartext::from(DEVICE_ID));
+        b.iter(|| Cleartext::decrypt(&ciphertext).unwrap())
+    });
+}
+
+criterion_group!(benches, json_parse, into_proto, hmac_verify, device_id);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+//! `XORC-Device-Id` values, both as given and base64 encoded to get past
+//! the decoding into the nonce and AEAD handling.
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate xorc_gateway;
+extern crate base64;
+
+use std::str;
+use xorc_gateway::encryption::{Ciphertext, Cleartext};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(s) = str::from_utf8(data) {
+        let _ = Cleartext::decrypt(&Ciphertext::from(s));
+    }
+
+    let _ = Cleartext::decrypt(&Ciphertext::from(base64::encode(data)));
+});
@@ -0,0 +1,33 @@
+//! Request headers for `Context::new`, one `name: value` per line.
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate xorc_gateway;
+extern crate http;
+
+use http::{HeaderMap, header::{HeaderName, HeaderValue}};
+use xorc_gateway::{context::Context, events::input::Platform};
+
+fuzz_target!(|data: &[u8]| {
+    let mut headers = HeaderMap::new();
+
+    for line in data.split(|b| *b == b'\n') {
+        let mut parts = line.splitn(2, |b| *b == b':');
+
+        let name = parts.next().and_then(|n| HeaderName::from_bytes(n).ok());
+        let value = parts.next().and_then(|v| HeaderValue::from_bytes(trim(v)).ok());
+
+        if let (Some(name), Some(value)) = (name, value) {
+            headers.append(name, value);
+        }
+    }
+
+    let _ = Context::new(&headers, "fuzz", Platform::Ios);
+});
+
+fn trim(value: &[u8]) -> &[u8] {
+    match value.first() {
+        Some(b' ') => &value[1..],
+        _ => value,
+    }
+}
@@ -0,0 +1,31 @@
+//! Arbitrary JSON objects as event properties, flattened into the
+//! `SdkEventData` properties.
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate xorc_gateway;
+extern crate serde_json;
+
+use serde_json::Value;
+use xorc_gateway::events::{input::SDKEvent, output::events::SdkEventData};
+
+fuzz_target!(|data: &[u8]| {
+    let properties = match serde_json::from_slice::<Value>(data) {
+        Ok(properties @ Value::Object(_)) => properties,
+        _ => return,
+    };
+
+    let event = json_event(properties);
+    let event: SDKEvent = serde_json::from_value(event).unwrap();
+    let _: SdkEventData = event.into();
+});
+
+fn json_event(properties: Value) -> Value {
+    let mut event = serde_json::Map::new();
+
+    event.insert("timestamp".to_string(), Value::from("1527092525607"));
+    event.insert("name".to_string(), Value::from("fuzz"));
+    event.insert("properties".to_string(), properties);
+
+    Value::Object(event)
+}
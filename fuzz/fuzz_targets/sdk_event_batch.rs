@@ -0,0 +1,27 @@
+//! Raw request bodies through the same parsing and conversion as
+//! `Gateway::handle_sdk`.
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate xorc_gateway;
+extern crate http;
+extern crate prost;
+extern crate serde_json;
+
+use http::HeaderMap;
+use prost::Message;
+use xorc_gateway::{
+    context::Context,
+    events::input::SDKEventBatch,
+};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(batch) = serde_json::from_slice::<SDKEventBatch>(data) {
+        let platform = batch.device.platform();
+        let context = Context::new(&HeaderMap::new(), "fuzz", platform);
+        let proto = batch.into_proto(&context);
+
+        let mut buf = Vec::new();
+        proto.encode(&mut buf).unwrap();
+    }
+});
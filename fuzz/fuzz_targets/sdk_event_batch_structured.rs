@@ -0,0 +1,184 @@
+//! Builds valid-ish event batches from the fuzzer input, so most of the runs
+//! get past the JSON parsing: the known fields with plausible, missing or
+//! wrongly typed values, and nested properties.
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate xorc_gateway;
+extern crate http;
+extern crate prost;
+extern crate serde_json;
+
+use http::HeaderMap;
+use prost::Message;
+use serde_json::{Map, Value};
+use xorc_gateway::{
+    context::Context,
+    events::input::SDKEventBatch,
+};
+
+const PLATFORMS: [&'static str; 5] = ["ios", "android", "web", "symbian", ""];
+const OS_NAMES: [&'static str; 4] = ["iOS", "iPhone OS", "Android", "Linux"];
+const EVENT_NAMES: [&'static str; 4] = ["xorc_register", "view_item", "purchase", ""];
+const LOCALES: [&'static str; 5] = ["fi_FI", "en", "_", "ä_Ö", ""];
+const TIMESTAMPS: [&'static str; 5] = ["1527092525607", "0", "-1", "18446744073709551616", "kulli"];
+
+/// Reads decisions from the fuzzer input, zeroes when it runs out.
+struct Input<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Input<'a> {
+    fn byte(&mut self) -> u8 {
+        match self.data.split_first() {
+            Some((b, rest)) => {
+                self.data = rest;
+                *b
+            },
+            None => 0,
+        }
+    }
+
+    fn bool(&mut self) -> bool {
+        self.byte() & 1 == 1
+    }
+
+    fn pick<T: Copy>(&mut self, values: &[T]) -> T {
+        values[self.byte() as usize % values.len()]
+    }
+
+    fn string(&mut self) -> String {
+        let len = self.byte() as usize % 16;
+        let bytes: Vec<u8> = (0..len).map(|_| self.byte()).collect();
+
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Mostly the expected value, sometimes missing or of the wrong type.
+    fn field(&mut self, map: &mut Map<String, Value>, key: &str, value: Value) {
+        match self.byte() % 8 {
+            0 => (),
+            1 => { map.insert(key.to_string(), Value::Null); },
+            2 => { map.insert(key.to_string(), self.scalar()); },
+            _ => { map.insert(key.to_string(), value); },
+        }
+    }
+
+    fn scalar(&mut self) -> Value {
+        match self.byte() % 5 {
+            0 => Value::Null,
+            1 => Value::from(self.bool()),
+            2 => Value::from(self.byte() as i64 - 128),
+            3 => Value::from(f64::from(self.byte()) / 7.0),
+            _ => Value::from(self.string()),
+        }
+    }
+
+    fn properties(&mut self, depth: usize) -> Value {
+        let mut map = Map::new();
+
+        for _ in 0..self.byte() % 4 {
+            let key = self.string();
+
+            let value = if depth > 0 && self.byte() % 4 == 0 {
+                self.properties(depth - 1)
+            } else if self.byte() % 8 == 0 {
+                Value::Array(vec![self.scalar()])
+            } else {
+                self.scalar()
+            };
+
+            map.insert(key, value);
+        }
+
+        Value::Object(map)
+    }
+
+    fn device(&mut self) -> Value {
+        let mut device = Map::new();
+
+        let platform = self.pick(&PLATFORMS);
+        let os_name = self.pick(&OS_NAMES);
+        let locale = self.pick(&LOCALES);
+        let h = self.byte() as i64 * 10;
+        let ifa = self.string();
+        let tracking = self.bool();
+
+        self.field(&mut device, "platform", Value::from(platform));
+        self.field(&mut device, "os_name", Value::from(os_name));
+        self.field(&mut device, "locale", Value::from(locale));
+        self.field(&mut device, "h", Value::from(h));
+        self.field(&mut device, "ifa", Value::from(ifa));
+        self.field(&mut device, "ifa_tracking_enabled", Value::from(tracking));
+
+        Value::Object(device)
+    }
+
+    fn environment(&mut self) -> Value {
+        let mut environment = Map::new();
+
+        let app_id = self.string();
+        let sdk_version = self.string();
+
+        self.field(&mut environment, "app_id", Value::from(app_id));
+        self.field(&mut environment, "sdk_version", Value::from(sdk_version));
+
+        Value::Object(environment)
+    }
+
+    fn event(&mut self) -> Value {
+        let mut event = Map::new();
+
+        let id = self.string();
+        let timestamp = self.pick(&TIMESTAMPS);
+        let name = self.pick(&EVENT_NAMES);
+        let properties = self.properties(4);
+
+        self.field(&mut event, "id", Value::from(id));
+        self.field(&mut event, "timestamp", Value::from(timestamp));
+        self.field(&mut event, "name", Value::from(name));
+        self.field(&mut event, "properties", properties);
+
+        Value::Object(event)
+    }
+
+    fn consent(&mut self) -> Value {
+        let mut consent = Map::new();
+
+        for key in &["analytics", "advertising", "personalization"] {
+            let given = self.bool();
+            self.field(&mut consent, key, Value::from(given));
+        }
+
+        Value::Object(consent)
+    }
+
+    fn batch(&mut self) -> Value {
+        let mut batch = Map::new();
+
+        let environment = self.environment();
+        let device = self.device();
+        let events = (0..self.byte() % 8).map(|_| self.event()).collect();
+        let consent = self.consent();
+
+        batch.insert("environment".to_string(), environment);
+        batch.insert("device".to_string(), device);
+        batch.insert("events".to_string(), Value::Array(events));
+        self.field(&mut batch, "consent", consent);
+
+        Value::Object(batch)
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let json = Input { data }.batch().to_string();
+
+    if let Ok(batch) = serde_json::from_str::<SDKEventBatch>(&json) {
+        let platform = batch.device.platform();
+        let context = Context::new(&HeaderMap::new(), "fuzz", platform);
+        let proto = batch.into_proto(&context);
+
+        let mut buf = Vec::new();
+        proto.encode(&mut buf).unwrap();
+    }
+});
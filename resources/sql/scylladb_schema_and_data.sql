@@ -10,6 +10,16 @@ CREATE TABLE gw_known_ifas (
   PRIMARY KEY (app_id, ifa)
 ) WITH comment='xorc gateway IFA matching';
 
+CREATE INDEX gw_known_ifas_entity_id ON gw_known_ifas (entity_id);
+
+CREATE TABLE gw_erased_identities (
+  app_id uuid,
+  kind text,
+  identifier text,
+  erased_at timestamp,
+  PRIMARY KEY (app_id, kind, identifier)
+) WITH comment='xorc gateway tombstones for erased device identities';
+
 CREATE TABLE gw_application_access (
   app_id uuid,
   sdk_token text,
@@ -19,6 +29,14 @@ CREATE TABLE gw_application_access (
   PRIMARY KEY (app_id)
 ) WITH comment='xorc gateway application access tokens';
 
+CREATE TABLE gw_application_origins (
+  app_id uuid,
+  allowed_origins text,
+  allowed_methods text,
+  allowed_headers text,
+  PRIMARY KEY (app_id)
+) WITH comment='xorc gateway allowed CORS origins, comma-separated';
+
 INSERT INTO gw_application_access (
   app_id,
   sdk_token,
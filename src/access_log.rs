@@ -0,0 +1,321 @@
+use std::{
+    fs::OpenOptions,
+    io,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use http::header;
+use hyper::{Body, Request, Response, body::Payload};
+use rand::{thread_rng, Rng};
+use slog::{self, Drain};
+use slog_async::Async;
+use slog_json::Json;
+use config::Config;
+use context;
+use ip_hash::{HashedIp, IpHashing};
+
+use ::CONFIG;
+
+/// A stage of the SDK request with its time in the access log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Parse,
+    Validate,
+    IfaMatching,
+    Publish,
+}
+
+/// What the handling of a request found out, for the access log.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub app_id: Option<String>,
+    pub platform: Option<String>,
+    pub bytes_in: Option<usize>,
+    pub events: Option<usize>,
+    pub parse: Option<Duration>,
+    pub validate: Option<Duration>,
+    pub ifa_matching: Option<Duration>,
+    pub publish: Option<Duration>,
+}
+
+/// The stats of a request, shared by its stages.
+#[derive(Debug, Clone, Default)]
+pub struct RequestStats {
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl RequestStats {
+    pub fn new() -> RequestStats {
+        RequestStats::default()
+    }
+
+    pub fn record(&self, stage: Stage, elapsed: Duration) {
+        self.update(|stats| {
+            let time = match stage {
+                Stage::Parse => &mut stats.parse,
+                Stage::Validate => &mut stats.validate,
+                Stage::IfaMatching => &mut stats.ifa_matching,
+                Stage::Publish => &mut stats.publish,
+            };
+
+            *time = Some(elapsed);
+        })
+    }
+
+    /// Runs the stage, recording the time it took.
+    pub fn time<T, F>(&self, stage: Stage, f: F) -> T
+    where
+        F: FnOnce() -> T
+    {
+        let started = Instant::now();
+        let result = f();
+
+        self.record(stage, started.elapsed());
+        result
+    }
+
+    pub fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Stats)
+    {
+        f(&mut self.stats.lock().unwrap())
+    }
+
+    pub fn get(&self) -> Stats {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+/// What's logged of the client IP address.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIp {
+    /// The address hashed like in the events, with the salt epoch.
+    Hash,
+    /// The /24 network of an IPv4 and the /48 network of an IPv6 address.
+    Truncate,
+    /// Nothing.
+    Drop,
+}
+
+impl ClientIp {
+    /// The logged address and the salt epoch of its hash, if any.
+    pub fn apply(&self, ip: &IpAddr, ip_hashing: &IpHashing) -> (Option<String>, Option<u64>) {
+        match self {
+            ClientIp::Hash => match ip_hashing.hash(ip) {
+                HashedIp::Keyed { hash, epoch } => (Some(hash), Some(epoch)),
+                HashedIp::Digest(hash) => (Some(hash), None),
+                HashedIp::Truncated(network) => (Some(network.to_string()), None),
+            },
+            ClientIp::Truncate => (Some(truncate_ip(ip).to_string()), None),
+            ClientIp::Drop => (None, None),
+        }
+    }
+}
+
+/// The address with the host part zeroed: the /24 network of an IPv4 and
+/// the /48 network of an IPv6 address.
+pub fn truncate_ip(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            IpAddr::from([o[0], o[1], o[2], 0])
+        },
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            IpAddr::from([s[0], s[1], s[2], 0, 0, 0, 0, 0])
+        },
+    }
+}
+
+/// The request as received, before handling it.
+pub struct RequestInfo {
+    method: String,
+    path: String,
+    user_agent: Option<String>,
+    client_ip: Option<IpAddr>,
+    received: Instant,
+}
+
+impl RequestInfo {
+    pub fn new(req: &Request<Body>, client_ip: Option<IpAddr>) -> RequestInfo {
+        RequestInfo {
+            method: req.method().to_string(),
+            path: req.uri().path().to_string(),
+            user_agent: req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|h| h.to_str().ok())
+                .map(String::from),
+            client_ip,
+            received: Instant::now(),
+        }
+    }
+}
+
+/// One JSON line per SDK request, to a file or stdout. The errors are always
+/// logged, the successful requests with the configured sample rate.
+pub struct AccessLog {
+    logger: slog::Logger,
+    success_sample_rate: f64,
+    client_ip: ClientIp,
+}
+
+impl AccessLog {
+    pub fn new() -> Option<AccessLog> {
+        Self::from_config(&CONFIG)
+    }
+
+    pub fn from_config(config: &Config) -> Option<AccessLog> {
+        config.access_log.as_ref().map(|access_log| {
+            AccessLog {
+                logger: Self::logger(access_log.path.as_ref().map(|p| p.as_str())),
+                success_sample_rate: access_log.success_sample_rate.unwrap_or(1.0),
+                client_ip: access_log.client_ip.unwrap_or(ClientIp::Truncate),
+            }
+        })
+    }
+
+    fn logger(path: Option<&str>) -> slog::Logger {
+        let drain = match path {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .expect("Couldn't open the access log file");
+
+                let drain = Json::new(file).add_default_keys().build().fuse();
+                Async::new(drain).build().fuse()
+            },
+            None => {
+                let drain = Json::new(io::stdout()).add_default_keys().build().fuse();
+                Async::new(drain).build().fuse()
+            },
+        };
+
+        slog::Logger::root(drain, o!("log" => "access"))
+    }
+
+    /// Errors are always logged, the successes with the sample rate.
+    pub fn sample(&self, response: &Response<Body>) -> bool {
+        !response.status().is_success() || thread_rng().gen::<f64>() < self.success_sample_rate
+    }
+
+    pub fn write(
+        &self,
+        request: &RequestInfo,
+        response: &Response<Body>,
+        stats: &Stats,
+        ip_hashing: &IpHashing,
+    )
+    {
+        let millis = |duration: Option<Duration>| {
+            duration.map(|d| d.as_secs() as f64 * 1000.0 + f64::from(d.subsec_micros()) / 1000.0)
+        };
+
+        let request_id = response
+            .headers()
+            .get(context::REQUEST_ID)
+            .and_then(|h| h.to_str().ok());
+
+        let (client_ip, client_ip_epoch) = request.client_ip
+            .as_ref()
+            .map(|ip| self.client_ip.apply(ip, ip_hashing))
+            .unwrap_or((None, None));
+
+        info!(
+            self.logger, "{} {}", request.method, request.path;
+            "method" => &request.method,
+            "path" => &request.path,
+            "status" => response.status().as_u16(),
+            "bytes_in" => stats.bytes_in,
+            "bytes_out" => response.body().content_length(),
+            "request_id" => request_id,
+            "app_id" => stats.app_id.as_ref(),
+            "platform" => stats.platform.as_ref(),
+            "events" => stats.events,
+            "client_ip" => client_ip,
+            "client_ip_epoch" => client_ip_epoch,
+            "user_agent" => request.user_agent.as_ref(),
+            "total_ms" => millis(Some(request.received.elapsed())),
+            "parse_ms" => millis(stats.parse),
+            "validate_ms" => millis(stats.validate),
+            "ifa_matching_ms" => millis(stats.ifa_matching),
+            "publish_ms" => millis(stats.publish)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::{Ipv4Addr, Ipv6Addr}, time::SystemTime};
+    use hyper::StatusCode;
+    use ip_hash::{IpHashPolicy, DEFAULT_ROTATION};
+
+    fn access_log(success_sample_rate: f64) -> AccessLog {
+        AccessLog {
+            logger: slog::Logger::root(slog::Discard, o!()),
+            success_sample_rate,
+            client_ip: ClientIp::Truncate,
+        }
+    }
+
+    fn response(status: StatusCode) -> Response<Body> {
+        Response::builder().status(status).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_truncate_ip() {
+        let ipv4 = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 42));
+        let ipv6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0x85a3, 0x1, 0, 0x8a2e, 0x370, 0x7334));
+
+        let ip_hashing = IpHashing::with_policy(IpHashPolicy::Truncate);
+
+        assert_eq!((Some(String::from("192.168.1.0")), None), ClientIp::Truncate.apply(&ipv4, &ip_hashing));
+        assert_eq!((Some(String::from("2001:db8:85a3::")), None), ClientIp::Truncate.apply(&ipv6, &ip_hashing));
+        assert_eq!((None, None), ClientIp::Drop.apply(&ipv4, &ip_hashing));
+    }
+
+    #[test]
+    fn test_hash_ip() {
+        let ipv4 = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 42));
+        let policy = IpHashPolicy::Keyed { secret: vec![7; 32], rotation: DEFAULT_ROTATION };
+        let ip_hashing = IpHashing::with_policy(policy.clone());
+
+        let (hash, epoch) = ClientIp::Hash.apply(&ipv4, &ip_hashing);
+
+        assert!(epoch.is_some());
+        assert_eq!(policy.hash(&ipv4, SystemTime::now()).hash(), hash);
+        assert_ne!(Some(ipv4.to_string()), hash);
+    }
+
+    #[test]
+    fn test_errors_always_sampled() {
+        let access_log = access_log(0.0);
+
+        assert!(!access_log.sample(&response(StatusCode::OK)));
+        assert!(access_log.sample(&response(StatusCode::PRECONDITION_FAILED)));
+        assert!(access_log(1.0).sample(&response(StatusCode::OK)));
+    }
+
+    #[test]
+    fn test_stage_times() {
+        let stats = RequestStats::new();
+        let shared = stats.clone();
+
+        assert_eq!(42, shared.time(Stage::Parse, || 42));
+        shared.record(Stage::Publish, Duration::from_millis(5));
+        shared.update(|stats| stats.events = Some(3));
+
+        let stats = stats.get();
+
+        assert!(stats.parse.is_some());
+        assert_eq!(None, stats.validate);
+        assert_eq!(Some(Duration::from_millis(5)), stats.publish);
+        assert_eq!(Some(3), stats.events);
+    }
+}
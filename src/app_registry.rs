@@ -20,14 +20,16 @@ use crossbeam::sync::ArcCell;
 use uuid::Uuid;
 use r2d2;
 use hex;
-use ::{GLOG, CONFIG};
+use config::{Config, OriginConfig};
+use cors::{self, OriginPattern};
+use ::{GLOG, CONFIG, CORS};
 
 use cdrs::{
     authenticators::NoneAuthenticator,
     compression::Compression,
     query::QueryBuilder,
     transport::TransportTcp,
-    types::ByName,
+    types::{ByName, rows::Row},
     cluster::{LoadBalancingStrategy, LoadBalancer, ClusterConnectionManager},
 };
 
@@ -37,13 +39,14 @@ type CassandraPool =
 pub struct Application {
     pub id: String,
     pub token: Option<String>,
-    pub ios_secret: Option<hmac::VerificationKey>,
-    pub android_secret: Option<hmac::VerificationKey>,
-    pub web_secret: Option<hmac::VerificationKey>,
+    pub ios_secret: Option<hmac::SigningKey>,
+    pub android_secret: Option<hmac::SigningKey>,
+    pub web_secret: Option<hmac::SigningKey>,
 }
 
 pub struct AppRegistry {
     allow_empty_signature: bool,
+    default_token: String,
     apps: ArcCell<HashMap<String, Application>>,
     pool: Option<CassandraPool>,
 }
@@ -65,8 +68,13 @@ impl AppRegistry {
     /// `secret_ios`, `secret_android` and `secret_web`. If any of these is
     /// missing, the system will not allow requests for those platforms.
     pub fn new() -> AppRegistry {
-        if CONFIG.cassandra.manage_apps {
-            let config = &CONFIG.cassandra;
+        Self::from_config(&CONFIG)
+    }
+
+    pub fn from_config(config: &Config) -> AppRegistry {
+        if config.cassandra.manage_apps {
+            let gateway = &config.gateway;
+            let config = &config.cassandra;
             info!(*GLOG, "Apps loaded from ScyllaDB.");
 
             let cluster = config
@@ -89,7 +97,8 @@ impl AppRegistry {
                 .unwrap();
 
             let registry = AppRegistry {
-                allow_empty_signature: CONFIG.gateway.allow_empty_signature,
+                allow_empty_signature: gateway.allow_empty_signature,
+                default_token: gateway.default_token.clone(),
                 pool: Some(pool),
                 apps: ArcCell::new(Arc::new(HashMap::new())),
             };
@@ -98,40 +107,55 @@ impl AppRegistry {
 
             registry
         } else {
-            let apps = CONFIG.test_apps.as_ref().unwrap()
-                .iter()
-                .fold(HashMap::new(), |mut acc, test_app| {
-                    let ios_secret = test_app
-                        .secret_ios
-                        .clone();
-
-                    let android_secret = test_app
-                        .secret_android
-                        .clone();
+            AppRegistry {
+                allow_empty_signature: config.gateway.allow_empty_signature,
+                default_token: config.gateway.default_token.clone(),
+                pool: None,
+                apps: ArcCell::new(Arc::new(Self::test_apps(config))),
+            }
+        }
+    }
 
-                    let web_secret = test_app
-                        .secret_web
-                        .clone();
+    /// Replaces the test apps from a reloaded configuration. Apps managed in
+    /// ScyllaDB are not touched.
+    pub fn reload(&self, config: &Config) {
+        if self.pool.is_none() {
+            self.swap_apps(Self::test_apps(config));
+        }
+    }
 
-                    let app = Self::create_app(
-                        test_app.app_id.clone(),
-                        test_app.token.clone(),
-                        &ios_secret,
-                        &android_secret,
-                        &web_secret,
-                    );
+    fn test_apps(config: &Config) -> HashMap<String, Application> {
+        config.test_apps.as_ref().unwrap()
+            .iter()
+            .fold(HashMap::new(), |mut acc, test_app| {
+                let ios_secret = test_app
+                    .secret_ios
+                    .clone();
+
+                let android_secret = test_app
+                    .secret_android
+                    .clone();
+
+                let web_secret = test_app
+                    .secret_web
+                    .clone();
+
+                let app = Self::create_app(
+                    test_app.app_id.clone(),
+                    test_app.token.clone(),
+                    &ios_secret,
+                    &android_secret,
+                    &web_secret,
+                );
 
-                    acc.insert(test_app.app_id.clone(), app);
+                acc.insert(test_app.app_id.clone(), app);
 
-                    acc
-                });
+                acc
+            })
+    }
 
-            AppRegistry {
-                allow_empty_signature: CONFIG.gateway.allow_empty_signature,
-                pool: None,
-                apps: ArcCell::new(Arc::new(apps)),
-            }
-        }
+    pub fn exists(&self, app_id: &str) -> bool {
+        self.apps.get().contains_key(app_id)
     }
 
     pub fn token_for(&self, app_id: &str) -> Option<String> {
@@ -139,6 +163,20 @@ impl AppRegistry {
         apps.get(app_id).and_then(|a| a.token.clone())
     }
 
+    /// Validates a replayed request without the credentials: does the
+    /// application ID exist and are there any events.
+    pub fn validate_app(&self, event: &input::SDKEventBatch) -> Result<(), GatewayError> {
+        if !self.apps.get().contains_key(&event.environment.app_id) {
+            return Err(GatewayError::AppDoesNotExist)
+        }
+
+        if event.events.is_empty() {
+            return Err(GatewayError::InvalidPayload)
+        }
+
+        Ok(())
+    }
+
     /// Validates the incoming request for several things:
     ///
     /// * Does the application ID exist,
@@ -163,7 +201,7 @@ impl AppRegistry {
         let valid_token = app
             .token
             .as_ref()
-            .unwrap_or(&CONFIG.gateway.default_token);
+            .unwrap_or(&self.default_token);
 
         if let Some(ref sent_token) = context.api_token {
             if sent_token != valid_token { return Err(GatewayError::InvalidToken) }
@@ -189,12 +227,12 @@ impl AppRegistry {
             input::Platform::Android => app.android_secret.as_ref(),
             input::Platform::Web     => app.web_secret.as_ref(),
             _                        => None,
-        }.ok_or(GatewayError::AppDoesNotExist)?;
+        }.ok_or(GatewayError::PlatformNotConfigured)?;
 
         let decoded_signature = base64::decode(signature.as_bytes())
             .map_err(|_| GatewayError::InvalidSignature)?;
 
-        hmac::verify(&platform_key, raw_data, &decoded_signature)
+        hmac::verify_with_own_key(&platform_key, raw_data, &decoded_signature)
             .map_err(|_| GatewayError::InvalidSignature)
             .and_then(|_| Ok(()))
     }
@@ -213,17 +251,20 @@ impl AppRegistry {
         }
     }
 
+    /// The key of a hex encoded platform secret, for signing a payload as
+    /// the SDKs do and for validating the signature.
+    pub fn platform_key(s: &[u8]) -> Result<hmac::SigningKey, hex::FromHexError> {
+        hex::decode(s).map(|decoded| {
+            hmac::SigningKey::new(&digest::SHA512, &decoded)
+        })
+    }
+
     fn create_key(
         app_id: &str,
         column: &'static str,
         s: &[u8]
-    ) -> Option<hmac::VerificationKey> {
-        hex::decode(s).and_then(|decoded| {
-            Ok(hmac::VerificationKey::new(
-                &digest::SHA512,
-                &decoded,
-            ))
-        }).or_else(|e| {
+    ) -> Option<hmac::SigningKey> {
+        Self::platform_key(s).or_else(|e| {
             error!(
                 *GLOG,
                 "Error converting {} for app {}",
@@ -325,6 +366,8 @@ impl AppRegistry {
                 warn!(*GLOG, "No apps found, no access to gateway!");
                 self.swap_apps(HashMap::new());
             }
+
+            self.update_origins(pool)?;
         } else {
             warn!(*GLOG, "No ScyllaDB connection defined, registry update dysfunctional");
         }
@@ -332,6 +375,114 @@ impl AppRegistry {
         Ok(())
     }
 
+    /// Loads the allowed CORS origins from ScyllaDB. The `allowed_origins`
+    /// column is a comma-separated list of origin patterns.
+    fn update_origins(&self, pool: &CassandraPool) -> Result<(), io::Error> {
+        let cors = match *CORS {
+            Some(ref cors) => cors,
+            None => return Ok(()),
+        };
+
+        let query = QueryBuilder::new(
+            format!(
+                "SELECT app_id, allowed_origins, allowed_methods, allowed_headers FROM {}.gw_application_origins",
+                CONFIG.cassandra.keyspace
+            )
+        ).finalize();
+
+        let connection = pool.get()
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "Couldn't get a ScyllaDB connection for CORS origins",
+                )
+            })?;
+
+        let frame = connection
+            .query(query, false, false)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "Couldn't query CORS origins from ScyllaDB",
+                )
+            })?;
+
+        let rows = frame
+            .get_body()
+            .ok()
+            .and_then(|body| body.into_rows())
+            .unwrap_or_else(Vec::new);
+
+        let origins: Vec<OriginConfig> = rows
+            .iter()
+            .filter_map(|row| {
+                Self::origin_row(row).map_err(|e| {
+                    error!(*GLOG, "Skipping a row of gw_application_origins: [{}]", e);
+                }).ok()
+            })
+            .collect();
+
+        cors.swap_origins(&origins);
+
+        Ok(())
+    }
+
+    fn origin_row(row: &Row) -> Result<OriginConfig, String> {
+        let column_error = |column: &str| format!("Couldn't read the {} column", column);
+
+        let id: Uuid = row.r_by_name("app_id")
+            .map_err(|_| column_error("app_id"))?;
+        let allowed: Option<String> = row.by_name("allowed_origins")
+            .map_err(|_| column_error("allowed_origins"))?;
+        let allowed_methods: Option<String> = row.by_name("allowed_methods")
+            .map_err(|_| column_error("allowed_methods"))?;
+        let allowed_headers: Option<String> = row.by_name("allowed_headers")
+            .map_err(|_| column_error("allowed_headers"))?;
+
+        Self::origin_config(id.hyphenated().to_string(), allowed, allowed_methods, allowed_headers)
+    }
+
+    /// The origins of an app from the database. Values that can't be sent
+    /// in the CORS headers reject the whole row, invalid origin patterns are
+    /// skipped.
+    fn origin_config(
+        app_id: String,
+        allowed: Option<String>,
+        allowed_methods: Option<String>,
+        allowed_headers: Option<String>,
+    ) -> Result<OriginConfig, String>
+    {
+        for value in allowed_methods.iter().chain(allowed_headers.iter()) {
+            cors::check_header_value(value)
+                .map_err(|e| format!("app {}: {}", app_id, e))?;
+        }
+
+        let allowed = allowed
+            .map(|allowed| {
+                allowed
+                    .split(',')
+                    .map(|s| s.trim())
+                    .filter(|s| !s.is_empty())
+                    .filter(|s| match OriginPattern::parse(s) {
+                        Ok(_) => true,
+                        Err(e) => {
+                            error!(*GLOG, "Skipping an origin for app {}: [{}]", app_id, e);
+                            false
+                        }
+                    })
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_else(Vec::new);
+
+        Ok(OriginConfig {
+            app_id,
+            allowed,
+            allowed_methods,
+            allowed_headers,
+        })
+    }
+
     fn swap_apps(&self, apps: HashMap<String, Application>) {
         self.apps.set(Arc::new(apps));
     }
@@ -696,6 +847,34 @@ mod tests {
         assert_eq!(Err(GatewayError::AppDoesNotExist), validation);
     }
 
+    #[test]
+    fn test_validate_platform_not_configured() {
+        let mut header_map = HeaderMap::new();
+
+        header_map.insert(
+            "XORC-Signature",
+            HeaderValue::from_static(
+                "iamp0NMGsLvLTsoTSRRKQn4uTThETrkdk7hjCX0jqDXdjNyOv/tRK9C9cnPhi4IIvP4Fj/kP/5L8waXx3fokOg=="
+            ),
+        );
+
+        header_map.insert(
+            "XORC-Api-Token",
+            HeaderValue::from_static(TOKEN),
+        );
+
+        let context = Context::new(&header_map, "123", Platform::Unknown);
+        let app_registry = AppRegistry::new();
+
+        let validation = app_registry.validate(
+            &create_test_event("22222222-0000-0000-0000-000000000000", "pylly"),
+            &context,
+            "kulli".as_bytes()
+        );
+
+        assert_eq!(Err(GatewayError::PlatformNotConfigured), validation);
+    }
+
     #[test]
     fn test_validate_web_invalid_signature() {
         let mut header_map = HeaderMap::new();
@@ -723,4 +902,47 @@ mod tests {
 
         assert_eq!(Err(GatewayError::InvalidSignature), validation);
     }
+
+    #[test]
+    fn test_validate_app_without_credentials() {
+        let app_registry = AppRegistry::new();
+        let mut event = create_test_event("22222222-0000-0000-0000-000000000000", "ios");
+
+        assert_eq!(Ok(()), app_registry.validate_app(&event));
+
+        event.events.clear();
+        assert_eq!(Err(GatewayError::InvalidPayload), app_registry.validate_app(&event));
+
+        assert_eq!(
+            Err(GatewayError::AppDoesNotExist),
+            app_registry.validate_app(&create_test_event("33333333-0000-0000-0000-000000000000", "ios"))
+        );
+    }
+
+    #[test]
+    fn test_origin_row_with_invalid_headers() {
+        let origin = AppRegistry::origin_config(
+            String::from("22222222-0000-0000-0000-000000000000"),
+            Some(String::from("https://example.com")),
+            None,
+            Some(String::from("Content-Type,\nX-Kulli")),
+        );
+
+        assert!(origin.is_err());
+    }
+
+    #[test]
+    fn test_origin_row_skips_invalid_patterns() {
+        let origin = AppRegistry::origin_config(
+            String::from("22222222-0000-0000-0000-000000000000"),
+            Some(String::from("https://example.com, kulli, https://*.example.org")),
+            Some(String::from("POST,OPTIONS")),
+            None,
+        ).unwrap();
+
+        assert_eq!(
+            vec![String::from("https://example.com"), String::from("https://*.example.org")],
+            origin.allowed
+        );
+    }
 }
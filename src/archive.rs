@@ -0,0 +1,548 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions, Permissions},
+    io::{self, prelude::*, BufReader, BufWriter},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::PathBuf,
+    sync::{
+        Arc,
+        Mutex,
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+    },
+    thread,
+};
+
+use base64;
+use chrono::{DateTime, offset::Utc};
+use crossbeam::sync::ArcCell;
+use http::{HeaderMap, header::{HeaderName, HeaderValue}};
+use rand::{Rng, thread_rng};
+use serde_json;
+use config::{ArchiveConfig, Config};
+use metrics::ARCHIVE_COUNTER;
+
+use ::{CONFIG, GLOG};
+
+/// The file being written, rotated files get a `.1`, `.2`... suffix with
+/// `.1` being the newest.
+const FILE_NAME: &'static str = "requests.jsonl";
+
+/// Rotate after the file grows over 100 MB, if `max_file_size` is not set.
+const DEFAULT_MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Files to keep including the current one, if `max_files` is not set.
+const DEFAULT_MAX_FILES: usize = 10;
+
+/// Entries waiting for the writer thread. If the disk can't keep up, new
+/// entries are dropped instead of slowing down the requests.
+const QUEUE_SIZE: usize = 10_000;
+
+/// The archive has client addresses and device ids, so only the user running
+/// the gateway can read it.
+const DIRECTORY_MODE: u32 = 0o700;
+const FILE_MODE: u32 = 0o600;
+
+const REDACTED: &'static str = "[REDACTED]";
+
+/// The headers stored with the request, and if the value is a secret that
+/// must be redacted. The addresses and the device id are kept for replaying.
+const HEADERS: [(&'static str, bool); 10] = [
+    ("content-type", false),
+    ("origin", false),
+    ("user-agent", false),
+    ("x-real-ip", false),
+    ("x-forwarded-for", false),
+    ("forwarded", false),
+    ("xorc-device-id", false),
+    ("x-request-id", false),
+    ("xorc-api-token", true),
+    ("xorc-signature", true),
+];
+
+/// One archived request, a line of JSON in the archive files.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Entry {
+    pub received_at: String,
+    pub app_id: Option<String>,
+    pub headers: BTreeMap<String, String>,
+    /// The body as is, if it's valid UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// The body base64 encoded, if it's not valid UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_base64: Option<String>,
+    pub outcome: Outcome,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Outcome {
+    pub status: u16,
+    pub error: Option<String>,
+}
+
+impl Entry {
+    pub fn new(
+        received_at: DateTime<Utc>,
+        app_id: Option<String>,
+        headers: &HeaderMap,
+        body: &[u8],
+        outcome: Outcome,
+    ) -> Entry
+    {
+        let headers = HEADERS
+            .iter()
+            .filter_map(|(name, secret)| {
+                let value = headers.get(*name)?;
+
+                let value = if *secret {
+                    String::from(REDACTED)
+                } else {
+                    value.to_str().ok()?.to_string()
+                };
+
+                Some((name.to_string(), value))
+            })
+            .collect();
+
+        let (body, body_base64) = match String::from_utf8(body.to_vec()) {
+            Ok(body) => (Some(body), None),
+            Err(_) => (None, Some(base64::encode(body))),
+        };
+
+        Entry {
+            received_at: received_at.to_rfc3339(),
+            app_id,
+            headers,
+            body,
+            body_base64,
+            outcome,
+        }
+    }
+
+    /// Reads the entries from an archive file, failing with the line number
+    /// of the first invalid entry.
+    pub fn read(path: &str) -> Result<Vec<Entry>, String> {
+        let file = File::open(path)
+            .map_err(|err| format!("Error while reading the archive {}: [{}]", path, err))?;
+
+        BufReader::new(file)
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| line.as_ref().map(|l| !l.trim().is_empty()).unwrap_or(true))
+            .map(|(i, line)| {
+                line.map_err(|err| format!("{}:{}: [{}]", path, i + 1, err))
+                    .and_then(|line| {
+                        serde_json::from_str(&line)
+                            .map_err(|err| format!("{}:{}: invalid entry [{}]", path, i + 1, err))
+                    })
+            })
+            .collect()
+    }
+
+    /// Requests that were accepted when archived, and so authenticated.
+    pub fn is_success(&self) -> bool {
+        self.outcome.status == 200
+    }
+
+    pub fn body(&self) -> Result<Vec<u8>, String> {
+        match (&self.body, &self.body_base64) {
+            (Some(body), _) => Ok(body.clone().into_bytes()),
+            (None, Some(encoded)) => {
+                base64::decode(encoded).map_err(|_| String::from("invalid body_base64"))
+            },
+            (None, None) => Err(String::from("no body")),
+        }
+    }
+
+    /// The archived headers for replaying, without the redacted ones.
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in self.headers.iter().filter(|(_, v)| *v != REDACTED) {
+            let name = HeaderName::from_bytes(name.as_bytes());
+            let value = HeaderValue::from_str(value);
+
+            if let (Ok(name), Ok(value)) = (name, value) {
+                headers.insert(name, value);
+            }
+        }
+
+        headers
+    }
+}
+
+/// Sampling rates from `[archive]` and its `[[archive.apps]]`.
+struct Sampling {
+    default_rate: f64,
+    app_rates: HashMap<String, f64>,
+}
+
+impl Sampling {
+    fn new(config: &ArchiveConfig) -> Sampling {
+        let app_rates = config.apps
+            .iter()
+            .map(|app| (app.app_id.clone(), app.sample_rate))
+            .collect();
+
+        Sampling {
+            default_rate: config.sample_rate,
+            app_rates,
+        }
+    }
+
+    fn rate(&self, app_id: Option<&str>) -> f64 {
+        app_id
+            .and_then(|app_id| self.app_rates.get(app_id))
+            .cloned()
+            .unwrap_or(self.default_rate)
+    }
+
+    fn sample(&self, app_id: Option<&str>) -> bool {
+        let rate = self.rate(app_id);
+
+        rate >= 1.0 || (rate > 0.0 && thread_rng().gen::<f64>() < rate)
+    }
+}
+
+/// Appends lines to `requests.jsonl`, rotating it when it would grow over
+/// the maximum size.
+pub struct RotatingFile {
+    directory: PathBuf,
+    max_file_size: u64,
+    max_files: usize,
+    writer: BufWriter<File>,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(directory: &str, max_file_size: u64, max_files: usize) -> io::Result<RotatingFile> {
+        fs::create_dir_all(directory)?;
+        fs::set_permissions(directory, Permissions::from_mode(DIRECTORY_MODE))?;
+
+        let directory = PathBuf::from(directory);
+        let file = Self::open_file(&directory.join(FILE_NAME))?;
+        let size = file.metadata()?.len();
+
+        Ok(RotatingFile {
+            directory,
+            max_file_size,
+            max_files,
+            writer: BufWriter::new(file),
+            size,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+
+        if self.size > 0 && self.size + len > self.max_file_size {
+            self.rotate()?;
+        }
+
+        self.writer.write_all(line)?;
+        self.writer.write_all(b"\n")?;
+        self.size += len;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Opens the file readable only by its owner, also when it was created
+    /// by an earlier version with looser permissions.
+    fn open_file(path: &PathBuf) -> io::Result<File> {
+        let file = OpenOptions::new().create(true).append(true).mode(FILE_MODE).open(path)?;
+        file.set_permissions(Permissions::from_mode(FILE_MODE))?;
+
+        Ok(file)
+    }
+
+    fn path(&self, index: usize) -> PathBuf {
+        if index == 0 {
+            self.directory.join(FILE_NAME)
+        } else {
+            self.directory.join(format!("{}.{}", FILE_NAME, index))
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+
+        let oldest = self.path(self.max_files - 1);
+
+        if oldest.exists() {
+            fs::remove_file(&oldest)?;
+        }
+
+        for index in (0..self.max_files - 1).rev() {
+            let path = self.path(index);
+
+            if path.exists() {
+                fs::rename(&path, self.path(index + 1))?;
+            }
+        }
+
+        self.writer = BufWriter::new(Self::open_file(&self.path(0))?);
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+/// Optional archive of the raw SDK requests, configured in the `[archive]`
+/// section. Sampled requests are queued to a writer thread, so the files
+/// are never written in the request path.
+pub struct Archive {
+    sampling: ArcCell<Sampling>,
+    sender: Mutex<SyncSender<Entry>>,
+}
+
+impl Archive {
+    pub fn new() -> Option<Archive> {
+        Self::from_config(&CONFIG)
+    }
+
+    pub fn from_config(config: &Config) -> Option<Archive> {
+        config.archive.as_ref().map(|archive_config| {
+            let file = RotatingFile::open(
+                &archive_config.directory,
+                archive_config.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE),
+                archive_config.max_files.unwrap_or(DEFAULT_MAX_FILES),
+            ).unwrap_or_else(|err| {
+                panic!("Could not open the archive in {}: {}", archive_config.directory, err)
+            });
+
+            let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+
+            thread::Builder::new()
+                .name(String::from("request-archive"))
+                .spawn(move || Self::write_entries(file, receiver))
+                .unwrap();
+
+            Archive {
+                sampling: ArcCell::new(Arc::new(Sampling::new(archive_config))),
+                sender: Mutex::new(sender),
+            }
+        })
+    }
+
+    /// Swaps the sampling rates from a reloaded configuration. The files
+    /// need a restart to change.
+    pub fn reload(&self, config: &Config) {
+        if let Some(ref archive_config) = config.archive {
+            self.sampling.set(Arc::new(Sampling::new(archive_config)));
+        }
+    }
+
+    /// Should the request of the app be archived, decided by the app's
+    /// sampling rate.
+    pub fn sample(&self, app_id: Option<&str>) -> bool {
+        self.sampling.get().sample(app_id)
+    }
+
+    pub fn write(&self, entry: Entry) {
+        let result = self.sender.lock().unwrap().try_send(entry);
+
+        match result {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                ARCHIVE_COUNTER.with_label_values(&["dropped"]).inc();
+            },
+            Err(TrySendError::Disconnected(_)) => {
+                ARCHIVE_COUNTER.with_label_values(&["error"]).inc();
+            },
+        }
+    }
+
+    /// Writes until the archive is dropped, flushing whenever the queue is
+    /// empty.
+    fn write_entries(mut file: RotatingFile, receiver: Receiver<Entry>) {
+        while let Ok(entry) = receiver.recv() {
+            Self::write_entry(&mut file, &entry);
+
+            while let Ok(entry) = receiver.try_recv() {
+                Self::write_entry(&mut file, &entry);
+            }
+
+            if let Err(err) = file.flush() {
+                error!(*GLOG, "Error flushing the archive: [{}]", err);
+            }
+        }
+    }
+
+    fn write_entry(file: &mut RotatingFile, entry: &Entry) {
+        let result = serde_json::to_vec(entry)
+            .map_err(io::Error::from)
+            .and_then(|line| file.write_line(&line));
+
+        match result {
+            Ok(()) => ARCHIVE_COUNTER.with_label_values(&["written"]).inc(),
+            Err(err) => {
+                ARCHIVE_COUNTER.with_label_values(&["error"]).inc();
+                error!(*GLOG, "Error writing to the archive: [{}]", err);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+    use config::ArchiveAppConfig;
+
+    const APP_ID: &'static str = "22222222-0000-0000-0000-000000000000";
+
+    fn temp_dir() -> String {
+        env::temp_dir()
+            .join(format!("xorc-archive-{}", Uuid::new_v4()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        headers.insert("Origin", HeaderValue::from_static("https://www.example.com"));
+        headers.insert("XORC-Api-Token", HeaderValue::from_static("token"));
+        headers.insert("XORC-Signature", HeaderValue::from_static("signature"));
+        headers.insert("Cookie", HeaderValue::from_static("session=1"));
+
+        headers
+    }
+
+    fn entry(body: &[u8]) -> Entry {
+        Entry::new(
+            Utc::now(),
+            Some(APP_ID.to_string()),
+            &headers(),
+            body,
+            Outcome { status: 200, error: None },
+        )
+    }
+
+    fn archive_config(directory: &str) -> ArchiveConfig {
+        ArchiveConfig {
+            directory: directory.to_string(),
+            sample_rate: 0.0,
+            max_file_size: None,
+            max_files: None,
+            apps: vec![ArchiveAppConfig { app_id: APP_ID.to_string(), sample_rate: 1.0 }],
+        }
+    }
+
+    #[test]
+    fn test_redacted_headers() {
+        let entry = entry(b"{}");
+
+        assert_eq!(Some(&String::from(REDACTED)), entry.headers.get("xorc-api-token"));
+        assert_eq!(Some(&String::from(REDACTED)), entry.headers.get("xorc-signature"));
+        assert_eq!(Some(&String::from("https://www.example.com")), entry.headers.get("origin"));
+        assert!(entry.headers.get("cookie").is_none());
+    }
+
+    #[test]
+    fn test_replay_headers() {
+        let headers = entry(b"{}").headers();
+
+        assert_eq!(1, headers.len());
+        assert_eq!("https://www.example.com", headers.get("origin").unwrap().to_str().unwrap());
+    }
+
+    #[test]
+    fn test_body() {
+        let utf8 = entry(b"{\"foo\":\"b\xc3\xa4r\"}");
+        assert_eq!(Some(String::from("{\"foo\":\"bär\"}")), utf8.body);
+        assert_eq!(Ok(b"{\"foo\":\"b\xc3\xa4r\"}".to_vec()), utf8.body());
+
+        let binary = entry(b"\xff\x00");
+        assert!(binary.body.is_none());
+        assert_eq!(Ok(b"\xff\x00".to_vec()), binary.body());
+    }
+
+    #[test]
+    fn test_sampling() {
+        let sampling = Sampling::new(&archive_config("unused"));
+
+        assert!(sampling.sample(Some(APP_ID)));
+        assert!(!sampling.sample(Some("33333333-0000-0000-0000-000000000000")));
+        assert!(!sampling.sample(None));
+    }
+
+    #[test]
+    fn test_rotation() {
+        let directory = temp_dir();
+        let mut file = RotatingFile::open(&directory, 10, 3).unwrap();
+
+        for line in &["first", "second", "third", "fourth"] {
+            file.write_line(line.as_bytes()).unwrap();
+        }
+
+        file.flush().unwrap();
+
+        let read = |name: &str| fs::read_to_string(PathBuf::from(&directory).join(name)).unwrap();
+
+        assert_eq!("fourth\n", read("requests.jsonl"));
+        assert_eq!("third\n", read("requests.jsonl.1"));
+        assert_eq!("second\n", read("requests.jsonl.2"));
+        assert!(!PathBuf::from(&directory).join("requests.jsonl.3").exists());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_permissions() {
+        let directory = temp_dir();
+        let mut file = RotatingFile::open(&directory, 10, 2).unwrap();
+
+        file.write_line(b"first").unwrap();
+        file.write_line(b"second").unwrap();
+        file.flush().unwrap();
+
+        let mode = |path: PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        assert_eq!(DIRECTORY_MODE, mode(PathBuf::from(&directory)));
+        assert_eq!(FILE_MODE, mode(PathBuf::from(&directory).join("requests.jsonl")));
+        assert_eq!(FILE_MODE, mode(PathBuf::from(&directory).join("requests.jsonl.1")));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let directory = temp_dir();
+        let path = PathBuf::from(&directory).join(FILE_NAME).to_string_lossy().to_string();
+
+        let mut file = RotatingFile::open(&directory, DEFAULT_MAX_FILE_SIZE, 1).unwrap();
+        Archive::write_entry(&mut file, &entry(b"{}"));
+        Archive::write_entry(&mut file, &entry(b"\xff"));
+        file.flush().unwrap();
+
+        let entries = Entry::read(&path).unwrap();
+
+        assert_eq!(2, entries.len());
+        assert_eq!(Some(String::from(APP_ID)), entries[0].app_id);
+        assert_eq!(Ok(b"\xff".to_vec()), entries[1].body());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_read_invalid_line() {
+        let directory = temp_dir();
+        let path = PathBuf::from(&directory).join(FILE_NAME).to_string_lossy().to_string();
+
+        let mut file = RotatingFile::open(&directory, DEFAULT_MAX_FILE_SIZE, 1).unwrap();
+        Archive::write_entry(&mut file, &entry(b"{}"));
+        file.write_line(b"kulli").unwrap();
+        file.flush().unwrap();
+
+        let error = Entry::read(&path).unwrap_err();
+        assert!(error.ends_with(":2: invalid entry [expected value at line 1 column 1]"), "{}", error);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use hyper::{Body, Client, Request, StatusCode};
+use futures::{Future, Stream, stream, sync::oneshot};
+use tokio::runtime::{Builder as RuntimeBuilder, Runtime};
+use ring::hmac;
+use base64;
+use slog::Level;
+use uuid::Uuid;
+use chrono::offset::Utc;
+
+use app_registry::AppRegistry;
+use bus;
+use encryption::{Ciphertext, Cleartext};
+use gateway::{Gateway, Dependencies};
+use ifa_store::MemoryIfaStore;
+use logger::Logger;
+
+use ::CONFIG;
+
+const EVENTS_PATH: &'static str = "/xray/events/xorc/sdk/v1";
+const DEVICE_ID: &'static str = "8f7f5c07-5eb2-4695-870c-065d886cdc9e";
+
+/// Load test options: the server thread counts to test, and the requests
+/// sent for each with how many in flight.
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub threads: Vec<usize>,
+    pub requests: usize,
+    pub concurrency: usize,
+}
+
+/// The app the load test signs its requests for, the first iOS test app
+/// from the configuration.
+struct BenchApp {
+    app_id: String,
+    token: String,
+    signing_key: hmac::SigningKey,
+}
+
+impl BenchApp {
+    fn from_config() -> Result<BenchApp, String> {
+        CONFIG.test_apps
+            .as_ref()
+            .and_then(|apps| apps.iter().find(|app| app.secret_ios.is_some()))
+            .and_then(|app| {
+                let secret = app.secret_ios.clone().unwrap();
+
+                AppRegistry::platform_key(secret.as_bytes()).ok().map(|signing_key| BenchApp {
+                    app_id: app.app_id.clone(),
+                    token: app.token.clone().unwrap_or_else(|| CONFIG.gateway.default_token.clone()),
+                    signing_key,
+                })
+            })
+            .ok_or_else(|| String::from("The load test needs a [[test_apps]] entry with secret_ios"))
+    }
+
+    fn sign(&self, payload: &[u8]) -> String {
+        base64::encode(hmac::sign(&self.signing_key, payload).as_ref())
+    }
+
+    /// A batch of ten custom events, with a register event for a new device
+    /// if an IFA is given.
+    fn payload(&self, register_ifa: Option<Uuid>) -> Vec<u8> {
+        let now = Utc::now().timestamp_millis();
+
+        let mut events: Vec<_> = (0..10).map(|i| json!({
+            "id": format!("{}", i + 1),
+            "timestamp": format!("{}", now + i),
+            "name": "view_item",
+            "session_id": "3a2b9d4e-0c1f-4a6b-8e7d-5f3c2b1a0e9d",
+            "properties": {
+                "price": 9.99,
+                "currency": "EUR",
+                "item": { "id": format!("item-{}", i), "in_stock": true },
+                "tags": ["sale", "summer"],
+            },
+        })).collect();
+
+        if register_ifa.is_some() {
+            events.push(json!({
+                "id": "0",
+                "timestamp": format!("{}", now),
+                "name": "xorc_register",
+                "properties": {},
+            }));
+        }
+
+        json!({
+            "environment": {
+                "app_id": self.app_id,
+                "sdk_version": "1.0.0",
+                "app_version": "2.3.1",
+            },
+            "device": {
+                "platform": "ios",
+                "ifa": register_ifa.map(|ifa| ifa.hyphenated().to_string()),
+                "ifa_tracking_enabled": true,
+                "manufacturer": "Apple",
+                "model": "iPhone10,6",
+                "os_name": "iOS",
+                "os_version": "12.0",
+                "locale": "fi_FI",
+                "time_zone": "Europe/Helsinki",
+            },
+            "events": events,
+        }).to_string().into_bytes()
+    }
+
+    /// A signed SDK request, with the `XORC-Device-Id` unless registering.
+    fn request(&self, uri: &str, payload: Vec<u8>, device_id: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder();
+
+        builder
+            .method("POST")
+            .uri(uri)
+            .header("Content-Type", "application/json")
+            .header("XORC-Api-Token", self.token.as_str())
+            .header("XORC-Signature", self.sign(&payload).as_str());
+
+        if let Some(device_id) = device_id {
+            builder.header("XORC-Device-Id", device_id);
+        }
+
+        builder.body(Body::from(payload)).unwrap()
+    }
+}
+
+fn nanos(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1e9 + f64::from(duration.subsec_nanos())
+}
+
+fn format_nanos(nanos: f64) -> String {
+    if nanos < 1e3 {
+        format!("{:.2} ns", nanos)
+    } else if nanos < 1e6 {
+        format!("{:.2} µs", nanos / 1e3)
+    } else if nanos < 1e9 {
+        format!("{:.2} ms", nanos / 1e6)
+    } else {
+        format!("{:.2} s", nanos / 1e9)
+    }
+}
+
+/// Nearest-rank percentile of sorted values.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::from_secs(0)
+    }
+
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1).min(sorted.len()) - 1]
+}
+
+/// Runs the load test for every thread count, the gateway logging only
+/// warnings to not measure the logging.
+pub fn run(options: Options) -> Result<String, String> {
+    let app = BenchApp::from_config()?;
+
+    Logger::set_level(Level::Warning);
+
+    println!(
+        "{:>8} {:>10} {:>8} {:>12} {:>12} {:>12}",
+        "threads", "requests", "errors", "req/s", "p50", "p99"
+    );
+
+    for threads in options.threads {
+        load(&app, threads, options.requests, options.concurrency)?;
+    }
+
+    Ok(String::new())
+}
+
+/// Sends the requests to a gateway running with the given thread count,
+/// concurrency requests at a time. Every tenth request registers a new
+/// device, going through the IFA store.
+fn load(
+    app: &BenchApp,
+    threads: usize,
+    requests: usize,
+    concurrency: usize,
+) -> Result<(), String>
+{
+    let mut server_runtime = RuntimeBuilder::new()
+        .name_prefix("bench-gateway-")
+        .core_threads(threads)
+        .build()
+        .map_err(|e| format!("{}", e))?;
+
+    let mut client_runtime = Runtime::new().map_err(|e| format!("{}", e))?;
+
+    let gateway = Gateway::with(Dependencies::new(
+        Arc::new(bus::Memory::counting()),
+        Arc::new(MemoryIfaStore::new()),
+    ));
+
+    let (server_tx, server_rx) = oneshot::channel();
+    let addr = gateway.spawn(&"127.0.0.1:0".parse().unwrap(), &mut server_runtime, server_rx);
+    let uri = format!("http://{}{}", addr, EVENTS_PATH);
+
+    let device_id = Ciphertext::encrypt(&Cleartext::from(DEVICE_ID)).to_string();
+    let payload = app.payload(None);
+
+    let requests: Vec<Request<Body>> = (0..requests).map(|i| {
+        if i % 10 == 0 {
+            app.request(&uri, app.payload(Some(Uuid::new_v4())), None)
+        } else {
+            app.request(&uri, payload.clone(), Some(device_id.as_str()))
+        }
+    }).collect();
+
+    let request_count = requests.len();
+    let client = Client::new();
+    let started = Instant::now();
+
+    let responses = stream::iter_ok(requests)
+        .map(move |request| {
+            let sent = Instant::now();
+
+            client.request(request).and_then(move |response| {
+                let status = response.status();
+
+                response.into_body().concat2().map(move |_| (status, sent.elapsed()))
+            })
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect();
+
+    let results = client_runtime
+        .block_on(responses)
+        .map_err(|e| format!("Load test request failed: {}", e))?;
+
+    let elapsed = started.elapsed();
+
+    let errors = results.iter().filter(|(status, _)| *status != StatusCode::OK).count();
+    let mut latencies: Vec<Duration> = results.into_iter().map(|(_, latency)| latency).collect();
+    latencies.sort();
+
+    println!(
+        "{:>8} {:>10} {:>8} {:>12.0} {:>12} {:>12}",
+        threads,
+        request_count,
+        errors,
+        request_count as f64 / (nanos(elapsed) / 1e9),
+        format_nanos(nanos(percentile(&latencies, 50.0))),
+        format_nanos(nanos(percentile(&latencies, 99.0))),
+    );
+
+    let _ = server_tx.send(());
+    let _ = server_runtime.shutdown_on_idle().wait();
+    let _ = client_runtime.shutdown_now().wait();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use events::input::SDKEventBatch;
+
+    fn millis(values: &[u64]) -> Vec<Duration> {
+        values.iter().map(|v| Duration::from_millis(*v)).collect()
+    }
+
+    #[test]
+    fn test_percentile() {
+        let latencies = millis(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+
+        assert_eq!(Duration::from_millis(5), percentile(&latencies, 50.0));
+        assert_eq!(Duration::from_millis(10), percentile(&latencies, 99.0));
+        assert_eq!(Duration::from_millis(1), percentile(&latencies, 0.0));
+    }
+
+    #[test]
+    fn test_percentile_empty() {
+        assert_eq!(Duration::from_secs(0), percentile(&[], 99.0));
+    }
+
+    #[test]
+    fn test_format_nanos() {
+        assert_eq!("12.00 ns", format_nanos(12.0));
+        assert_eq!("1.50 µs", format_nanos(1500.0));
+        assert_eq!("2.00 ms", format_nanos(2e6));
+    }
+
+    #[test]
+    fn test_load_payload_is_valid() {
+        let app = BenchApp::from_config().unwrap();
+        let payload = app.payload(Some(Uuid::new_v4()));
+        let event: SDKEventBatch = serde_json::from_slice(&payload).unwrap();
+
+        assert_eq!(11, event.events.len());
+        assert!(event.events.iter().any(|e| e.is_register()));
+    }
+}
@@ -0,0 +1,421 @@
+//! Sends realistic signed SDK event batches to a gateway, to reproduce what
+//! the client SDKs do. The first batch registers the device, the following
+//! batches use the `device_id` from the registration response.
+
+#[macro_use] extern crate serde_json;
+
+extern crate hyper;
+extern crate futures;
+extern crate tokio;
+extern crate ring;
+extern crate hex;
+extern crate base64;
+extern crate uuid;
+extern crate chrono;
+
+use std::{
+    env,
+    process,
+    thread,
+    time::{Duration, Instant},
+};
+
+use hyper::{Body, Client, Request};
+use futures::{Future, Stream};
+use tokio::runtime::Runtime;
+use ring::{hmac, digest};
+use serde_json::Value;
+use uuid::Uuid;
+use chrono::offset::Utc;
+
+const USAGE: &'static str = "\
+Usage: xorc-simulator --app-id <uuid> [options]
+
+Options:
+    --url <url>             Gateway events endpoint
+                            [default: http://localhost:1337/xray/events/xorc/sdk/v1]
+    --app-id <uuid>         The application id
+    --token <token>         Sent as XORC-Api-Token
+    --secret <hex>          Platform secret for XORC-Signature, unsigned if not given
+    --platform <platform>   ios, android or web [default: ios]
+    --origin <url>          Origin of the web batches [default: http://localhost]
+    --rate <n>              Batches per second [default: 1]
+    --count <n>             Number of batches to send [default: 1]
+    --events <n>            Custom events per batch [default: 1]";
+
+const CUSTOM_EVENTS: [&'static str; 4] = [
+    "view_item",
+    "add_to_cart",
+    "purchase",
+    "level_up",
+];
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    url: String,
+    app_id: String,
+    token: Option<String>,
+    secret: Option<String>,
+    platform: String,
+    origin: String,
+    rate: f64,
+    count: usize,
+    events: usize,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options {
+            url: String::from("http://localhost:1337/xray/events/xorc/sdk/v1"),
+            app_id: String::new(),
+            token: None,
+            secret: None,
+            platform: String::from("ios"),
+            origin: String::from("http://localhost"),
+            rate: 1.0,
+            count: 1,
+            events: 1,
+        };
+
+        let mut args = args.iter();
+
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| format!("Missing value for {}", flag))?;
+
+            match flag.as_str() {
+                "--url" => options.url = value.clone(),
+                "--app-id" => options.app_id = value.clone(),
+                "--token" => options.token = Some(value.clone()),
+                "--secret" => options.secret = Some(value.clone()),
+                "--platform" => options.platform = value.clone(),
+                "--origin" => options.origin = value.clone(),
+                "--rate" => options.rate = Self::number(flag, value)?,
+                "--count" => options.count = Self::number(flag, value)?,
+                "--events" => options.events = Self::number(flag, value)?,
+                _ => return Err(format!("Unknown option {}", flag)),
+            }
+        }
+
+        if Uuid::parse_str(&options.app_id).is_err() {
+            return Err(String::from("--app-id must be an uuid"))
+        }
+
+        match options.platform.as_str() {
+            "ios" | "android" | "web" => (),
+            _ => return Err(format!("Invalid platform {}", options.platform)),
+        }
+
+        if options.rate <= 0.0 {
+            return Err(String::from("--rate must be positive"))
+        }
+
+        Ok(options)
+    }
+
+    /// The `Origin` header, sent only by browsers.
+    fn origin(&self) -> Option<&str> {
+        if self.platform == "web" { Some(self.origin.as_str()) } else { None }
+    }
+
+    fn number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+        value.parse().map_err(|_| format!("Invalid number for {}: {}", flag, value))
+    }
+}
+
+/// The device block as the SDK of the platform would send it.
+fn device(platform: &str) -> Value {
+    match platform {
+        "android" => json!({
+            "platform": "android",
+            "ifa": Uuid::new_v4().hyphenated().to_string(),
+            "ifa_tracking_enabled": true,
+            "manufacturer": "Google",
+            "model": "Pixel 2",
+            "os_name": "Android",
+            "os_version": "9",
+            "locale": "de_DE",
+            "language": "de",
+            "time_zone": "Europe/Berlin",
+            "network_connection_type": "wifi",
+            "carrier_name": "Telekom.de",
+            "carrier_country": "de",
+            "h": 1920,
+            "w": 1080,
+            "orientation": "portrait",
+        }),
+        "web" => json!({
+            "platform": "web",
+            "browser_name": "Firefox",
+            "browser_version": "63.0",
+            "browser_ua": "Mozilla/5.0 (X11; Linux x86_64; rv:63.0) Gecko/20100101 Firefox/63.0",
+            "locale": "en_US",
+            "language": "en",
+            "time_zone": "America/New_York",
+            "h": 1080,
+            "w": 1920,
+        }),
+        _ => json!({
+            "platform": "ios",
+            "ifa": Uuid::new_v4().hyphenated().to_string(),
+            "idfv": Uuid::new_v4().hyphenated().to_string(),
+            "ifa_tracking_enabled": true,
+            "notification_registered": true,
+            "notification_types": 7,
+            "manufacturer": "Apple",
+            "model": "iPhone10,6",
+            "os_name": "iOS",
+            "os_version": "12.0",
+            "device_name": "iPhone",
+            "locale": "fi_FI",
+            "language": "fi",
+            "time_zone": "Europe/Helsinki",
+            "network_connection_type": "4g",
+            "carrier_name": "Elisa",
+            "carrier_country": "fi",
+            "h": 2436,
+            "w": 1125,
+            "orientation": "portrait",
+        }),
+    }
+}
+
+/// An `SDKEventBatch` with the register event if the device is not known
+/// yet, and the given number of custom events.
+fn batch(options: &Options, device: &Value, sequence: usize, register: bool) -> Value {
+    let now = Utc::now().timestamp_millis();
+    let session_id = Uuid::new_v4().hyphenated().to_string();
+    let mut events = Vec::new();
+
+    if register {
+        events.push(json!({
+            "id": Uuid::new_v4().hyphenated().to_string(),
+            "timestamp": format!("{}", now),
+            "name": "xorc_register",
+            "session_id": session_id,
+            "properties": {},
+        }));
+    }
+
+    for i in 0..options.events {
+        events.push(json!({
+            "id": Uuid::new_v4().hyphenated().to_string(),
+            "timestamp": format!("{}", now + i as i64 + 1),
+            "name": CUSTOM_EVENTS[(sequence + i) % CUSTOM_EVENTS.len()],
+            "session_id": session_id,
+            "properties": {
+                "sequence": sequence,
+                "price": 9.99,
+                "currency": "EUR",
+                "item": {
+                    "id": format!("item-{}", i),
+                    "in_stock": true,
+                },
+            },
+        }));
+    }
+
+    json!({
+        "environment": {
+            "app_id": options.app_id,
+            "sdk_version": "1.0.0",
+            "app_version": "2.3.1",
+            "app_instance_id": Uuid::new_v4().hyphenated().to_string(),
+        },
+        "device": device,
+        "events": events,
+    })
+}
+
+/// The `XORC-Signature` of the payload, a base64 encoded HMAC-SHA512 with
+/// the hex encoded platform secret.
+fn sign(secret: &str, payload: &[u8]) -> Result<String, String> {
+    let decoded = hex::decode(secret).map_err(|_| String::from("The secret is not a hex string"))?;
+    let key = hmac::SigningKey::new(&digest::SHA512, &decoded);
+
+    Ok(base64::encode(hmac::sign(&key, payload).as_ref()))
+}
+
+/// The encrypted device id from a registration response.
+fn registered_device_id(response: &Value) -> Option<String> {
+    response["events_status"]
+        .as_array()?
+        .iter()
+        .filter_map(|status| status["registration_data"]["device_id"].as_str())
+        .next()
+        .map(|device_id| device_id.to_string())
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let mut runtime = Runtime::new().map_err(|e| format!("{}", e))?;
+    let client = Client::new();
+    let device = device(&options.platform);
+    let interval = Duration::from_millis((1000.0 / options.rate) as u64);
+    let mut device_id: Option<String> = None;
+
+    for sequence in 0..options.count {
+        let started = Instant::now();
+        let payload = batch(&options, &device, sequence, device_id.is_none()).to_string();
+
+        let mut builder = Request::builder();
+
+        builder
+            .method("POST")
+            .uri(options.url.as_str())
+            .header("Content-Type", "application/json");
+
+        if let Some(origin) = options.origin() {
+            builder.header("Origin", origin);
+        }
+
+        if let Some(ref token) = options.token {
+            builder.header("XORC-Api-Token", token.as_str());
+        }
+
+        if let Some(ref secret) = options.secret {
+            builder.header("XORC-Signature", sign(secret, payload.as_bytes())?.as_str());
+        }
+
+        if let Some(ref device_id) = device_id {
+            builder.header("XORC-Device-Id", device_id.as_str());
+        }
+
+        let request = builder
+            .body(Body::from(payload))
+            .map_err(|e| format!("Invalid request: {}", e))?;
+
+        let response = client.request(request).and_then(|res| {
+            let status = res.status();
+            res.into_body().concat2().map(move |body| (status, body))
+        });
+
+        match runtime.block_on(response) {
+            Ok((status, body)) => {
+                let body = String::from_utf8_lossy(&body).to_string();
+
+                println!("#{} {} {}", sequence, status, body);
+
+                if device_id.is_none() {
+                    device_id = serde_json::from_str(&body)
+                        .ok()
+                        .and_then(|response| registered_device_id(&response));
+                }
+            },
+            Err(e) => eprintln!("#{} Error sending the request: {}", sequence, e),
+        }
+
+        let elapsed = started.elapsed();
+
+        if sequence + 1 < options.count && elapsed < interval {
+            thread::sleep(interval - elapsed);
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let options = Options::parse(&args).unwrap_or_else(|error| {
+        eprintln!("{}\n\n{}", error, USAGE);
+        process::exit(2)
+    });
+
+    if let Err(error) = run(options) {
+        eprintln!("{}", error);
+        process::exit(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn options() -> Options {
+        Options::parse(&args(&[
+            "--app-id", "22222222-0000-0000-0000-000000000000",
+            "--platform", "android",
+            "--events", "3",
+        ])).unwrap()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options = options();
+
+        assert_eq!("android", options.platform);
+        assert_eq!(3, options.events);
+        assert_eq!(1, options.count);
+    }
+
+    #[test]
+    fn test_origin_only_for_web() {
+        assert_eq!(None, options().origin());
+
+        let web = Options::parse(&args(&[
+            "--app-id", "22222222-0000-0000-0000-000000000000",
+            "--platform", "web",
+            "--origin", "https://www.example.com",
+        ])).unwrap();
+
+        assert_eq!(Some("https://www.example.com"), web.origin());
+    }
+
+    #[test]
+    fn test_parse_invalid_options() {
+        assert!(Options::parse(&args(&["--app-id", "kulli"])).is_err());
+        assert!(Options::parse(&args(&["--app-id"])).is_err());
+
+        assert!(Options::parse(&args(&[
+            "--app-id", "22222222-0000-0000-0000-000000000000",
+            "--platform", "symbian",
+        ])).is_err());
+    }
+
+    #[test]
+    fn test_batch_with_register() {
+        let options = options();
+        let batch = batch(&options, &device("android"), 0, true);
+        let events = batch["events"].as_array().unwrap();
+
+        assert_eq!(4, events.len());
+        assert_eq!("xorc_register", events[0]["name"]);
+        assert_eq!("android", batch["device"]["platform"]);
+    }
+
+    #[test]
+    fn test_batch_without_register() {
+        let options = options();
+        let batch = batch(&options, &device("web"), 1, false);
+
+        assert_eq!(3, batch["events"].as_array().unwrap().len());
+        assert_eq!("22222222-0000-0000-0000-000000000000", batch["environment"]["app_id"]);
+    }
+
+    /// Same signature as in the app registry validation tests.
+    #[test]
+    fn test_sign() {
+        let secret = "1b66af517dd60807aeff8b4582d202ef500085bc0cec92bc3e67f0c58d6203b5";
+
+        assert_eq!(
+            Ok(String::from("8iq7J8PjWZvkfzPDa0HbfwnlbNWTK6giMO2Z1vsUhToMY62rSJtdIHkFaMY+UDIWRjCbf+c5le3AAHVUlDJDRg==")),
+            sign(secret, b"kulli")
+        );
+    }
+
+    #[test]
+    fn test_registered_device_id() {
+        let response = json!({
+            "events_status": [
+                { "id": "1", "status": "success" },
+                { "id": "2", "status": "success", "registration_data": { "device_id": "abc" } },
+            ]
+        });
+
+        assert_eq!(Some(String::from("abc")), registered_device_id(&response));
+    }
+}
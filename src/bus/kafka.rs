@@ -1,5 +1,6 @@
 use rdkafka::{
     config::ClientConfig,
+    message::OwnedHeaders,
     producer::{
         FutureProducer,
         future_producer::FutureRecord,
@@ -13,6 +14,7 @@ use futures::{
 
 use error::GatewayError;
 use context::Context;
+use bus::{self, Bus};
 use ::{CONFIG, GLOG};
 
 use metrics::KAFKA_LATENCY_HISTOGRAM;
@@ -37,13 +39,23 @@ impl Kafka {
         }
     }
 
-    pub fn publish(
+    fn headers(context: &Context) -> OwnedHeaders {
+        bus::headers(context)
+            .iter()
+            .fold(OwnedHeaders::new(), |headers, (key, value)| {
+                headers.add(key, value.as_str())
+            })
+    }
+}
+
+impl Bus for Kafka {
+    fn publish(
         &self,
         payload: &[u8],
         context: &Context,
-    ) -> impl Future<Item=(), Error=GatewayError>
+    ) -> Box<Future<Item=(), Error=GatewayError> + Send>
     {
-        let routing_key = Self::routing_key(context);
+        let routing_key = bus::routing_key(context);
 
         let record: FutureRecord<Vec<u8>, [u8]> = FutureRecord {
             topic: CONFIG.kafka.topic.as_ref(),
@@ -51,12 +63,12 @@ impl Kafka {
             payload: Some(payload),
             key: routing_key.as_ref(),
             timestamp: None,
-            headers: None,
+            headers: Some(Self::headers(context)),
         };
 
         let timer = KAFKA_LATENCY_HISTOGRAM.start_timer();
 
-        self.producer.send(record, 1000).then(|res| {
+        Box::new(self.producer.send(record, 1000).then(|res| {
             timer.observe_duration();
 
             match res {
@@ -65,16 +77,32 @@ impl Kafka {
                 Err(_) =>
                     err(GatewayError::ServiceUnavailable("Could not send to kafka")),
             }
-        })
+        }))
     }
 
-    fn routing_key(context: &Context) -> Option<Vec<u8>> {
-        context
-            .device_id
-            .as_ref()
-            .map(|ref device_id| {
-                let key = format!("{}|{}", context.app_id, device_id.cleartext);
-                key.as_bytes().to_vec()
-            })
+    fn publish_audit(
+        &self,
+        topic: &str,
+        app_id: &str,
+        payload: &[u8],
+    ) -> Box<Future<Item=(), Error=GatewayError> + Send>
+    {
+        let record: FutureRecord<str, [u8]> = FutureRecord {
+            topic,
+            partition: None,
+            payload: Some(payload),
+            key: Some(app_id),
+            timestamp: None,
+            headers: None,
+        };
+
+        Box::new(self.producer.send(record, 1000).then(|res| {
+            match res {
+                Ok(_) =>
+                    ok(()),
+                Err(_) =>
+                    err(GatewayError::ServiceUnavailable("Could not send to kafka")),
+            }
+        }))
     }
 }
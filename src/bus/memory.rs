@@ -0,0 +1,108 @@
+use std::sync::{
+    Mutex,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use futures::future::{self, Future};
+use error::GatewayError;
+use context::Context;
+use bus::{self, Bus};
+
+use ::CONFIG;
+
+/// A message sent to the bus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub topic: String,
+    pub key: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+    pub headers: Vec<(String, String)>,
+}
+
+/// Keeps the published messages in memory. When only counting, the
+/// messages are dropped.
+pub struct Memory {
+    records: Option<Mutex<Vec<Record>>>,
+    published: AtomicUsize,
+}
+
+impl Memory {
+    /// Stores every published message.
+    pub fn new() -> Memory {
+        Memory {
+            records: Some(Mutex::new(Vec::new())),
+            published: AtomicUsize::new(0),
+        }
+    }
+
+    /// Only counts the published messages.
+    pub fn counting() -> Memory {
+        Memory {
+            records: None,
+            published: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn published(&self) -> usize {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    pub fn records(&self) -> Vec<Record> {
+        self.records
+            .as_ref()
+            .map(|records| records.lock().unwrap().clone())
+            .unwrap_or_else(Vec::new)
+    }
+
+    fn push(&self, record: Record) {
+        self.published.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(ref records) = self.records {
+            records.lock().unwrap().push(record);
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for Memory {
+    fn publish(
+        &self,
+        payload: &[u8],
+        context: &Context,
+    ) -> Box<Future<Item=(), Error=GatewayError> + Send>
+    {
+        self.push(Record {
+            topic: CONFIG.kafka.topic.clone(),
+            key: bus::routing_key(context),
+            payload: payload.to_vec(),
+            headers: bus::headers(context)
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        });
+
+        Box::new(future::ok(()))
+    }
+
+    fn publish_audit(
+        &self,
+        topic: &str,
+        app_id: &str,
+        payload: &[u8],
+    ) -> Box<Future<Item=(), Error=GatewayError> + Send>
+    {
+        self.push(Record {
+            topic: topic.to_string(),
+            key: Some(app_id.as_bytes().to_vec()),
+            payload: payload.to_vec(),
+            headers: Vec::new(),
+        });
+
+        Box::new(future::ok(()))
+    }
+}
@@ -1,3 +1,75 @@
 mod kafka;
+mod memory;
 
 pub use self::kafka::Kafka;
+pub use self::memory::{Memory, Record};
+
+use futures::Future;
+use error::GatewayError;
+use context::Context;
+use consent::ConsentAction;
+use tracing::TRACEPARENT;
+
+/// Where the events are sent downstream. Kafka in production, in memory for
+/// load tests.
+pub trait Bus: Send + Sync {
+    /// Sends the encoded event batch to the events topic.
+    fn publish(
+        &self,
+        payload: &[u8],
+        context: &Context,
+    ) -> Box<Future<Item=(), Error=GatewayError> + Send>;
+
+    /// Sends an audit event to the given topic, keyed with the app id.
+    fn publish_audit(
+        &self,
+        topic: &str,
+        app_id: &str,
+        payload: &[u8],
+    ) -> Box<Future<Item=(), Error=GatewayError> + Send>;
+}
+
+/// The key for partitioning the events of a device.
+pub fn routing_key(context: &Context) -> Option<Vec<u8>> {
+    context
+        .device_id
+        .as_ref()
+        .map(|ref device_id| {
+            let key = format!("{}|{}", context.app_id, device_id.cleartext);
+            key.as_bytes().to_vec()
+        })
+}
+
+/// Metadata of the request sent as message headers.
+pub fn headers(context: &Context) -> Vec<(&'static str, String)> {
+    let mut headers = context.consent.headers();
+
+    // The location is only as precise as the country in the device, if
+    // even that is not allowed
+    if let Some(ref geo) = context.geo {
+        if !context.consent.has(ConsentAction::StripCountry) {
+            headers.extend(geo.headers());
+        }
+    }
+
+    if let Some(ref ip_hash) = context.ip_hash {
+        if !context.consent.has(ConsentAction::StripIpHash) {
+            headers.extend(ip_hash.headers());
+        }
+    }
+
+    if let Some(ref user_agent) = context.user_agent {
+        headers.extend(user_agent.headers());
+    }
+
+    if let Some(ref request_id) = context.request_id {
+        headers.push(("request_id", request_id.clone()));
+    }
+
+    if let Some(ref trace) = context.trace {
+        headers.push(("trace_id", trace.trace_id_hex()));
+        headers.push((TRACEPARENT, trace.traceparent()));
+    }
+
+    headers
+}
@@ -0,0 +1,313 @@
+use std::{
+    fs::File,
+    io::{self, prelude::*},
+};
+
+use base64;
+use hex;
+use ring::hmac;
+use rand::{RngCore, thread_rng};
+use app_registry::AppRegistry;
+use config::Config;
+use encryption::{Ciphertext, Cleartext};
+use bench;
+use replay;
+
+const USAGE: &'static str = "\
+Usage:
+    xorc-gateway [serve]                      Run the gateway
+    xorc-gateway encrypt <device_id>          Encrypt a device id with the SECRET
+    xorc-gateway decrypt <encrypted_id>       Decrypt an XORC-Device-Id with the SECRET
+    xorc-gateway sign <secret> [<file>]       Sign a payload (or stdin) with a hex platform secret
+    xorc-gateway validate-config [<file>]     Validate a configuration file, default CONFIG
+    xorc-gateway generate token               Generate a new app token
+    xorc-gateway generate secret              Generate a new hex platform secret
+    xorc-gateway generate server-secret       Generate a new SECRET or IFA_PEPPER
+    xorc-gateway bench load [options]         Load test with an in-memory bus and IFA store
+        --threads <n,n,...>                   Server thread counts to test [default: 1,2,4]
+        --requests <n>                        Requests per thread count [default: 10000]
+        --concurrency <n>                     Requests in flight [default: 64]
+    xorc-gateway replay <file> [options]      Send accepted requests from an archive file again
+        --app-id <uuid>                       Only replay the requests of the app
+        --dry-run                             Print the events instead of sending them
+    xorc-gateway help                         Show this message";
+
+/// Operator commands run instead of the server. Running without arguments
+/// or with `serve` starts the gateway.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Serve,
+    Help,
+    Encrypt(String),
+    Decrypt(String),
+    Sign { secret: String, file: Option<String> },
+    ValidateConfig(Option<String>),
+    GenerateToken,
+    GenerateSecret,
+    GenerateServerSecret,
+    Bench(bench::Options),
+    Replay(replay::Options),
+}
+
+impl Command {
+    pub fn parse(args: &[String]) -> Result<Command, String> {
+        let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+
+        match args.as_slice() {
+            [] | ["serve"] => Ok(Command::Serve),
+            ["help"] | ["--help"] | ["-h"] => Ok(Command::Help),
+            ["encrypt", device_id] => Ok(Command::Encrypt(device_id.to_string())),
+            ["decrypt", ciphertext] => Ok(Command::Decrypt(ciphertext.to_string())),
+            ["sign", secret] => Ok(Command::Sign {
+                secret: secret.to_string(),
+                file: None,
+            }),
+            ["sign", secret, file] => Ok(Command::Sign {
+                secret: secret.to_string(),
+                file: Some(file.to_string()),
+            }),
+            ["validate-config"] => Ok(Command::ValidateConfig(None)),
+            ["validate-config", file] => Ok(Command::ValidateConfig(Some(file.to_string()))),
+            ["generate", "token"] => Ok(Command::GenerateToken),
+            ["generate", "secret"] => Ok(Command::GenerateSecret),
+            ["generate", "server-secret"] => Ok(Command::GenerateServerSecret),
+            _ if args.len() >= 2 && args[0] == "bench" && args[1] == "load" => {
+                Self::parse_load(&args[2..]).map(Command::Bench)
+            },
+            _ if args.len() >= 2 && args[0] == "replay" => {
+                Self::parse_replay(args[1], &args[2..]).map(Command::Replay)
+            },
+            _ => Err(format!("Invalid arguments: {}", args.join(" "))),
+        }
+    }
+
+    fn parse_load(args: &[&str]) -> Result<bench::Options, String> {
+        let mut threads = vec![1, 2, 4];
+        let mut requests = 10_000;
+        let mut concurrency = 64;
+
+        for option in args.chunks(2) {
+            let (flag, value) = match option {
+                [flag, value] => (*flag, *value),
+                _ => return Err(format!("Missing value for {}", option[0])),
+            };
+
+            let invalid = || format!("Invalid value for {}: {}", flag, value);
+
+            match flag {
+                "--threads" => {
+                    threads = value
+                        .split(',')
+                        .map(|t| t.trim().parse().map_err(|_| invalid()))
+                        .collect::<Result<Vec<usize>, String>>()?;
+                },
+                "--requests" => requests = value.parse().map_err(|_| invalid())?,
+                "--concurrency" => concurrency = value.parse().map_err(|_| invalid())?,
+                _ => return Err(format!("Unknown option {}", flag)),
+            }
+        }
+
+        if threads.iter().any(|t| *t == 0) || requests == 0 || concurrency == 0 {
+            return Err(String::from("Thread counts, requests and concurrency must be positive"))
+        }
+
+        Ok(bench::Options { threads, requests, concurrency })
+    }
+
+    fn parse_replay(path: &str, args: &[&str]) -> Result<replay::Options, String> {
+        let mut options = replay::Options {
+            path: path.to_string(),
+            app_id: None,
+            dry_run: false,
+        };
+
+        let mut args = args.iter();
+
+        while let Some(flag) = args.next() {
+            match *flag {
+                "--dry-run" => options.dry_run = true,
+                "--app-id" => {
+                    let app_id = args.next().ok_or_else(|| format!("Missing value for {}", flag))?;
+                    options.app_id = Some(app_id.to_string());
+                },
+                _ => return Err(format!("Unknown option {}", flag)),
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// Runs the command, printing the result to STDOUT and errors to
+    /// STDERR. Returns the exit code.
+    pub fn run(self) -> i32 {
+        let result = match self {
+            Command::Serve => Ok(String::new()),
+            Command::Help => Ok(USAGE.to_string()),
+            Command::Encrypt(device_id) => Ok(Self::encrypt(&device_id)),
+            Command::Decrypt(ciphertext) => Self::decrypt(&ciphertext),
+            Command::Sign { secret, file } => {
+                Self::read_payload(file).and_then(|payload| Self::sign(&secret, &payload))
+            },
+            Command::ValidateConfig(file) => {
+                let path = file.unwrap_or_else(Config::path);
+
+                Config::load(&path).map(|_| format!("Configuration {} is valid", path))
+            },
+            Command::GenerateToken => Ok(base64::encode(&Self::random_bytes(64))),
+            Command::GenerateSecret => Ok(hex::encode(&Self::random_bytes(32))),
+            Command::GenerateServerSecret => {
+                Ok(base64::encode_config(&Self::random_bytes(32), base64::URL_SAFE_NO_PAD))
+            },
+            Command::Bench(options) => bench::run(options),
+            Command::Replay(options) => replay::run(options),
+        };
+
+        match result {
+            Ok(output) => {
+                println!("{}", output);
+                0
+            },
+            Err(error) => {
+                eprintln!("{}", error);
+                1
+            },
+        }
+    }
+
+    /// Prints the usage with the error for invalid arguments.
+    pub fn usage(error: &str) -> i32 {
+        eprintln!("{}\n\n{}", error, USAGE);
+        2
+    }
+
+    fn encrypt(device_id: &str) -> String {
+        Ciphertext::encrypt(&Cleartext::from(device_id)).to_string()
+    }
+
+    fn decrypt(ciphertext: &str) -> Result<String, String> {
+        Cleartext::decrypt(&Ciphertext::from(ciphertext))
+            .map(|cleartext| cleartext.as_ref().to_string())
+            .map_err(|_| String::from("Could not decrypt, invalid data or wrong SECRET"))
+    }
+
+    /// The `XORC-Signature` for the payload, with the same key the app
+    /// registry validates with.
+    fn sign(secret: &str, payload: &[u8]) -> Result<String, String> {
+        let key = AppRegistry::platform_key(secret.as_bytes())
+            .map_err(|_| String::from("The secret is not a hex string"))?;
+
+        Ok(base64::encode(hmac::sign(&key, payload).as_ref()))
+    }
+
+    fn read_payload(file: Option<String>) -> Result<Vec<u8>, String> {
+        let mut payload = Vec::new();
+
+        let result = match file {
+            Some(path) => File::open(&path).and_then(|mut f| f.read_to_end(&mut payload)),
+            None => io::stdin().read_to_end(&mut payload),
+        };
+
+        result
+            .map(|_| payload)
+            .map_err(|err| format!("Error reading the payload: [{}]", err))
+    }
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        thread_rng().fill_bytes(&mut bytes);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_serve() {
+        assert_eq!(Ok(Command::Serve), Command::parse(&args(&[])));
+        assert_eq!(Ok(Command::Serve), Command::parse(&args(&["serve"])));
+    }
+
+    #[test]
+    fn test_parse_sign() {
+        assert_eq!(
+            Ok(Command::Sign { secret: String::from("abcd"), file: Some(String::from("batch.json")) }),
+            Command::parse(&args(&["sign", "abcd", "batch.json"]))
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Command::parse(&args(&["generate", "kulli"])).is_err());
+        assert!(Command::parse(&args(&["encrypt"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_bench() {
+        assert_eq!(
+            Ok(Command::Bench(bench::Options { threads: vec![1, 8], requests: 500, concurrency: 64 })),
+            Command::parse(&args(&["bench", "load", "--threads", "1,8", "--requests", "500"]))
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_bench() {
+        assert!(Command::parse(&args(&["bench", "load", "--threads"])).is_err());
+        assert!(Command::parse(&args(&["bench", "load", "--threads", "0"])).is_err());
+        assert!(Command::parse(&args(&["bench", "load", "--kulli", "1"])).is_err());
+        assert!(Command::parse(&args(&["bench", "micro"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_replay() {
+        assert_eq!(
+            Ok(Command::Replay(replay::Options {
+                path: String::from("requests.jsonl"),
+                app_id: Some(String::from("22222222-0000-0000-0000-000000000000")),
+                dry_run: true,
+            })),
+            Command::parse(&args(&[
+                "replay", "requests.jsonl",
+                "--dry-run",
+                "--app-id", "22222222-0000-0000-0000-000000000000",
+            ]))
+        );
+
+        assert!(Command::parse(&args(&["replay"])).is_err());
+        assert!(Command::parse(&args(&["replay", "requests.jsonl", "--app-id"])).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        let device_id = "8f7f5c07-5eb2-4695-870c-065d886cdc9e";
+        let ciphertext = Command::encrypt(device_id);
+
+        assert_eq!(Ok(device_id.to_string()), Command::decrypt(&ciphertext));
+    }
+
+    #[test]
+    fn test_decrypt_garbage() {
+        assert!(Command::decrypt("kulli").is_err());
+    }
+
+    /// Same signature as in the app registry validation tests.
+    #[test]
+    fn test_sign() {
+        let secret = "1b66af517dd60807aeff8b4582d202ef500085bc0cec92bc3e67f0c58d6203b5";
+
+        assert_eq!(
+            Ok(String::from("8iq7J8PjWZvkfzPDa0HbfwnlbNWTK6giMO2Z1vsUhToMY62rSJtdIHkFaMY+UDIWRjCbf+c5le3AAHVUlDJDRg==")),
+            Command::sign(secret, b"kulli")
+        );
+    }
+
+    #[test]
+    fn test_sign_with_invalid_secret() {
+        assert!(Command::sign("kulli", b"kulli").is_err());
+    }
+}
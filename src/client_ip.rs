@@ -0,0 +1,369 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use http::HeaderMap;
+use config::Config;
+use context;
+
+use ::CONFIG;
+
+/// A network in CIDR notation, or a single address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parses `10.0.0.0/8`, `2001:db8::/32` or a single address.
+    pub fn parse(value: &str) -> Result<Cidr, String> {
+        let mut parts = value.trim().splitn(2, '/');
+
+        let network: IpAddr = parts
+            .next()
+            .and_then(|ip| ip.parse().ok())
+            .ok_or_else(|| format!("`{}` is not an IP address or network", value))?;
+
+        let max_prefix = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix = match parts.next() {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max_prefix)
+                .ok_or_else(|| format!("`{}` has an invalid prefix length", value))?,
+            None => max_prefix,
+        };
+
+        Ok(Cidr { network, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, canonical(*ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::max_value().checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::max_value().checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+/// An IPv4-mapped IPv6 address, as given by a dual-stack socket, as IPv4.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+
+            if s[..5].iter().all(|s| *s == 0) && s[5] == 0xffff {
+                IpAddr::V4(Ipv4Addr::new((s[6] >> 8) as u8, s[6] as u8, (s[7] >> 8) as u8, s[7] as u8))
+            } else {
+                IpAddr::V6(v6)
+            }
+        },
+        ip => ip,
+    }
+}
+
+/// A node of `X-Forwarded-For` or the `for` of `Forwarded`, with or without
+/// a port. `None` for the `unknown` and obfuscated identifiers.
+fn parse_node(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+
+    if value.starts_with('[') {
+        let end = value.find(']')?;
+        return value[1..end].parse::<Ipv6Addr>().ok().map(|ip| canonical(IpAddr::V6(ip)))
+    }
+
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(canonical(ip))
+    }
+
+    let mut parts = value.splitn(2, ':');
+    let host = parts.next()?;
+
+    match parts.next() {
+        Some(port) if port.parse::<u16>().is_ok() => host.parse::<Ipv4Addr>().ok().map(IpAddr::V4),
+        _ => None,
+    }
+}
+
+/// The proxy chain from the `Forwarded` header, or `X-Forwarded-For` if not
+/// set, the client first and the last proxy last.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name: &str| -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .map(|node| node.trim().to_string())
+            .filter(|node| !node.is_empty())
+            .collect()
+    };
+
+    let forwarded = values("forwarded");
+
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| {
+                        let mut kv = pair.splitn(2, '=');
+                        let key = kv.next()?.trim();
+
+                        if key.eq_ignore_ascii_case("for") { kv.next() } else { None }
+                    })
+                    .next()
+                    .and_then(parse_node)
+            })
+            .collect()
+    }
+
+    values("x-forwarded-for").iter().map(|node| parse_node(node)).collect()
+}
+
+/// Finds the address of the client, trusting the forwarding headers only
+/// from the configured proxies.
+///
+/// Without `[client_ip]` the `X-Real-IP` header is used if set, otherwise
+/// the address of the connection.
+///
+/// With it, the headers are ignored unless the connection comes from a
+/// trusted proxy. The chain of `Forwarded` or `X-Forwarded-For` is then
+/// walked from the right, and the first address not belonging to a trusted
+/// proxy is the client. A node that's not an address stops the walk: what's
+/// left of it can't be trusted, and the client is unknown.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientIpResolver {
+    trusted_proxies: Option<Vec<Cidr>>,
+}
+
+impl ClientIpResolver {
+    pub fn new() -> ClientIpResolver {
+        Self::from_config(&CONFIG)
+    }
+
+    pub fn from_config(config: &Config) -> ClientIpResolver {
+        let trusted_proxies = config.client_ip.as_ref().map(|client_ip| {
+            client_ip.trusted_proxies
+                .iter()
+                .map(|cidr| Cidr::parse(cidr).expect("Invalid trusted proxy"))
+                .collect()
+        });
+
+        ClientIpResolver { trusted_proxies }
+    }
+
+    fn trusted(&self, proxies: &[Cidr], ip: &IpAddr) -> bool {
+        proxies.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// True if the connection comes from a trusted proxy. Without
+    /// `trusted_proxies` no peer is trusted.
+    pub fn trusted_peer(&self, peer: Option<IpAddr>) -> bool {
+        match (&self.trusted_proxies, peer) {
+            (Some(proxies), Some(peer)) => self.trusted(proxies, &canonical(peer)),
+            _ => false,
+        }
+    }
+
+    /// The client address of the request. The peer is the address of the
+    /// connection, `None` when replaying an archived request.
+    pub fn resolve(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<IpAddr> {
+        let peer = peer.map(canonical);
+
+        let proxies = match self.trusted_proxies {
+            Some(ref proxies) => proxies,
+            None => return context::client_ip(headers).or(peer),
+        };
+
+        if let Some(ref peer) = peer {
+            if !self.trusted(proxies, peer) {
+                return Some(*peer)
+            }
+        }
+
+        let chain = forwarded_chain(headers);
+
+        if chain.is_empty() {
+            return context::client_ip(headers).map(canonical).or(peer)
+        }
+
+        let mut client = None;
+
+        for node in chain.into_iter().rev() {
+            match node {
+                Some(ip) => {
+                    client = Some(ip);
+
+                    if !self.trusted(proxies, &ip) {
+                        break
+                    }
+                },
+                None => return None,
+            }
+        }
+
+        client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::HeaderValue;
+
+    fn resolver(trusted_proxies: &[&str]) -> ClientIpResolver {
+        ClientIpResolver {
+            trusted_proxies: Some(trusted_proxies.iter().map(|c| Cidr::parse(c).unwrap()).collect()),
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(*value));
+        }
+
+        headers
+    }
+
+    fn ip(value: &str) -> Option<IpAddr> {
+        Some(value.parse().unwrap())
+    }
+
+    #[test]
+    fn test_cidr() {
+        let v4 = Cidr::parse("10.0.0.0/8").unwrap();
+        let v6 = Cidr::parse("2001:db8::/32").unwrap();
+
+        assert!(v4.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(v4.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!v4.contains(&"11.1.2.3".parse().unwrap()));
+        assert!(v6.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!v6.contains(&"2001:db9::1".parse().unwrap()));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&"1.2.3.4".parse().unwrap()));
+        assert!(Cidr::parse("127.0.0.1").unwrap().contains(&"127.0.0.1".parse().unwrap()));
+
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("kulli/8").is_err());
+    }
+
+    #[test]
+    fn test_without_config() {
+        let resolver = ClientIpResolver { trusted_proxies: None };
+        let with_header = headers(&[("x-real-ip", "1.1.1.1"), ("x-forwarded-for", "2.2.2.2")]);
+
+        assert_eq!(ip("1.1.1.1"), resolver.resolve(&with_header, ip("10.0.0.1")));
+        assert_eq!(ip("10.0.0.1"), resolver.resolve(&HeaderMap::new(), ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_untrusted_peer_ignores_headers() {
+        let resolver = resolver(&["10.0.0.0/8"]);
+        let spoofed = headers(&[("x-real-ip", "1.1.1.1"), ("x-forwarded-for", "2.2.2.2")]);
+
+        assert_eq!(ip("3.3.3.3"), resolver.resolve(&spoofed, ip("3.3.3.3")));
+    }
+
+    #[test]
+    fn test_trusted_chain() {
+        let resolver = resolver(&["10.0.0.0/8"]);
+        let headers = headers(&[("x-forwarded-for", "1.1.1.1, 10.0.0.2")]);
+
+        assert_eq!(ip("1.1.1.1"), resolver.resolve(&headers, ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_chain_with_untrusted_hops() {
+        let resolver = resolver(&["10.0.0.0/8"]);
+
+        // 4.4.4.4 connected to our proxies. Whatever it claims to forward,
+        // and the client may have sent, can't be trusted.
+        let headers = headers(&[
+            ("x-forwarded-for", "6.6.6.6, 1.1.1.1"),
+            ("x-forwarded-for", "4.4.4.4, 10.0.0.3"),
+        ]);
+
+        assert_eq!(ip("4.4.4.4"), resolver.resolve(&headers, ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_all_trusted_takes_the_first() {
+        let resolver = resolver(&["10.0.0.0/8"]);
+        let headers = headers(&[("x-forwarded-for", "10.0.0.5, 10.0.0.2")]);
+
+        assert_eq!(ip("10.0.0.5"), resolver.resolve(&headers, ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_unknown_hop_stops_the_walk() {
+        let resolver = resolver(&["10.0.0.0/8"]);
+        let headers = headers(&[("x-forwarded-for", "1.1.1.1, unknown, 10.0.0.2")]);
+
+        assert_eq!(None, resolver.resolve(&headers, ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_forwarded_header() {
+        let resolver = resolver(&["10.0.0.0/8"]);
+
+        let headers = headers(&[
+            ("forwarded", r#"for="[2001:db8:cafe::17]:4711";proto=https, For=10.0.0.2:8080"#),
+            ("x-forwarded-for", "6.6.6.6"),
+        ]);
+
+        assert_eq!(ip("2001:db8:cafe::17"), resolver.resolve(&headers, ip("10.0.0.1")));
+
+        let headers = self::headers(&[("forwarded", "for=192.0.2.60;proto=http;by=203.0.113.43")]);
+        assert_eq!(ip("192.0.2.60"), resolver.resolve(&headers, ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_trusted_peer_without_chain() {
+        let resolver = resolver(&["10.0.0.0/8"]);
+
+        assert_eq!(ip("10.0.0.1"), resolver.resolve(&HeaderMap::new(), ip("10.0.0.1")));
+        assert_eq!(
+            ip("1.1.1.1"),
+            resolver.resolve(&headers(&[("x-real-ip", "1.1.1.1")]), ip("10.0.0.1"))
+        );
+    }
+
+    #[test]
+    fn test_mapped_peer() {
+        let resolver = resolver(&["10.0.0.0/8"]);
+        let headers = headers(&[("x-forwarded-for", "1.1.1.1")]);
+
+        assert_eq!(ip("1.1.1.1"), resolver.resolve(&headers, ip("::ffff:10.0.0.1")));
+        assert_eq!(ip("3.3.3.3"), resolver.resolve(&headers, ip("::ffff:3.3.3.3")));
+    }
+
+    #[test]
+    fn test_trusted_peer() {
+        let resolver = resolver(&["10.0.0.0/8"]);
+
+        assert!(resolver.trusted_peer(ip("10.0.0.1")));
+        assert!(resolver.trusted_peer(ip("::ffff:10.0.0.1")));
+        assert!(!resolver.trusted_peer(ip("1.1.1.1")));
+        assert!(!resolver.trusted_peer(None));
+        assert!(!ClientIpResolver { trusted_proxies: None }.trusted_peer(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn test_replay_trusts_the_archived_chain() {
+        let resolver = resolver(&["10.0.0.0/8"]);
+        let headers = headers(&[("x-forwarded-for", "1.1.1.1, 10.0.0.2")]);
+
+        assert_eq!(ip("1.1.1.1"), resolver.resolve(&headers, None));
+    }
+}
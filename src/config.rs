@@ -1,7 +1,18 @@
 use std::fs::File;
 use std::io::prelude::*;
+use std::{env, str::FromStr, net::SocketAddr, collections::HashMap};
+use slog::Level;
 use toml;
-use ::RUST_ENV;
+use hex;
+use consent::ConsentAction;
+use cors::{self, OriginPattern};
+use policy::EnvironmentPolicy;
+use redaction::Redaction;
+use access_log::ClientIp;
+use client_ip::Cidr;
+use ip_hash::{IpHashMode, MIN_SECRET_LENGTH};
+use ifa_matching;
+use serde::de::DeserializeOwned;
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
@@ -11,53 +22,455 @@ pub struct Config {
     pub origins: Option<Vec<OriginConfig>>,
     pub test_apps: Option<Vec<TestAppConfig>>,
     pub cassandra: CassandraConfig,
+    pub admin: Option<AdminConfig>,
+    pub consent_policies: Option<Vec<ConsentPolicyConfig>>,
+    pub archive: Option<ArchiveConfig>,
+    pub log_redaction: Option<HashMap<String, LogRedactionConfig>>,
+    pub metrics: Option<MetricsConfig>,
+    pub tracing: Option<TracingConfig>,
+    pub access_log: Option<AccessLogConfig>,
+    pub geoip: Option<GeoIpConfig>,
+    pub client_ip: Option<ClientIpConfig>,
+    pub ip_hash: Option<HashMap<String, IpHashConfig>>,
+    pub user_agent: Option<UserAgentConfig>,
 }
 
+/// Prefix for environment variables overriding configuration keys, the
+/// sections and keys separated with `__`, e.g. `XORC__KAFKA__TOPIC`.
+const ENV_PREFIX: &'static str = "XORC__";
+
 impl Config {
+    /// The configuration file location from the `CONFIG` environment
+    /// variable.
+    pub fn path() -> String {
+        env::var("CONFIG").unwrap_or_else(|_| {
+            String::from("./config/config.toml.tests")
+        })
+    }
+
     pub fn parse(path: &str) -> Config {
+        Self::load(path).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Reads and validates the configuration, used at startup and when
+    /// reloading. Values from `XORC__` prefixed environment variables
+    /// override the values in the file.
+    pub fn load(path: &str) -> Result<Config, String> {
         let mut config_toml = String::new();
 
-        let mut file = match File::open(&path) {
-            Ok(file) => file,
+        let mut file = File::open(&path)
+            .map_err(|err| format!("Error while reading config file: [{}]", err))?;
+
+        file.read_to_string(&mut config_toml)
+            .map_err(|err| format!("Error while reading config: [{}]", err))?;
+
+        Self::from_toml(&config_toml, env::vars())
+    }
+
+    /// Parses the configuration with the overrides from the environment
+    /// variables in `vars`, which also give the `RUST_ENV`, `SECRET` and
+    /// `IFA_PEPPER` for validation.
+    fn from_toml<I>(config_toml: &str, vars: I) -> Result<Config, String>
+    where
+        I: Iterator<Item=(String, String)>
+    {
+        let mut value: toml::Value = config_toml.parse()
+            .map_err(|err| format!("Error while reading config: [{}]", err))?;
+
+        let vars: HashMap<String, String> = vars.collect();
+        let mut errors = Self::apply_env_overrides(&mut value, vars.clone().into_iter());
+
+        let config: Config = match value.clone().try_into() {
+            Ok(config) => config,
             Err(err) => {
-                panic!("Error while reading config file: [{}]", err);
+                let mut section_errors = Self::section_errors(&value);
+
+                if section_errors.is_empty() {
+                    section_errors.push(err.to_string());
+                }
+
+                errors.extend(section_errors);
+
+                return Err(Self::format_errors(&errors))
             }
         };
 
-        file.read_to_string(&mut config_toml)
-            .unwrap_or_else(|err| panic!("Error while reading config: [{}]", err));
+        if let Err(validation_errors) = config.validate(&vars) {
+            errors.extend(validation_errors);
+        }
 
-        let config: Config = toml::from_str(&config_toml)
-            .unwrap_or_else(|err| {
-                panic!("Error while reading config: [{}]", err)
-            });
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(Self::format_errors(&errors))
+        }
+    }
 
-        if &*RUST_ENV != "development" {
-            if config.gateway.allow_empty_signature {
-                panic!("Cannot allow empty signatures outside of development environment.")
+    /// Deserializes every section by itself, so a faulty value in one
+    /// doesn't hide the faulty values in the others.
+    fn section_errors(value: &toml::Value) -> Vec<String> {
+        fn check<T: DeserializeOwned>(value: &toml::Value, key: &str, errors: &mut Vec<String>) {
+            if let Some(section) = value.get(key) {
+                if let Err(err) = section.clone().try_into::<T>() {
+                    errors.push(format!("{}: {}", key, err));
+                }
             }
+        }
 
-            if config.cassandra.manage_apps {
-                panic!("Cannot allow manage_apps to be false outside of development environment.")
+        let mut errors = Vec::new();
+
+        check::<GatewayConfig>(value, "gateway", &mut errors);
+        check::<KafkaConfig>(value, "kafka", &mut errors);
+        check::<CassandraConfig>(value, "cassandra", &mut errors);
+        check::<CorsConfig>(value, "cors", &mut errors);
+        check::<Vec<OriginConfig>>(value, "origins", &mut errors);
+        check::<Vec<TestAppConfig>>(value, "test_apps", &mut errors);
+        check::<AdminConfig>(value, "admin", &mut errors);
+        check::<Vec<ConsentPolicyConfig>>(value, "consent_policies", &mut errors);
+        check::<ArchiveConfig>(value, "archive", &mut errors);
+        check::<HashMap<String, LogRedactionConfig>>(value, "log_redaction", &mut errors);
+        check::<MetricsConfig>(value, "metrics", &mut errors);
+        check::<TracingConfig>(value, "tracing", &mut errors);
+        check::<AccessLogConfig>(value, "access_log", &mut errors);
+        check::<GeoIpConfig>(value, "geoip", &mut errors);
+        check::<ClientIpConfig>(value, "client_ip", &mut errors);
+        check::<HashMap<String, IpHashConfig>>(value, "ip_hash", &mut errors);
+        check::<UserAgentConfig>(value, "user_agent", &mut errors);
+
+        for key in ["gateway", "kafka", "cassandra"].iter() {
+            if value.get(key).is_none() {
+                errors.push(format!("{}: missing section", key));
             }
         }
 
-        config
+        errors
+    }
+
+    fn format_errors(errors: &[String]) -> String {
+        let lines: Vec<String> = errors.iter().map(|e| format!("  - {}", e)).collect();
+        format!("Invalid configuration:\n{}", lines.join("\n"))
+    }
+
+    /// Sets the values from the `XORC__` environment variables to the
+    /// configuration. Array items are referenced by their index, e.g.
+    /// `XORC__TEST_APPS__0__TOKEN`. If the key exists in the file as a
+    /// string, the value is used as is. Otherwise it's parsed as a TOML value,
+    /// falling back to a string.
+    fn apply_env_overrides<I>(config: &mut toml::Value, vars: I) -> Vec<String>
+    where
+        I: Iterator<Item=(String, String)>
+    {
+        let mut errors = Vec::new();
+
+        for (key, raw) in vars {
+            if !key.starts_with(ENV_PREFIX) { continue }
+
+            let path: Vec<String> = key[ENV_PREFIX.len()..]
+                .split("__")
+                .map(|s| s.to_lowercase())
+                .collect();
+
+            if path.iter().any(|s| s.is_empty()) {
+                errors.push(format!("{}: invalid environment variable name", key));
+                continue
+            }
+
+            if let Err(err) = Self::set_path(config, &path, &raw) {
+                errors.push(format!("{}: {}", path.join("."), err));
+            }
+        }
+
+        errors
+    }
+
+    fn set_path(value: &mut toml::Value, path: &[String], raw: &str) -> Result<(), String> {
+        match path.split_first() {
+            None => {
+                *value = if value.is_str() {
+                    toml::Value::String(raw.to_string())
+                } else {
+                    Self::parse_env_value(raw)
+                };
+
+                Ok(())
+            },
+            Some((key, rest)) => match value {
+                toml::Value::Table(table) => {
+                    let entry = table
+                        .entry(key.clone())
+                        .or_insert_with(|| toml::Value::Table(toml::value::Table::new()));
+
+                    Self::set_path(entry, rest, raw)
+                },
+                toml::Value::Array(array) => {
+                    let index: usize = key.parse()
+                        .map_err(|_| format!("`{}` is not an array index", key))?;
+
+                    if index == array.len() {
+                        array.push(toml::Value::Table(toml::value::Table::new()));
+                    }
+
+                    match array.get_mut(index) {
+                        Some(item) => Self::set_path(item, rest, raw),
+                        None => Err(format!("array index {} is out of bounds", index)),
+                    }
+                },
+                _ => Err(format!("`{}` is not a section", key)),
+            }
+        }
+    }
+
+    fn parse_env_value(raw: &str) -> toml::Value {
+        format!("value = {}", raw)
+            .parse::<toml::Value>()
+            .ok()
+            .and_then(|v| v.get("value").cloned())
+            .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+    }
+
+    /// Checks the whole configuration, returning all the problems found with
+    /// their key paths. The environment is read from `vars`.
+    fn validate(&self, vars: &HashMap<String, String>) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        if self.gateway.threads == 0 {
+            errors.push(String::from("gateway.threads: must be at least 1"));
+        }
+
+        if let Some(ref address) = self.gateway.address {
+            if address.parse::<SocketAddr>().is_err() {
+                errors.push(format!("gateway.address: `{}` is not an ip:port address", address));
+            }
+        }
+
+        if let Some(ref level) = self.gateway.log_level {
+            if Level::from_str(level).is_err() {
+                errors.push(format!("gateway.log_level: `{}` is not a log level", level));
+            }
+        }
+
+        if self.gateway.default_token.trim().is_empty() {
+            errors.push(String::from("gateway.default_token: must not be empty"));
+        }
+
+        if self.kafka.brokers.trim().is_empty() {
+            errors.push(String::from("kafka.brokers: must not be empty"));
+        }
+
+        if self.kafka.topic.trim().is_empty() {
+            errors.push(String::from("kafka.topic: must not be empty"));
+        }
+
+        if self.cassandra.contact_points.trim().is_empty() {
+            errors.push(String::from("cassandra.contact_points: must not be empty"));
+        }
+
+        if self.cassandra.keyspace.trim().is_empty() {
+            errors.push(String::from("cassandra.keyspace: must not be empty"));
+        }
+
+        if let Some(ref cors) = self.cors {
+            if let Err(err) = cors::check_header_value(&cors.allowed_methods) {
+                errors.push(format!("cors.allowed_methods: {}", err));
+            }
+
+            if let Err(err) = cors::check_header_value(&cors.allowed_headers) {
+                errors.push(format!("cors.allowed_headers: {}", err));
+            }
+        }
+
+        if let Some(ref origins) = self.origins {
+            for (i, origin) in origins.iter().enumerate() {
+                for (j, pattern) in origin.allowed.iter().enumerate() {
+                    if let Err(err) = OriginPattern::parse(pattern) {
+                        errors.push(format!("origins[{}].allowed[{}]: {}", i, j, err));
+                    }
+                }
+
+                if let Some(Err(err)) = origin.allowed_methods.as_ref().map(|v| cors::check_header_value(v)) {
+                    errors.push(format!("origins[{}].allowed_methods: {}", i, err));
+                }
+
+                if let Some(Err(err)) = origin.allowed_headers.as_ref().map(|v| cors::check_header_value(v)) {
+                    errors.push(format!("origins[{}].allowed_headers: {}", i, err));
+                }
+            }
+        }
+
+        if !self.cassandra.manage_apps && self.test_apps.is_none() {
+            errors.push(String::from("test_apps: at least one needed if cassandra.manage_apps is false"));
+        }
+
+        if let Some(ref test_apps) = self.test_apps {
+            for (i, test_app) in test_apps.iter().enumerate() {
+                let secrets = [
+                    ("secret_ios", &test_app.secret_ios),
+                    ("secret_android", &test_app.secret_android),
+                    ("secret_web", &test_app.secret_web),
+                ];
+
+                for (key, secret) in secrets.iter() {
+                    if let Some(secret) = secret {
+                        if hex::decode(secret).is_err() {
+                            errors.push(format!("test_apps[{}].{}: not a hex string", i, key));
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(ref admin) = self.admin {
+            if admin.token.is_empty() {
+                errors.push(String::from("admin.token: must not be empty"));
+            }
+        }
+
+        if let Some(ref archive) = self.archive {
+            if archive.directory.trim().is_empty() {
+                errors.push(String::from("archive.directory: must not be empty"));
+            }
+
+            if !Self::valid_rate(archive.sample_rate) {
+                errors.push(String::from("archive.sample_rate: must be between 0.0 and 1.0"));
+            }
+
+            for (i, app) in archive.apps.iter().enumerate() {
+                if !Self::valid_rate(app.sample_rate) {
+                    errors.push(format!("archive.apps[{}].sample_rate: must be between 0.0 and 1.0", i));
+                }
+            }
+
+            if archive.max_file_size == Some(0) {
+                errors.push(String::from("archive.max_file_size: must be at least 1"));
+            }
+
+            if archive.max_files == Some(0) {
+                errors.push(String::from("archive.max_files: must be at least 1"));
+            }
+        }
+
+        if let Some(ref metrics) = self.metrics {
+            if metrics.max_app_ids == Some(0) {
+                errors.push(String::from("metrics.max_app_ids: must be at least 1"));
+            }
+
+            if metrics.max_sdk_versions == Some(0) {
+                errors.push(String::from("metrics.max_sdk_versions: must be at least 1"));
+            }
+        }
+
+        if let Some(ref geoip) = self.geoip {
+            if geoip.check_interval == Some(0) {
+                errors.push(String::from("geoip.check_interval: must be at least 1"));
+            }
+        }
+
+        if let Some(ref client_ip) = self.client_ip {
+            for (i, proxy) in client_ip.trusted_proxies.iter().enumerate() {
+                if let Err(err) = Cidr::parse(proxy) {
+                    errors.push(format!("client_ip.trusted_proxies[{}]: {}", i, err));
+                }
+            }
+        }
+
+        if let Some(ref environments) = self.ip_hash {
+            for (environment, ip_hash) in environments.iter() {
+                let key = format!("ip_hash.{}", environment);
+
+                if ip_hash.mode == IpHashMode::Keyed {
+                    match ip_hash.secret.as_ref().map(hex::decode) {
+                        None => errors.push(format!("{}.secret: required with the keyed mode", key)),
+                        Some(Err(_)) => errors.push(format!("{}.secret: not a hex string", key)),
+                        Some(Ok(ref secret)) if secret.len() < MIN_SECRET_LENGTH => {
+                            errors.push(format!("{}.secret: must be at least {} bytes", key, MIN_SECRET_LENGTH));
+                        },
+                        Some(Ok(_)) => (),
+                    }
+                }
+
+                if ip_hash.rotation == Some(0) {
+                    errors.push(format!("{}.rotation: must be at least 1", key));
+                }
+            }
+        }
+
+        if let Some(ref user_agent) = self.user_agent {
+            if user_agent.database.trim().is_empty() {
+                errors.push(String::from("user_agent.database: must not be empty"));
+            }
+
+            if user_agent.cache_size == Some(0) {
+                errors.push(String::from("user_agent.cache_size: must be at least 1"));
+            }
+        }
+
+        if let Some(ref access_log) = self.access_log {
+            if let Some(rate) = access_log.success_sample_rate {
+                if !Self::valid_rate(rate) {
+                    errors.push(String::from("access_log.success_sample_rate: must be between 0.0 and 1.0"));
+                }
+            }
+        }
+
+        if let Some(ref tracing) = self.tracing {
+            if tracing.agent_address.parse::<SocketAddr>().is_err() {
+                errors.push(format!(
+                    "tracing.agent_address: `{}` is not an ip:port address",
+                    tracing.agent_address
+                ));
+            }
+
+            if !Self::valid_rate(tracing.sample_rate) {
+                errors.push(String::from("tracing.sample_rate: must be between 0.0 and 1.0"));
+            }
+        }
+
+        let environment = vars.get("RUST_ENV").map(|s| s.as_str()).unwrap_or("development");
+        let secret = vars.get("SECRET");
+        let ifa_pepper = vars.get("IFA_PEPPER");
+
+        if self.cassandra.hash_ifas {
+            if let Some(Err(err)) = ifa_pepper.map(|p| ifa_matching::decode_pepper(p)) {
+                errors.push(format!("IFA_PEPPER: {}", err));
+            }
+        }
+
+        let policy = EnvironmentPolicy::new(
+            environment,
+            secret.map(|s| s.as_str()),
+            ifa_pepper.map(|s| s.as_str()),
+        );
+
+        errors.extend(policy.check(self));
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn valid_rate(rate: f64) -> bool {
+        rate >= 0.0 && rate <= 1.0
     }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct GatewayConfig {
+    pub address: Option<String>,
     pub threads: usize,
     pub process_name_prefix: String,
     pub default_token: String,
     pub allow_empty_signature: bool,
+    pub log_level: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct OriginConfig {
     pub app_id: String,
     pub allowed: Vec<String>,
+    pub allowed_methods: Option<String>,
+    pub allowed_headers: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -69,10 +482,22 @@ pub struct TestAppConfig {
     pub secret_web: Option<String>,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct ConsentPolicyConfig {
+    pub app_id: String,
+    #[serde(default)]
+    pub analytics: Vec<ConsentAction>,
+    #[serde(default)]
+    pub advertising: Vec<ConsentAction>,
+    #[serde(default)]
+    pub personalization: Vec<ConsentAction>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct CorsConfig {
     pub allowed_methods: String,
     pub allowed_headers: String,
+    pub max_age: Option<u32>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -82,6 +507,95 @@ pub struct CassandraConfig {
     pub username: Option<String>,
     pub password: Option<String>,
     pub manage_apps: bool,
+    #[serde(default)]
+    pub hash_ifas: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AdminConfig {
+    pub token: String,
+    pub audit_topic: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ArchiveConfig {
+    pub directory: String,
+    pub sample_rate: f64,
+    pub max_file_size: Option<u64>,
+    pub max_files: Option<usize>,
+    #[serde(default)]
+    pub apps: Vec<ArchiveAppConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ArchiveAppConfig {
+    pub app_id: String,
+    pub sample_rate: f64,
+}
+
+/// Redactions for the logged fields in one environment, the ones not set
+/// use the defaults of the environment.
+#[derive(Deserialize, Debug)]
+pub struct LogRedactionConfig {
+    pub api_token: Option<Redaction>,
+    pub signature: Option<Redaction>,
+    pub device_id: Option<Redaction>,
+    pub encrypted_device_id: Option<Redaction>,
+}
+
+/// Parsing the user agents of web devices.
+#[derive(Deserialize, Debug)]
+pub struct UserAgentConfig {
+    pub database: String,
+    pub cache_size: Option<usize>,
+}
+
+/// Pseudonymization of the client address in one environment.
+#[derive(Deserialize, Debug)]
+pub struct IpHashConfig {
+    pub mode: IpHashMode,
+    pub secret: Option<String>,
+    pub rotation: Option<u64>,
+}
+
+/// Limits for the per-app and per-SDK version metric labels.
+#[derive(Deserialize, Debug)]
+pub struct MetricsConfig {
+    pub app_ids: Option<Vec<String>>,
+    pub max_app_ids: Option<usize>,
+    pub sdk_versions: Option<Vec<String>>,
+    pub max_sdk_versions: Option<usize>,
+}
+
+/// Databases for locating the clients besides the country database.
+#[derive(Deserialize, Debug)]
+pub struct GeoIpConfig {
+    pub city_database: Option<String>,
+    pub asn_database: Option<String>,
+    pub check_interval: Option<u64>,
+}
+
+/// The proxies allowed to tell the client address in the forwarding
+/// headers.
+#[derive(Deserialize, Debug)]
+pub struct ClientIpConfig {
+    pub trusted_proxies: Vec<String>,
+}
+
+/// One line per SDK request, to a file or stdout.
+#[derive(Deserialize, Debug)]
+pub struct AccessLogConfig {
+    pub path: Option<String>,
+    pub success_sample_rate: Option<f64>,
+    pub client_ip: Option<ClientIp>,
+}
+
+/// Reporting the sampled request spans to a Jaeger agent.
+#[derive(Deserialize, Debug)]
+pub struct TracingConfig {
+    pub agent_address: String,
+    pub service_name: Option<String>,
+    pub sample_rate: f64,
 }
 
 #[derive(Deserialize, Debug)]
@@ -89,3 +603,266 @@ pub struct KafkaConfig {
     pub topic: String,
     pub brokers: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG_TOML: &'static str = r#"
+        [gateway]
+        threads = 4
+        process_name_prefix = "sdk-gateway-worker-"
+        default_token = "token"
+        allow_empty_signature = false
+
+        [kafka]
+        brokers = "kafka:9092"
+        topic = "sdk"
+
+        [cassandra]
+        keyspace = "sdk"
+        contact_points = "localhost:9042"
+        manage_apps = false
+
+        [[test_apps]]
+        app_id = "22222222-0000-0000-0000-000000000000"
+        token = "1234"
+    "#;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_without_overrides() {
+        let config = Config::from_toml(CONFIG_TOML, Vec::new().into_iter()).unwrap();
+
+        assert_eq!("sdk", config.kafka.topic);
+        assert!(config.gateway.address.is_none());
+    }
+
+    #[test]
+    fn test_override_string() {
+        let vars = vars(&[("XORC__KAFKA__TOPIC", "events")]);
+        let config = Config::from_toml(CONFIG_TOML, vars.into_iter()).unwrap();
+
+        assert_eq!("events", config.kafka.topic);
+    }
+
+    #[test]
+    fn test_override_integer() {
+        let vars = vars(&[("XORC__GATEWAY__THREADS", "16")]);
+        let config = Config::from_toml(CONFIG_TOML, vars.into_iter()).unwrap();
+
+        assert_eq!(16, config.gateway.threads);
+    }
+
+    #[test]
+    fn test_override_numeric_string() {
+        let vars = vars(&[("XORC__TEST_APPS__0__TOKEN", "5678")]);
+        let config = Config::from_toml(CONFIG_TOML, vars.into_iter()).unwrap();
+
+        assert_eq!(
+            Some(String::from("5678")),
+            config.test_apps.unwrap()[0].token
+        );
+    }
+
+    #[test]
+    fn test_override_missing_key() {
+        let vars = vars(&[("XORC__GATEWAY__ADDRESS", "127.0.0.1:8080")]);
+        let config = Config::from_toml(CONFIG_TOML, vars.into_iter()).unwrap();
+
+        assert_eq!(Some(String::from("127.0.0.1:8080")), config.gateway.address);
+    }
+
+    #[test]
+    fn test_override_new_section() {
+        let vars = vars(&[
+            ("XORC__CORS__ALLOWED_METHODS", "POST"),
+            ("XORC__CORS__ALLOWED_HEADERS", "Content-Type"),
+        ]);
+
+        let config = Config::from_toml(CONFIG_TOML, vars.into_iter()).unwrap();
+
+        assert_eq!("POST", config.cors.unwrap().allowed_methods);
+    }
+
+    #[test]
+    fn test_ignores_other_variables() {
+        let vars = vars(&[("KAFKA__TOPIC", "events")]);
+        let config = Config::from_toml(CONFIG_TOML, vars.into_iter()).unwrap();
+
+        assert_eq!("sdk", config.kafka.topic);
+    }
+
+    #[test]
+    fn test_override_into_a_value() {
+        let vars = vars(&[("XORC__KAFKA__TOPIC__NAME", "events")]);
+        let err = Config::from_toml(CONFIG_TOML, vars.into_iter()).unwrap_err();
+
+        assert!(err.contains("kafka.topic.name"));
+    }
+
+    #[test]
+    fn test_reports_all_errors() {
+        let vars = vars(&[
+            ("XORC__GATEWAY__THREADS", "0"),
+            ("XORC__GATEWAY__ADDRESS", "kulli"),
+            ("XORC__GATEWAY__DEFAULT_TOKEN", ""),
+            ("XORC__KAFKA__TOPIC", ""),
+            ("XORC__TEST_APPS__0__SECRET_WEB", "not hex"),
+        ]);
+
+        let err = Config::from_toml(CONFIG_TOML, vars.into_iter()).unwrap_err();
+
+        assert!(err.contains("gateway.threads"));
+        assert!(err.contains("gateway.address"));
+        assert!(err.contains("gateway.default_token"));
+        assert!(err.contains("kafka.topic"));
+        assert!(err.contains("test_apps[0].secret_web"));
+    }
+
+    #[test]
+    fn test_reports_type_errors_together() {
+        let vars = vars(&[
+            ("XORC__GATEWAY__THREADS", "many"),
+            ("XORC__CASSANDRA__MANAGE_APPS", "maybe"),
+            ("XORC__KAFKA__TOPIC__NAME", "events"),
+        ]);
+
+        let err = Config::from_toml(CONFIG_TOML, vars.into_iter()).unwrap_err();
+
+        assert!(err.contains("gateway:"));
+        assert!(err.contains("cassandra:"));
+        assert!(err.contains("kafka.topic.name"));
+    }
+
+    #[test]
+    fn test_missing_section() {
+        let config_toml = r#"
+            [gateway]
+            threads = 4
+            process_name_prefix = "sdk-gateway-worker-"
+            default_token = "token"
+            allow_empty_signature = false
+        "#;
+
+        let err = Config::from_toml(config_toml, Vec::new().into_iter()).unwrap_err();
+
+        assert!(err.contains("kafka: missing section"));
+        assert!(err.contains("cassandra: missing section"));
+    }
+
+    #[test]
+    fn test_environment_from_vars() {
+        let vars = vars(&[("RUST_ENV", "production")]);
+        let err = Config::from_toml(CONFIG_TOML, vars.into_iter()).unwrap_err();
+
+        assert!(err.contains("SECRET: Please set"));
+        assert!(err.contains("cassandra.manage_apps"));
+    }
+
+    #[test]
+    fn test_invalid_ifa_pepper() {
+        let vars = vars(&[
+            ("XORC__CASSANDRA__HASH_IFAS", "true"),
+            ("IFA_PEPPER", "c2hvcnQ"),
+        ]);
+
+        let err = Config::from_toml(CONFIG_TOML, vars.into_iter()).unwrap_err();
+
+        assert!(err.contains("IFA_PEPPER: Must be at least"));
+    }
+
+    #[test]
+    fn test_invalid_origin() {
+        let vars = vars(&[
+            ("XORC__ORIGINS__0__APP_ID", "22222222-0000-0000-0000-000000000000"),
+            ("XORC__ORIGINS__0__ALLOWED", "[\"https://example.com\", \"kulli\"]"),
+        ]);
+
+        let err = Config::from_toml(CONFIG_TOML, vars.into_iter()).unwrap_err();
+
+        assert!(err.contains("origins[0].allowed[1]"));
+    }
+
+    #[test]
+    fn test_invalid_origin_headers() {
+        let vars = vars(&[
+            ("XORC__ORIGINS__0__APP_ID", "22222222-0000-0000-0000-000000000000"),
+            ("XORC__ORIGINS__0__ALLOWED", "[\"https://example.com\"]"),
+            ("XORC__ORIGINS__0__ALLOWED_HEADERS", "Content-Type,\nX-Kulli"),
+        ]);
+
+        let err = Config::from_toml(CONFIG_TOML, vars.into_iter()).unwrap_err();
+
+        assert!(err.contains("origins[0].allowed_headers"));
+        assert!(!err.contains("origins[0].allowed_methods"));
+    }
+
+    #[test]
+    fn test_invalid_archive_sample_rates() {
+        let config_toml = format!(r#"{}
+            [archive]
+            directory = "/tmp/archive"
+            sample_rate = 1.5
+
+            [[archive.apps]]
+            app_id = "22222222-0000-0000-0000-000000000000"
+            sample_rate = -0.1
+        "#, CONFIG_TOML);
+
+        let err = Config::from_toml(&config_toml, Vec::new().into_iter()).unwrap_err();
+
+        assert!(err.contains("archive.sample_rate"));
+        assert!(err.contains("archive.apps[0].sample_rate"));
+    }
+
+    #[test]
+    fn test_invalid_tracing() {
+        let config_toml = format!(r#"{}
+            [tracing]
+            agent_address = "localhost"
+            sample_rate = 2.0
+        "#, CONFIG_TOML);
+
+        let err = Config::from_toml(&config_toml, Vec::new().into_iter()).unwrap_err();
+
+        assert!(err.contains("tracing.agent_address"));
+        assert!(err.contains("tracing.sample_rate"));
+    }
+
+    #[test]
+    fn test_invalid_ip_hash() {
+        let config_toml = format!(r#"{}
+            [ip_hash.production]
+            mode = "keyed"
+            secret = "abcd"
+            rotation = 0
+
+            [ip_hash.staging]
+            mode = "keyed"
+        "#, CONFIG_TOML);
+
+        let err = Config::from_toml(&config_toml, Vec::new().into_iter()).unwrap_err();
+
+        assert!(err.contains("ip_hash.production.secret: must be at least 32 bytes"));
+        assert!(err.contains("ip_hash.production.rotation"));
+        assert!(err.contains("ip_hash.staging.secret: required"));
+    }
+
+    #[test]
+    fn test_invalid_trusted_proxies() {
+        let config_toml = format!(r#"{}
+            [client_ip]
+            trusted_proxies = ["10.0.0.0/8", "10.0.0.0/33", "localhost"]
+        "#, CONFIG_TOML);
+
+        let err = Config::from_toml(&config_toml, Vec::new().into_iter()).unwrap_err();
+
+        assert!(!err.contains("client_ip.trusted_proxies[0]"));
+        assert!(err.contains("client_ip.trusted_proxies[1]"));
+        assert!(err.contains("client_ip.trusted_proxies[2]"));
+    }
+}
@@ -0,0 +1,321 @@
+use std::{collections::HashMap, sync::Arc};
+use events::{input::SDKConsent, output};
+use config::Config;
+use crossbeam::sync::ArcCell;
+
+use ::CONFIG;
+
+/// What to do with the request if the user didn't give the consent.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsentAction {
+    DropEvents,
+    StripIfa,
+    StripIdfv,
+    StripIpHash,
+    StripCountry,
+    SkipIfaMatching,
+}
+
+impl<'a> From<&'a ConsentAction> for &'static str {
+    fn from(action: &'a ConsentAction) -> &'static str {
+        match action {
+            ConsentAction::DropEvents => "drop_events",
+            ConsentAction::StripIfa => "strip_ifa",
+            ConsentAction::StripIdfv => "strip_idfv",
+            ConsentAction::StripIpHash => "strip_ip_hash",
+            ConsentAction::StripCountry => "strip_country",
+            ConsentAction::SkipIfaMatching => "skip_ifa_matching",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsentState {
+    Granted,
+    Denied,
+    Unknown,
+}
+
+impl From<Option<bool>> for ConsentState {
+    fn from(flag: Option<bool>) -> ConsentState {
+        match flag {
+            Some(true) => ConsentState::Granted,
+            Some(false) => ConsentState::Denied,
+            None => ConsentState::Unknown,
+        }
+    }
+}
+
+impl<'a> From<&'a ConsentState> for &'static str {
+    fn from(state: &'a ConsentState) -> &'static str {
+        match state {
+            ConsentState::Granted => "granted",
+            ConsentState::Denied => "denied",
+            ConsentState::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct AppPolicy {
+    analytics: Vec<ConsentAction>,
+    advertising: Vec<ConsentAction>,
+    personalization: Vec<ConsentAction>,
+}
+
+/// The consent of the request and the actions taken because of it. Carried
+/// in the context and sent downstream as Kafka headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppliedConsent {
+    pub analytics: ConsentState,
+    pub advertising: ConsentState,
+    pub personalization: ConsentState,
+    pub actions: Vec<ConsentAction>,
+}
+
+impl Default for AppliedConsent {
+    fn default() -> AppliedConsent {
+        AppliedConsent {
+            analytics: ConsentState::Unknown,
+            advertising: ConsentState::Unknown,
+            personalization: ConsentState::Unknown,
+            actions: Vec::new(),
+        }
+    }
+}
+
+impl AppliedConsent {
+    pub fn has(&self, action: ConsentAction) -> bool {
+        // Events that are dropped are not matched to an entity either
+        let implied = action == ConsentAction::SkipIfaMatching
+            && self.actions.contains(&ConsentAction::DropEvents);
+
+        implied || self.actions.contains(&action)
+    }
+
+    /// Removes the fields from the outgoing event the user didn't consent
+    /// to share.
+    pub fn strip(&self, proto: &mut output::events::SdkEventBatch) {
+        if let Some(ref mut device) = proto.device {
+            if self.has(ConsentAction::StripIfa) {
+                device.ifa = None;
+            }
+
+            if self.has(ConsentAction::StripIdfv) {
+                device.idfv = None;
+            }
+
+            if self.has(ConsentAction::StripIpHash) {
+                device.ip_hashed_blake2 = None;
+            }
+
+            if self.has(ConsentAction::StripCountry) {
+                device.country = None;
+            }
+        }
+    }
+
+    /// Header key-value pairs describing the consent for downstream. Sent
+    /// as Kafka headers, the event proto `Header` has no consent fields yet.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let actions: Vec<&'static str> = self.actions.iter().map(|a| a.into()).collect();
+
+        vec![
+            ("consent_analytics", <&'static str>::from(&self.analytics).to_string()),
+            ("consent_advertising", <&'static str>::from(&self.advertising).to_string()),
+            ("consent_personalization", <&'static str>::from(&self.personalization).to_string()),
+            ("consent_actions", actions.join(",")),
+        ]
+    }
+}
+
+/// Per-app consent policies from the `[[consent_policies]]` configuration.
+/// Apps without a policy don't get any actions applied.
+pub struct Consent {
+    policies: ArcCell<HashMap<String, AppPolicy>>,
+}
+
+impl Consent {
+    pub fn new() -> Consent {
+        Self::from_config(&CONFIG)
+    }
+
+    pub fn from_config(config: &Config) -> Consent {
+        Consent { policies: ArcCell::new(Arc::new(Self::policies(config))) }
+    }
+
+    /// Replaces the policies from a reloaded configuration.
+    pub fn reload(&self, config: &Config) {
+        self.policies.set(Arc::new(Self::policies(config)));
+    }
+
+    fn policies(config: &Config) -> HashMap<String, AppPolicy> {
+        config.consent_policies.as_ref()
+            .map(|policies| {
+                policies.iter().fold(HashMap::new(), |mut acc, policy| {
+                    acc.insert(policy.app_id.clone(), AppPolicy {
+                        analytics: policy.analytics.clone(),
+                        advertising: policy.advertising.clone(),
+                        personalization: policy.personalization.clone(),
+                    });
+
+                    acc
+                })
+            }).unwrap_or_else(HashMap::new)
+    }
+
+    /// Resolves the actions for the app from the consent flags. Only the
+    /// flags explicitly denied trigger actions.
+    pub fn apply(&self, app_id: &str, consent: &Option<SDKConsent>) -> AppliedConsent {
+        let consent = consent.clone().unwrap_or_default();
+
+        let mut applied = AppliedConsent {
+            analytics: ConsentState::from(consent.analytics),
+            advertising: ConsentState::from(consent.advertising),
+            personalization: ConsentState::from(consent.personalization),
+            actions: Vec::new(),
+        };
+
+        if let Some(policy) = self.policies.get().get(app_id) {
+            let flags = [
+                (applied.analytics, &policy.analytics),
+                (applied.advertising, &policy.advertising),
+                (applied.personalization, &policy.personalization),
+            ];
+
+            for (state, actions) in flags.iter() {
+                if *state != ConsentState::Denied { continue }
+
+                for action in actions.iter() {
+                    if !applied.actions.contains(action) {
+                        applied.actions.push(*action);
+                    }
+                }
+            }
+        }
+
+        applied
+    }
+}
+
+impl Default for Consent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use events::output::common;
+
+    const APP_ID: &'static str = "22222222-0000-0000-0000-000000000000";
+
+    fn consent(analytics: Option<bool>, advertising: Option<bool>) -> Option<SDKConsent> {
+        Some(SDKConsent {
+            analytics,
+            advertising,
+            personalization: None,
+        })
+    }
+
+    fn proto_batch() -> output::events::SdkEventBatch {
+        output::events::SdkEventBatch {
+            device: Some(common::Device {
+                ifa: Some(String::from("f3b7e2a0-3e5c-4c8e-9d3a-5b1f1c6e9a42")),
+                idfv: Some(String::from("7a0c5b9d-6c1f-4e3b-8a2d-9e4f1b0c3d5a")),
+                ip_hashed_blake2: Some(String::from("hash")),
+                country: Some(String::from("DE")),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_no_consent_given() {
+        let applied = Consent::new().apply(APP_ID, &None);
+
+        assert_eq!(AppliedConsent::default(), applied);
+    }
+
+    #[test]
+    fn test_granted_consent_has_no_actions() {
+        let applied = Consent::new().apply(APP_ID, &consent(Some(true), Some(true)));
+
+        assert_eq!(ConsentState::Granted, applied.analytics);
+        assert!(applied.actions.is_empty());
+    }
+
+    #[test]
+    fn test_denied_advertising() {
+        let applied = Consent::new().apply(APP_ID, &consent(None, Some(false)));
+
+        assert_eq!(ConsentState::Denied, applied.advertising);
+        assert!(applied.has(ConsentAction::StripIfa));
+        assert!(applied.has(ConsentAction::SkipIfaMatching));
+        assert!(!applied.has(ConsentAction::DropEvents));
+    }
+
+    #[test]
+    fn test_denied_analytics() {
+        let applied = Consent::new().apply(APP_ID, &consent(Some(false), None));
+
+        assert!(applied.has(ConsentAction::DropEvents));
+        assert!(applied.has(ConsentAction::SkipIfaMatching));
+    }
+
+    #[test]
+    fn test_denied_for_app_without_policy() {
+        let applied = Consent::new().apply("kulli", &consent(Some(false), Some(false)));
+
+        assert_eq!(ConsentState::Denied, applied.analytics);
+        assert!(applied.actions.is_empty());
+    }
+
+    #[test]
+    fn test_strip_fields() {
+        let applied = AppliedConsent {
+            actions: vec![
+                ConsentAction::StripIfa,
+                ConsentAction::StripIdfv,
+                ConsentAction::StripIpHash,
+                ConsentAction::StripCountry,
+            ],
+            ..Default::default()
+        };
+
+        let mut proto = proto_batch();
+        applied.strip(&mut proto);
+
+        let device = proto.device.unwrap();
+
+        assert!(device.ifa.is_none());
+        assert!(device.idfv.is_none());
+        assert!(device.ip_hashed_blake2.is_none());
+        assert!(device.country.is_none());
+    }
+
+    #[test]
+    fn test_strip_nothing() {
+        let mut proto = proto_batch();
+        AppliedConsent::default().strip(&mut proto);
+
+        let device = proto.device.unwrap();
+
+        assert!(device.ifa.is_some());
+        assert_eq!(Some(String::from("DE")), device.country);
+    }
+
+    #[test]
+    fn test_headers() {
+        let applied = Consent::new().apply(APP_ID, &consent(Some(true), Some(false)));
+        let headers = applied.headers();
+
+        assert_eq!(("consent_analytics", String::from("granted")), headers[0]);
+        assert_eq!(("consent_advertising", String::from("denied")), headers[1]);
+        assert_eq!(("consent_personalization", String::from("unknown")), headers[2]);
+        assert_eq!(("consent_actions", String::from("strip_ifa,skip_ifa_matching")), headers[3]);
+    }
+}
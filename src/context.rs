@@ -5,17 +5,42 @@ use rand::{RngCore, thread_rng};
 use http::{header::{self, AsHeaderName}};
 use encryption::{Ciphertext, Cleartext};
 use events::input::Platform;
-use std::net::IpAddr;
+use consent::AppliedConsent;
+use std::{net::IpAddr, sync::Arc};
+use redaction::{Redaction, RedactionPolicy};
+use tracing::TraceContext;
+use geoip::Geo;
+use ip_hash::HashedIp;
+use user_agent::UserAgent;
+
+use ::LOG_REDACTION;
+
+/// The request id header, accepted from the client or generated.
+pub const REQUEST_ID: &'static str = "x-request-id";
+
+/// The longest request id accepted from the client.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Context {
     pub app_id: String,
     pub platform: Platform,
+    pub sdk_version: Option<String>,
     pub api_token: Option<String>,
     pub device_id: Option<DeviceId>,
     pub signature: Option<String>,
     pub ip: Option<IpAddr>,
     pub origin: Option<String>,
+    pub consent: AppliedConsent,
+    pub trace: Option<TraceContext>,
+    pub request_id: Option<String>,
+    pub geo: Option<Geo>,
+    pub ip_hash: Option<HashedIp>,
+    pub user_agent: Option<UserAgent>,
+    /// The request passed the token and signature checks.
+    pub validated: bool,
+    /// The redaction of the log lines, the global policy if not set.
+    pub log_redaction: Option<Arc<RedactionPolicy>>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -80,19 +105,25 @@ impl Context {
                 }
             });
 
-        let ip: Option<IpAddr> = headers
-            .get("X-Real-IP")
-            .and_then(|h| h.to_str().ok())
-            .and_then(|s| s.parse().ok());
+        let ip = client_ip(headers);
 
         Context {
             api_token: Self::get_value(&headers, "XORC-Api-Token"),
             app_id: String::from(app_id),
             platform,
+            sdk_version: None,
             device_id,
             signature: Self::get_value(&headers, "XORC-Signature"),
             ip,
             origin: Self::get_value(&headers, header::ORIGIN),
+            consent: AppliedConsent::default(),
+            trace: None,
+            request_id: None,
+            geo: None,
+            ip_hash: None,
+            user_agent: None,
+            validated: false,
+            log_redaction: None,
         }
     }
 
@@ -110,26 +141,80 @@ impl Context {
     }
 }
 
-impl KV for Context {
-    fn serialize(&self, _record: &Record, serializer: &mut Serializer) -> slog::Result {
-        serializer.emit_str("app_id", &self.app_id)?;
-        serializer.emit_str("platform", &String::from(&self.platform))?;
+/// The address of the client from the `X-Real-IP` header.
+pub fn client_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    headers
+        .get("X-Real-IP")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|s| s.parse().ok())
+}
 
-        if let Some(ref api_token) = self.api_token {
-            serializer.emit_str("api_token", api_token)?;
+/// The `X-Request-Id` of the request if it looks like an identifier,
+/// otherwise a new random one.
+pub fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID)
+        .and_then(|h| h.to_str().ok())
+        .map(|id| id.trim())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LENGTH
+                && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+        })
+        .map(String::from)
+        .unwrap_or_else(|| Uuid::new_v4().hyphenated().to_string())
+}
+
+impl Context {
+    /// The fields to log, the secrets and identifiers redacted with the
+    /// policy.
+    pub fn log_fields(&self, policy: &RedactionPolicy) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            ("app_id", self.app_id.clone()),
+            ("platform", String::from(&self.platform)),
+        ];
+
+        if let Some(ref sdk_version) = self.sdk_version {
+            fields.push(("sdk_version", sdk_version.clone()));
         }
 
-        if let Some(ref device_id) = self.device_id {
-            serializer.emit_str("device_id", device_id.cleartext.as_ref())?;
-            serializer.emit_str("encrypted_device_id", device_id.ciphertext.as_ref())?;
+        if let Some(ref trace) = self.trace {
+            fields.push(("trace_id", trace.trace_id_hex()));
         }
 
-        if let Some(ref signature) = self.signature {
-            serializer.emit_str("signature", signature)?;
+        if let Some(ref request_id) = self.request_id {
+            fields.push(("request_id", request_id.clone()));
         }
 
+        let mut redacted = |key, value: Option<&str>, redaction: Redaction| {
+            if let Some(value) = value.and_then(|v| redaction.apply(v)) {
+                fields.push((key, value));
+            }
+        };
+
+        redacted("api_token", self.api_token.as_ref().map(|t| t.as_str()), policy.api_token);
+
+        if let Some(ref device_id) = self.device_id {
+            redacted("device_id", Some(device_id.cleartext.as_ref()), policy.device_id);
+            redacted("encrypted_device_id", Some(device_id.ciphertext.as_ref()), policy.encrypted_device_id);
+        }
+
+        redacted("signature", self.signature.as_ref().map(|s| s.as_str()), policy.signature);
+
         if let Some(ref origin) = self.origin {
-            serializer.emit_str("origin", origin)?;
+            fields.push(("origin", origin.clone()));
+        }
+
+        fields
+    }
+}
+
+impl KV for Context {
+    fn serialize(&self, _record: &Record, serializer: &mut Serializer) -> slog::Result {
+        let policy = self.log_redaction.clone().unwrap_or_else(|| LOG_REDACTION.policy());
+
+        for (key, value) in self.log_fields(&policy) {
+            serializer.emit_str(key, &value)?;
         }
 
         Ok(())
@@ -310,4 +395,86 @@ mod tests {
 
         assert!(context.device_id.is_none());
     }
+
+    /// Found by the `context_headers` fuzz target, a valid base64 value
+    /// shorter than the nonce used to panic.
+    #[test]
+    fn test_too_short_device_id() {
+        let mut header_map = HeaderMap::new();
+
+        header_map.insert(
+            "XORC-Device-Id",
+            HeaderValue::from_static("AAAA"),
+        );
+
+        let context = Context::new(&header_map, "123", Platform::Ios);
+
+        assert!(context.device_id.is_none());
+    }
+
+    #[test]
+    fn test_request_id_from_header() {
+        let mut header_map = HeaderMap::new();
+
+        header_map.insert(
+            "X-Request-Id",
+            HeaderValue::from_static("f058ebd6-02f7-4d3f-942e-904344e8cde5"),
+        );
+
+        assert_eq!("f058ebd6-02f7-4d3f-942e-904344e8cde5", request_id(&header_map));
+    }
+
+    #[test]
+    fn test_generated_request_id() {
+        let mut header_map = HeaderMap::new();
+        let generated = request_id(&header_map);
+
+        assert_eq!(36, generated.len());
+        assert_ne!(generated, request_id(&header_map));
+
+        header_map.insert("X-Request-Id", HeaderValue::from_static("<script>"));
+        assert_ne!("<script>", request_id(&header_map));
+
+        let too_long = "a".repeat(MAX_REQUEST_ID_LENGTH + 1);
+        header_map.insert("X-Request-Id", HeaderValue::from_str(&too_long).unwrap());
+        assert_ne!(too_long, request_id(&header_map));
+    }
+
+    fn logged_context() -> Context {
+        let mut header_map = HeaderMap::new();
+
+        header_map.insert(
+            "XORC-Device-Id",
+            HeaderValue::from_static("PNslnKKJkbq8Nv5/C0CcoK7hnFsdltcW3yK/I0QYJ7bUX8EHx2/NX0r8OkJHC5lzY/cBwZ3FeeFmRRpxof+rtw=="),
+        );
+
+        header_map.insert("XORC-Api-Token", HeaderValue::from_static("token"));
+        header_map.insert("XORC-Signature", HeaderValue::from_static("signature"));
+
+        Context::new(&header_map, "123", Platform::Ios)
+    }
+
+    fn keys(fields: &[(&'static str, String)]) -> Vec<&'static str> {
+        fields.iter().map(|(key, _)| *key).collect()
+    }
+
+    #[test]
+    fn test_log_fields_in_development() {
+        let fields = logged_context().log_fields(&RedactionPolicy::defaults("development"));
+
+        assert_eq!(
+            vec!["app_id", "platform", "api_token", "device_id", "encrypted_device_id", "signature"],
+            keys(&fields)
+        );
+
+        assert!(fields.contains(&("device_id", String::from("8f7f5c07-5eb2-4695-870c-065d886cdc9e"))));
+    }
+
+    #[test]
+    fn test_log_fields_in_production() {
+        let fields = logged_context().log_fields(&RedactionPolicy::defaults("production"));
+
+        assert_eq!(vec!["app_id", "platform", "device_id"], keys(&fields));
+        assert!(!fields.iter().any(|(_, value)| value.contains("8f7f5c07")));
+    }
 }
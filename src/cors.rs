@@ -1,7 +1,8 @@
 use std::{
-    collections::{HashMap, HashSet},
-    iter::FromIterator,
+    collections::HashMap,
+    sync::{Arc, Mutex},
 };
+
 use http::{
     response::Builder,
     Response,
@@ -9,39 +10,304 @@ use http::{
 };
 
 use events::input::Platform;
+use config::{Config, OriginConfig};
+use crossbeam::sync::ArcCell;
+use metrics::ORIGINS_UPDATE_COUNTER;
+
+use ::{CONFIG, GLOG};
 
-use ::CONFIG;
+/// Seconds the browser can cache the preflight response, if `max_age` is
+/// not set in the `[cors]` section.
+const DEFAULT_MAX_AGE: u32 = 600;
 
 pub struct Cors {
+    state: ArcCell<CorsState>,
+    database_origins: Mutex<HashMap<String, AppOrigins>>,
+}
+
+/// Everything needed to answer a request, swapped as a whole when the
+/// configuration is reloaded or the origins are updated from the database.
+struct CorsState {
     allowed_methods: String,
     allowed_headers: String,
-    allowed_origins: HashMap<String, HashSet<String>>
+    max_age: u32,
+    static_origins: HashMap<String, AppOrigins>,
+    allowed_origins: HashMap<String, AppOrigins>,
 }
 
-impl Cors {
-    pub fn new() -> Option<Cors> {
-        CONFIG.cors.as_ref().map(|ref cors_config| {
-            let allowed_origins: HashMap<String, HashSet<String>> = CONFIG.origins.as_ref()
+impl CorsState {
+    fn new(config: &Config, database_origins: &HashMap<String, AppOrigins>) -> Option<CorsState> {
+        config.cors.as_ref().map(|ref cors_config| {
+            let static_origins: HashMap<String, AppOrigins> = config.origins.as_ref()
                 .map(|origins| {
                     origins.iter()
                         .fold(HashMap::new(), |mut acc, origin| {
                             acc.insert(
                                 origin.app_id.to_string(),
-                                HashSet::from_iter(origin.allowed.iter().map(|s| s.to_string()))
+                                AppOrigins::from_config(origin),
                             );
 
                             acc
                         })
                 }).unwrap_or_else(|| HashMap::new());
 
-            Cors {
+            let mut allowed_origins = static_origins.clone();
+
+            for (app_id, app_origins) in database_origins.iter() {
+                allowed_origins.insert(app_id.clone(), app_origins.clone());
+            }
+
+            CorsState {
                 allowed_origins,
+                static_origins,
                 allowed_methods: cors_config.allowed_methods.clone(),
                 allowed_headers: cors_config.allowed_headers.clone(),
+                max_age: cors_config.max_age.unwrap_or(DEFAULT_MAX_AGE),
+            }
+        })
+    }
+}
+
+/// Allowed origins of an app, with optional overrides for the methods and
+/// headers.
+#[derive(Clone)]
+struct AppOrigins {
+    patterns: Vec<OriginPattern>,
+    allowed_methods: Option<String>,
+    allowed_headers: Option<String>,
+}
+
+/// Checks that the allowed methods or headers can be sent as a header
+/// value.
+pub fn check_header_value(value: &str) -> Result<(), String> {
+    HeaderValue::from_str(value)
+        .map(|_| ())
+        .map_err(|_| format!("{:?} can't be sent as a header value", value))
+}
+
+impl AppOrigins {
+    /// Invalid patterns are skipped. The patterns from the configuration file
+    /// are already validated in `Config::parse`.
+    fn from_config(origin: &OriginConfig) -> AppOrigins {
+        let patterns = origin.allowed.iter()
+            .filter_map(|s| {
+                OriginPattern::parse(s).map_err(|e| {
+                    error!(*GLOG, "Skipping an origin for app {}: [{}]", origin.app_id, e);
+                }).ok()
+            })
+            .collect();
+
+        AppOrigins {
+            patterns,
+            allowed_methods: origin.allowed_methods.clone(),
+            allowed_headers: origin.allowed_headers.clone(),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        self.patterns.iter().any(|p| p.matches(origin))
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum HostPattern {
+    Exact(String),
+    /// `*.example.com`, stored as `.example.com`
+    Subdomain(String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum PortPattern {
+    Default,
+    Exact(u16),
+    Any,
+}
+
+/// An allowed origin from the `[[origins]]` configuration. Supports a
+/// wildcard scheme (`*://example.com`), wildcard subdomains
+/// (`https://*.example.com`) and wildcard ports (`http://localhost:*`). An
+/// origin without a port only matches the default port of the scheme.
+#[derive(Debug, PartialEq, Clone)]
+pub struct OriginPattern {
+    scheme: Option<String>,
+    host: HostPattern,
+    port: PortPattern,
+}
+
+impl OriginPattern {
+    pub fn parse(pattern: &str) -> Result<OriginPattern, String> {
+        let (scheme, host, port) = Self::split(pattern)
+            .ok_or_else(|| format!("Origin pattern `{}` is not in scheme://host[:port] format", pattern))?;
+
+        let scheme = if scheme == "*" {
+            None
+        } else if scheme == "http" || scheme == "https" {
+            Some(scheme)
+        } else {
+            return Err(format!("Origin pattern `{}` has an unsupported scheme", pattern))
+        };
+
+        let host = if host.starts_with("*.") {
+            let suffix = &host[1..];
+
+            if suffix.len() < 2 || suffix.contains('*') {
+                return Err(format!("Origin pattern `{}` has a faulty host wildcard", pattern))
+            }
+
+            HostPattern::Subdomain(suffix.to_string())
+        } else if host.is_empty() || host.contains('*') {
+            return Err(format!("Origin pattern `{}` has a faulty host", pattern))
+        } else {
+            HostPattern::Exact(host)
+        };
+
+        let port = match port.as_ref().map(|p| &**p) {
+            None => PortPattern::Default,
+            Some("*") => PortPattern::Any,
+            Some(port) => {
+                let port = port.parse()
+                    .map_err(|_| format!("Origin pattern `{}` has a faulty port", pattern))?;
+
+                PortPattern::Exact(port)
+            }
+        };
+
+        Ok(OriginPattern { scheme, host, port })
+    }
+
+    pub fn matches(&self, origin: &str) -> bool {
+        let (scheme, host, port) = match Self::split(origin) {
+            Some(parts) => parts,
+            None => return false,
+        };
+
+        if let Some(ref allowed_scheme) = self.scheme {
+            if allowed_scheme != &scheme { return false }
+        }
+
+        let host_matches = match self.host {
+            HostPattern::Exact(ref allowed_host) => allowed_host == &host,
+            HostPattern::Subdomain(ref suffix) => {
+                host.ends_with(suffix.as_str()) && host.len() > suffix.len()
+            },
+        };
+
+        if !host_matches { return false }
+
+        let port = match port {
+            Some(port) => match port.parse::<u16>() {
+                Ok(port) => Some(port),
+                Err(_) => return false,
+            },
+            None => None,
+        };
+
+        let default_port = match scheme.as_ref() {
+            "http" => Some(80),
+            "https" => Some(443),
+            _ => None,
+        };
+
+        match self.port {
+            PortPattern::Any => true,
+            PortPattern::Default => port.is_none() || port == default_port,
+            PortPattern::Exact(allowed_port) => {
+                port == Some(allowed_port) || (port.is_none() && default_port == Some(allowed_port))
+            },
+        }
+    }
+
+    /// Splits an origin to lowercase scheme, host and optional port.
+    fn split(origin: &str) -> Option<(String, String, Option<String>)> {
+        let origin = origin.to_lowercase();
+        let scheme_end = origin.find("://")?;
+        let (scheme, rest) = (&origin[..scheme_end], &origin[scheme_end + 3..]);
+
+        if scheme.is_empty() || rest.contains('/') {
+            return None
+        }
+
+        // IPv6 hosts are inside brackets, e.g. `http://[::1]:8080`
+        let host_end = if rest.starts_with('[') {
+            rest.find(']')? + 1
+        } else {
+            rest.find(':').unwrap_or_else(|| rest.len())
+        };
+
+        let (host, port) = rest.split_at(host_end);
+
+        let port = match port {
+            "" => None,
+            port if port.starts_with(':') && port.len() > 1 => Some(port[1..].to_string()),
+            _ => return None,
+        };
+
+        Some((scheme.to_string(), host.to_string(), port))
+    }
+}
+
+impl Cors {
+    /// Allowed origins are read from the `[[origins]]` configuration. If apps
+    /// are managed in ScyllaDB, the app registry updates the origins with
+    /// `swap_origins`, the configuration staying as a fallback for apps not
+    /// found from the database.
+    pub fn new() -> Option<Cors> {
+        Self::from_config(&CONFIG)
+    }
+
+    pub fn from_config(config: &Config) -> Option<Cors> {
+        CorsState::new(config, &HashMap::new()).map(|state| {
+            Cors {
+                state: ArcCell::new(Arc::new(state)),
+                database_origins: Mutex::new(HashMap::new()),
             }
         })
     }
 
+    /// Replaces the origins loaded from the database. An app in the database
+    /// overrides the same app in the configuration file.
+    pub fn swap_origins(&self, origins: &[OriginConfig]) {
+        let mut database_origins = self.database_origins.lock().unwrap();
+
+        *database_origins = origins.iter().fold(HashMap::new(), |mut acc, origin| {
+            ORIGINS_UPDATE_COUNTER.inc();
+            acc.insert(origin.app_id.to_string(), AppOrigins::from_config(origin));
+
+            acc
+        });
+
+        let current = self.state.get();
+        let mut allowed_origins = current.static_origins.clone();
+
+        for (app_id, app_origins) in database_origins.iter() {
+            allowed_origins.insert(app_id.clone(), app_origins.clone());
+        }
+
+        self.state.set(Arc::new(CorsState {
+            allowed_methods: current.allowed_methods.clone(),
+            allowed_headers: current.allowed_headers.clone(),
+            max_age: current.max_age,
+            static_origins: current.static_origins.clone(),
+            allowed_origins,
+        }));
+    }
+
+    /// Replaces the headers and the configured origins from a reloaded
+    /// configuration, keeping the origins from the database. Enabling or
+    /// disabling CORS needs a restart.
+    pub fn reload(&self, config: &Config) {
+        let database_origins = self.database_origins.lock().unwrap();
+
+        match CorsState::new(config, &database_origins) {
+            Some(state) => {
+                self.state.set(Arc::new(state));
+            },
+            None => {
+                warn!(*GLOG, "Cannot disable CORS without a restart, keeping the old settings");
+            }
+        }
+    }
+
     pub fn valid_origin(
         &self,
         app_id: &str,
@@ -49,8 +315,8 @@ impl Cors {
     ) -> bool
     {
         if let Some(origin) = origin {
-            match self.allowed_origins.get(app_id) {
-                Some(app_origins) if app_origins.contains(origin) => true,
+            match self.state.get().allowed_origins.get(app_id) {
+                Some(app_origins) => app_origins.matches(origin),
                 _ => false
             }
         } else {
@@ -67,49 +333,92 @@ impl Cors {
     {
         let mut builder = Response::builder();
 
-        if self.valid_origin(app_id, origin) && (platform == &Platform::Web) {
-            builder.header(
-                header::ACCESS_CONTROL_ALLOW_ORIGIN,
-                HeaderValue::from_str(origin.unwrap()).unwrap(),
-            );
-
-            builder.header(
-                header::ACCESS_CONTROL_ALLOW_METHODS,
-                HeaderValue::from_str(self.allowed_methods.as_ref()).unwrap(),
-            );
+        builder.header(header::VARY, HeaderValue::from_static("Origin"));
 
-            builder.header(
-                header::ACCESS_CONTROL_ALLOW_HEADERS,
-                HeaderValue::from_str(self.allowed_headers.as_ref()).unwrap(),
-            );
+        if self.valid_origin(app_id, origin) && (platform == &Platform::Web) {
+            let state = self.state.get();
+            Self::allow_headers(&mut builder, &state, state.allowed_origins.get(app_id), origin.unwrap());
         }
 
         builder
     }
 
-    pub fn response_builder_wildcard(
+    /// Response for a preflight request. The preflight has no body, so the app
+    /// is given in the `app_id` query parameter. Without it, the origin is
+    /// accepted if any app allows it and the global methods and headers are
+    /// used.
+    pub fn response_builder_preflight(
         &self,
+        app_id: Option<&str>,
+        origin: Option<&str>,
     ) -> Builder
     {
         let mut builder = Response::builder();
 
-        builder.header(
-            header::ACCESS_CONTROL_ALLOW_ORIGIN,
-            HeaderValue::from_static("*"),
-        );
+        builder.header(header::VARY, HeaderValue::from_static("Origin"));
+
+        let origin = match origin {
+            Some(origin) => origin,
+            None => return builder,
+        };
+
+        let state = self.state.get();
+
+        let allowed = match app_id {
+            Some(app_id) => {
+                state.allowed_origins
+                    .get(app_id)
+                    .filter(|app_origins| app_origins.matches(origin))
+                    .map(Some)
+            },
+            None => {
+                state.allowed_origins
+                    .values()
+                    .find(|app_origins| app_origins.matches(origin))
+                    .map(|_| None)
+            }
+        };
 
-        builder.header(
-            header::ACCESS_CONTROL_ALLOW_METHODS,
-            HeaderValue::from_str(self.allowed_methods.as_ref()).unwrap(),
-        );
+        if let Some(app_origins) = allowed {
+            Self::allow_headers(&mut builder, &state, app_origins, origin);
 
-        builder.header(
-            header::ACCESS_CONTROL_ALLOW_HEADERS,
-            HeaderValue::from_str(self.allowed_headers.as_ref()).unwrap(),
-        );
+            builder.header(
+                header::ACCESS_CONTROL_MAX_AGE,
+                HeaderValue::from_str(&state.max_age.to_string()).unwrap(),
+            );
+        }
 
         builder
     }
+
+    fn allow_headers(
+        builder: &mut Builder,
+        state: &CorsState,
+        app_origins: Option<&AppOrigins>,
+        origin: &str,
+    )
+    {
+        let allowed_methods = app_origins
+            .and_then(|o| o.allowed_methods.as_ref())
+            .unwrap_or(&state.allowed_methods);
+
+        let allowed_headers = app_origins
+            .and_then(|o| o.allowed_headers.as_ref())
+            .unwrap_or(&state.allowed_headers);
+
+        let values = [
+            (header::ACCESS_CONTROL_ALLOW_ORIGIN, origin),
+            (header::ACCESS_CONTROL_ALLOW_METHODS, allowed_methods.as_str()),
+            (header::ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers.as_str()),
+        ];
+
+        for (name, value) in values.iter() {
+            match HeaderValue::from_str(value) {
+                Ok(value) => { builder.header(name.clone(), value); },
+                Err(_) => error!(*GLOG, "Not sending an invalid {} header", name.as_str()),
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -124,15 +433,18 @@ mod tests {
     }
 
     #[test]
-    fn wildcard_headers() {
+    fn preflight_headers_for_existing_app_from_allowed_origin() {
         let cors = Cors::new().unwrap();
 
-        let response = cors.response_builder_wildcard().body("").unwrap();
+        let response = cors.response_builder_preflight(
+            Some("11111111-0000-0000-0000-000000000000"),
+            Some("https://reddit.com"),
+        ).body("").unwrap();
 
         let headers = response.headers();
 
         assert_eq!(
-            "*",
+            "https://reddit.com",
             headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap()
         );
 
@@ -145,6 +457,97 @@ mod tests {
             "Content-Type, Content-Length",
             headers.get(header::ACCESS_CONTROL_ALLOW_HEADERS).unwrap()
         );
+
+        assert_eq!("Origin", headers.get(header::VARY).unwrap());
+        assert_eq!("600", headers.get(header::ACCESS_CONTROL_MAX_AGE).unwrap());
+    }
+
+    #[test]
+    fn preflight_headers_for_existing_app_from_wrong_origin() {
+        let cors = Cors::new().unwrap();
+
+        let response = cors.response_builder_preflight(
+            Some("11111111-0000-0000-0000-000000000000"),
+            Some("https://www.google.fi"),
+        ).body("").unwrap();
+
+        let headers = response.headers();
+
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+        assert!(headers.get(header::ACCESS_CONTROL_MAX_AGE).is_none());
+        assert_eq!("Origin", headers.get(header::VARY).unwrap());
+    }
+
+    #[test]
+    fn preflight_headers_without_app_from_allowed_origin() {
+        let cors = Cors::new().unwrap();
+
+        let response = cors.response_builder_preflight(
+            None,
+            Some("https://www.google.fi"),
+        ).body("").unwrap();
+
+        let headers = response.headers();
+
+        assert_eq!(
+            "https://www.google.fi",
+            headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap()
+        );
+
+        assert_eq!(
+            "HERP,DERP",
+            headers.get(header::ACCESS_CONTROL_ALLOW_METHODS).unwrap()
+        );
+    }
+
+    #[test]
+    fn preflight_headers_without_app_from_unknown_origin() {
+        let cors = Cors::new().unwrap();
+
+        let response = cors.response_builder_preflight(
+            None,
+            Some("https://facebook.com"),
+        ).body("").unwrap();
+
+        let headers = response.headers();
+
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[test]
+    fn preflight_headers_without_origin() {
+        let cors = Cors::new().unwrap();
+
+        let response = cors.response_builder_preflight(
+            Some("11111111-0000-0000-0000-000000000000"),
+            None,
+        ).body("").unwrap();
+
+        let headers = response.headers();
+
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[test]
+    fn preflight_headers_with_app_overrides() {
+        let cors = Cors::new().unwrap();
+
+        let response = cors.response_builder_preflight(
+            Some("33333333-0000-0000-0000-000000000000"),
+            Some("https://www.example.com"),
+        ).body("").unwrap();
+
+        let headers = response.headers();
+
+        assert_eq!(
+            "POST,OPTIONS",
+            headers.get(header::ACCESS_CONTROL_ALLOW_METHODS).unwrap()
+        );
+
+        assert_eq!(
+            "Content-Type, XORC-Signature",
+            headers.get(header::ACCESS_CONTROL_ALLOW_HEADERS).unwrap()
+        );
     }
 
     #[test]
@@ -205,4 +608,197 @@ mod tests {
 
         assert!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
     }
+
+    #[test]
+    fn headers_for_existing_app_from_wildcard_subdomain() {
+        let cors = Cors::new().unwrap();
+
+        let response = cors.response_builder_origin(
+            "33333333-0000-0000-0000-000000000000",
+            Some("https://preview-42.example.com"),
+            &Platform::Web
+        ).body("").unwrap();
+
+        let headers = response.headers();
+
+        assert_eq!(
+            "https://preview-42.example.com",
+            headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap()
+        );
+    }
+
+    #[test]
+    fn headers_for_existing_app_from_wildcard_port() {
+        let cors = Cors::new().unwrap();
+
+        let response = cors.response_builder_origin(
+            "33333333-0000-0000-0000-000000000000",
+            Some("http://localhost:3000"),
+            &Platform::Web
+        ).body("").unwrap();
+
+        let headers = response.headers();
+
+        assert_eq!(
+            "http://localhost:3000",
+            headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap()
+        );
+    }
+
+    #[test]
+    fn headers_for_existing_app_from_apex_of_wildcard() {
+        let cors = Cors::new().unwrap();
+
+        let response = cors.response_builder_origin(
+            "33333333-0000-0000-0000-000000000000",
+            Some("https://example.com"),
+            &Platform::Web
+        ).body("").unwrap();
+
+        let headers = response.headers();
+
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[test]
+    fn headers_for_existing_app_from_wrong_scheme() {
+        let cors = Cors::new().unwrap();
+
+        let response = cors.response_builder_origin(
+            "33333333-0000-0000-0000-000000000000",
+            Some("http://preview-42.example.com"),
+            &Platform::Web
+        ).body("").unwrap();
+
+        let headers = response.headers();
+
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[test]
+    fn swapped_origins_override_configuration() {
+        let cors = Cors::new().unwrap();
+
+        cors.swap_origins(&[OriginConfig {
+            app_id: String::from("11111111-0000-0000-0000-000000000000"),
+            allowed: vec![String::from("https://news.ycombinator.com")],
+            allowed_methods: None,
+            allowed_headers: None,
+        }]);
+
+        assert!(cors.valid_origin(
+            "11111111-0000-0000-0000-000000000000",
+            Some("https://news.ycombinator.com")
+        ));
+
+        assert!(!cors.valid_origin(
+            "11111111-0000-0000-0000-000000000000",
+            Some("https://reddit.com")
+        ));
+    }
+
+    #[test]
+    fn swapped_origins_keep_configuration_as_fallback() {
+        let cors = Cors::new().unwrap();
+
+        cors.swap_origins(&[OriginConfig {
+            app_id: String::from("44444444-0000-0000-0000-000000000000"),
+            allowed: vec![String::from("https://news.ycombinator.com"), String::from("kulli")],
+            allowed_methods: None,
+            allowed_headers: None,
+        }]);
+
+        assert!(cors.valid_origin(
+            "44444444-0000-0000-0000-000000000000",
+            Some("https://news.ycombinator.com")
+        ));
+
+        assert!(cors.valid_origin(
+            "11111111-0000-0000-0000-000000000000",
+            Some("https://reddit.com")
+        ));
+    }
+
+    #[test]
+    fn reload_keeps_database_origins() {
+        let cors = Cors::new().unwrap();
+
+        cors.swap_origins(&[OriginConfig {
+            app_id: String::from("44444444-0000-0000-0000-000000000000"),
+            allowed: vec![String::from("https://news.ycombinator.com")],
+            allowed_methods: None,
+            allowed_headers: None,
+        }]);
+
+        cors.reload(&Config::load("./config/config.toml.tests").unwrap());
+
+        assert!(cors.valid_origin(
+            "44444444-0000-0000-0000-000000000000",
+            Some("https://news.ycombinator.com")
+        ));
+
+        assert!(cors.valid_origin(
+            "11111111-0000-0000-0000-000000000000",
+            Some("https://reddit.com")
+        ));
+    }
+
+    #[test]
+    fn pattern_any_scheme() {
+        let pattern = OriginPattern::parse("*://example.org").unwrap();
+
+        assert!(pattern.matches("http://example.org"));
+        assert!(pattern.matches("https://example.org"));
+        assert!(!pattern.matches("https://www.example.org"));
+    }
+
+    #[test]
+    fn pattern_default_port() {
+        let pattern = OriginPattern::parse("https://example.org").unwrap();
+
+        assert!(pattern.matches("https://example.org:443"));
+        assert!(!pattern.matches("https://example.org:8443"));
+    }
+
+    #[test]
+    fn pattern_exact_port() {
+        let pattern = OriginPattern::parse("http://localhost:5401").unwrap();
+
+        assert!(pattern.matches("http://localhost:5401"));
+        assert!(!pattern.matches("http://localhost:5402"));
+        assert!(!pattern.matches("http://localhost"));
+    }
+
+    #[test]
+    fn pattern_ipv6_host() {
+        let pattern = OriginPattern::parse("http://[::1]:*").unwrap();
+
+        assert!(pattern.matches("http://[::1]:8080"));
+        assert!(!pattern.matches("http://[::2]:8080"));
+    }
+
+    #[test]
+    fn pattern_is_case_insensitive() {
+        let pattern = OriginPattern::parse("https://*.Example.com").unwrap();
+
+        assert!(pattern.matches("https://WWW.example.COM"));
+    }
+
+    #[test]
+    fn pattern_does_not_match_suffix_lookalike() {
+        let pattern = OriginPattern::parse("https://*.example.com").unwrap();
+
+        assert!(!pattern.matches("https://evilexample.com"));
+        assert!(!pattern.matches("https://example.com.evil.org"));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert!(OriginPattern::parse("example.com").is_err());
+        assert!(OriginPattern::parse("ftp://example.com").is_err());
+        assert!(OriginPattern::parse("https://*").is_err());
+        assert!(OriginPattern::parse("https://www.*.example.com").is_err());
+        assert!(OriginPattern::parse("https://example.com:http").is_err());
+        assert!(OriginPattern::parse("https://example.com/path").is_err());
+    }
 }
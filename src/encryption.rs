@@ -9,6 +9,14 @@ use serde::{
 
 use ::{RUST_ENV};
 
+/// The `SECRET` used in development if the environment variable is not set.
+pub const DEVELOPMENT_SECRET: [u8; 32] = [
+    129, 164, 171, 19, 88, 96, 172, 49, 218, 122, 106, 79, 226, 124, 112, 233,
+    172, 165, 64, 54, 31, 139, 249, 226, 199, 148, 8, 27, 76, 91, 164, 146,
+];
+
+const NONCE_LEN: usize = 12;
+
 lazy_static! {
     static ref SECRET: Vec<u8> =
         if let Ok(ref secret) = env::var("SECRET") {
@@ -18,9 +26,7 @@ lazy_static! {
                 panic!("Please set SECRET environment variable.")
             }
 
-            vec![129, 164, 171, 19, 88, 96, 172, 49, 218, 122, 106, 79, 226, 124,
-                 112, 233, 172, 165, 64, 54, 31, 139, 249, 226, 199, 148, 8, 27,
-                 76, 91, 164, 146]
+            DEVELOPMENT_SECRET.to_vec()
         };
 
     static ref OPENING_KEY: aead::OpeningKey =
@@ -129,7 +135,13 @@ impl Cleartext {
     {
         // 64 characters of encrypted data, first 12 for the nonce, last for the device id
         let mut decoded = base64::decode(ciphertext.as_ref()).map_err(|_| error::Unspecified)?;
-        let (nonce, mut cipher) = decoded.split_at_mut(12);
+
+        // Anything shorter can't even hold the nonce
+        if decoded.len() < NONCE_LEN {
+            return Err(error::Unspecified)
+        }
+
+        let (nonce, mut cipher) = decoded.split_at_mut(NONCE_LEN);
 
         // Open with the nonce we generated with `encrypt` and the secret key
         let decrypted_content = aead::open_in_place(
@@ -188,3 +200,35 @@ impl Serialize for Cleartext {
         serializer.serialize_str(self.as_ref())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        let cleartext = Cleartext::from("8f7f5c07-5eb2-4695-870c-065d886cdc9e");
+        let ciphertext = Ciphertext::encrypt(&cleartext);
+
+        assert_eq!(Ok(cleartext), Cleartext::decrypt(&ciphertext));
+    }
+
+    #[test]
+    fn test_decrypt_not_base64() {
+        assert!(Cleartext::decrypt(&Ciphertext::from("THIS_IS_FAKED")).is_err());
+    }
+
+    /// Found by the `cleartext_decrypt` fuzz target, used to panic when
+    /// splitting the nonce.
+    #[test]
+    fn test_decrypt_shorter_than_nonce() {
+        assert!(Cleartext::decrypt(&Ciphertext::from("")).is_err());
+        assert!(Cleartext::decrypt(&Ciphertext::from("AAAA")).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_only_nonce() {
+        let ciphertext = base64::encode(&[0u8; 12]);
+        assert!(Cleartext::decrypt(&Ciphertext::from(ciphertext)).is_err());
+    }
+}
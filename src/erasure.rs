@@ -0,0 +1,312 @@
+use std::{io, sync::Arc};
+use http::HeaderMap;
+use ring::constant_time;
+use uuid::Uuid;
+use chrono::offset::Utc;
+use serde_json;
+use tokio_threadpool::blocking;
+use error::GatewayError;
+use encryption::{Ciphertext, Cleartext};
+use ifa_store::IfaStore;
+
+use futures::{
+    Future,
+    future::{lazy, poll_fn},
+};
+
+use config::AdminConfig;
+
+use ::GLOG;
+
+/// A request to remove the device identity mappings of a user. At least one
+/// of `ifa`, `idfv` or `device_id` must be given. The `device_id` can be
+/// either the entity id or the encrypted `XORC-Device-Id`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ErasureRequest {
+    pub app_id: String,
+    pub ifa: Option<String>,
+    pub idfv: Option<String>,
+    pub device_id: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ErasureResult {
+    pub deleted_mappings: usize,
+    pub entity_ids: Vec<String>,
+    /// The IDFV was given and tombstoned.
+    pub idfv_erased: bool,
+}
+
+/// Sent to the audit topic after every erasure. Holds no raw identifiers,
+/// only which kinds were given and the erased entity ids.
+#[derive(Serialize, Debug)]
+struct AuditEvent<'a> {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    app_id: &'a str,
+    erased_at: i64,
+    ifa_given: bool,
+    idfv_given: bool,
+    device_id_given: bool,
+    result: &'a ErasureResult,
+}
+
+pub struct Erasure;
+
+impl Erasure {
+    /// Checks the `XORC-Admin-Token` header against the `[admin]` token.
+    pub fn authorize(
+        headers: &HeaderMap,
+        admin: Option<&AdminConfig>,
+    ) -> Result<(), GatewayError>
+    {
+        let admin = admin.ok_or(GatewayError::Unauthorized)?;
+
+        let token = headers
+            .get("XORC-Admin-Token")
+            .and_then(|h| h.to_str().ok())
+            .ok_or(GatewayError::Unauthorized)?;
+
+        constant_time::verify_slices_are_equal(token.as_bytes(), admin.token.as_bytes())
+            .map_err(|_| GatewayError::Unauthorized)
+    }
+
+    /// Parses and checks the request. Every given identifier must be
+    /// usable, so an erasure never succeeds without erasing what was asked.
+    pub fn parse(body: &[u8]) -> Result<ErasureRequest, GatewayError> {
+        let request: ErasureRequest = serde_json::from_slice(body)
+            .map_err(|_| GatewayError::InvalidPayload)?;
+
+        if Uuid::parse_str(&request.app_id).is_err() {
+            return Err(Self::invalid_field("app_id", "not a UUID"))
+        }
+
+        if request.ifa.is_none() && request.idfv.is_none() && request.device_id.is_none() {
+            return Err(Self::invalid_field("ifa|idfv|device_id", "one of the identifiers is required"))
+        }
+
+        if let Some(ref ifa) = request.ifa {
+            if Uuid::parse_str(ifa).is_err() {
+                return Err(Self::invalid_field("ifa", "not a UUID"))
+            }
+        }
+
+        if let Some(ref idfv) = request.idfv {
+            if idfv.trim().is_empty() {
+                return Err(Self::invalid_field("idfv", "must not be empty"))
+            }
+        }
+
+        if let Some(ref device_id) = request.device_id {
+            if Self::entity_id(device_id).is_none() {
+                return Err(Self::invalid_field("device_id", "not an entity id or a decryptable XORC-Device-Id"))
+            }
+        }
+
+        Ok(request)
+    }
+
+    fn invalid_field(field: &'static str, reason: &'static str) -> GatewayError {
+        warn!(*GLOG, "Invalid erasure request: {}", reason; "field" => field);
+        GatewayError::InvalidPayload
+    }
+
+    /// Removes the mappings and tombstones the identifiers in the IFA store.
+    /// Returns the result with an audit event to be sent to the bus.
+    pub fn run(
+        request: ErasureRequest,
+        ifa_store: Arc<IfaStore>,
+    ) -> impl Future<Item=(ErasureResult, Vec<u8>), Error=GatewayError>
+    {
+        let erase = {
+            let request = request.clone();
+            lazy(move || poll_fn(move || blocking(|| Self::erase(&request, &*ifa_store))))
+        };
+
+        erase
+            .map_err(|_| GatewayError::ServiceUnavailable("Erasure thread pool is busy"))
+            .and_then(|res| {
+                res.map_err(|_| GatewayError::ServiceUnavailable("Could not erase from the IFA store"))
+            })
+            .map(move |result| {
+                info!(
+                    *GLOG,
+                    "Erased {} IFA mapping(s)", result.deleted_mappings;
+                    "app_id" => &request.app_id
+                );
+
+                let audit_event = serde_json::to_vec(&AuditEvent {
+                    type_: "gateway.IdentityErasure",
+                    app_id: &request.app_id,
+                    erased_at: Utc::now().timestamp_millis(),
+                    ifa_given: request.ifa.is_some(),
+                    idfv_given: request.idfv.is_some(),
+                    device_id_given: request.device_id.is_some(),
+                    result: &result,
+                }).unwrap();
+
+                (result, audit_event)
+            })
+    }
+
+    fn erase(request: &ErasureRequest, ifa_store: &IfaStore) -> Result<ErasureResult, io::Error> {
+        let app_id = Uuid::parse_str(&request.app_id).unwrap();
+        let mut result = ErasureResult {
+            deleted_mappings: 0,
+            entity_ids: Vec::new(),
+            idfv_erased: false,
+        };
+
+        if let Some(ref ifa) = request.ifa {
+            let ifa = Uuid::parse_str(ifa).unwrap();
+
+            if let Some(entity_id) = ifa_store.erase_ifa(app_id, ifa)? {
+                result.deleted_mappings += 1;
+                result.entity_ids.push(entity_id.hyphenated().to_string());
+            }
+        }
+
+        if let Some(ref idfv) = request.idfv {
+            ifa_store.erase_idfv(app_id, idfv)?;
+            result.idfv_erased = true;
+        }
+
+        if let Some(ref device_id) = request.device_id {
+            let entity_id = Self::entity_id(device_id).unwrap();
+            result.deleted_mappings += ifa_store.erase_entity(app_id, entity_id)?;
+
+            let entity_id = entity_id.hyphenated().to_string();
+
+            if !result.entity_ids.contains(&entity_id) {
+                result.entity_ids.push(entity_id);
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn entity_id(device_id: &str) -> Option<Uuid> {
+        Uuid::parse_str(device_id).ok().or_else(|| {
+            Cleartext::decrypt(&Ciphertext::from(device_id))
+                .ok()
+                .and_then(|cleartext| Uuid::parse_str(cleartext.as_ref()).ok())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::HeaderValue;
+    use ifa_store::MemoryIfaStore;
+
+    #[test]
+    fn test_parse_without_identifiers() {
+        let body = json!({
+            "app_id": "22222222-0000-0000-0000-000000000000",
+        }).to_string();
+
+        assert_eq!(
+            Err(GatewayError::InvalidPayload),
+            Erasure::parse(body.as_bytes()).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_parse_with_faulty_app_id() {
+        let body = json!({
+            "app_id": "kulli",
+            "ifa": "f3b7e2a0-3e5c-4c8e-9d3a-5b1f1c6e9a42",
+        }).to_string();
+
+        assert_eq!(
+            Err(GatewayError::InvalidPayload),
+            Erasure::parse(body.as_bytes()).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_parse_with_ifa() {
+        let body = json!({
+            "app_id": "22222222-0000-0000-0000-000000000000",
+            "ifa": "f3b7e2a0-3e5c-4c8e-9d3a-5b1f1c6e9a42",
+        }).to_string();
+
+        let request = Erasure::parse(body.as_bytes()).unwrap();
+
+        assert_eq!(
+            Some(String::from("f3b7e2a0-3e5c-4c8e-9d3a-5b1f1c6e9a42")),
+            request.ifa
+        );
+    }
+
+    #[test]
+    fn test_parse_with_faulty_ifa() {
+        let body = json!({
+            "app_id": "22222222-0000-0000-0000-000000000000",
+            "ifa": "kulli",
+        }).to_string();
+
+        assert_eq!(
+            Err(GatewayError::InvalidPayload),
+            Erasure::parse(body.as_bytes()).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_parse_with_faulty_device_id() {
+        let body = json!({
+            "app_id": "22222222-0000-0000-0000-000000000000",
+            "device_id": "kulli",
+        }).to_string();
+
+        assert_eq!(
+            Err(GatewayError::InvalidPayload),
+            Erasure::parse(body.as_bytes()).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_erase_idfv() {
+        let store = MemoryIfaStore::new();
+
+        let body = json!({
+            "app_id": "22222222-0000-0000-0000-000000000000",
+            "idfv": "5a1c9f3e-7b2d-4e8a-9c6f-1d3b5e7a9c2f",
+        }).to_string();
+
+        let request = Erasure::parse(body.as_bytes()).unwrap();
+        let result = Erasure::erase(&request, &store).unwrap();
+
+        assert!(result.idfv_erased);
+        assert_eq!(0, result.deleted_mappings);
+
+        assert!(store.is_erased(
+            "22222222-0000-0000-0000-000000000000",
+            &None,
+            &Some(String::from("5a1c9f3e-7b2d-4e8a-9c6f-1d3b5e7a9c2f")),
+        ).unwrap());
+    }
+
+    #[test]
+    fn test_entity_id_from_encrypted_device_id() {
+        let cipher = "PNslnKKJkbq8Nv5/C0CcoK7hnFsdltcW3yK/I0QYJ7bUX8EHx2/NX0r8OkJHC5lzY/cBwZ3FeeFmRRpxof+rtw==";
+
+        assert_eq!(
+            Uuid::parse_str("8f7f5c07-5eb2-4695-870c-065d886cdc9e").ok(),
+            Erasure::entity_id(cipher)
+        );
+    }
+
+    #[test]
+    fn test_authorize_without_admin_config() {
+        let mut header_map = HeaderMap::new();
+
+        header_map.insert(
+            "XORC-Admin-Token",
+            HeaderValue::from_static("kulli"),
+        );
+
+        assert_eq!(Err(GatewayError::Unauthorized), Erasure::authorize(&header_map, None));
+    }
+}
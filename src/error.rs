@@ -1,5 +1,6 @@
 use context::Context;
-use http::response;
+use http::{header, response};
+use serde_json;
 use std::{error::Error, fmt};
 
 use hyper::{
@@ -8,11 +9,12 @@ use hyper::{
     StatusCode,
 };
 
-use ::CORS;
+use cors::Cors;
 
 #[derive(Debug, PartialEq)]
 pub enum GatewayError {
     AppDoesNotExist,
+    PlatformNotConfigured,
     InvalidToken,
     MissingToken,
     MissingSignature,
@@ -20,10 +22,39 @@ pub enum GatewayError {
     UnknownOrigin,
     BadDeviceId,
     InvalidPayload,
+    Unauthorized,
     InternalServerError(&'static str),
     ServiceUnavailable(&'static str),
 }
 
+impl GatewayError {
+    /// The stable error code in the responses and metrics labels.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            GatewayError::AppDoesNotExist => "app_does_not_exist",
+            GatewayError::PlatformNotConfigured => "platform_not_configured",
+            GatewayError::InvalidToken => "invalid_token",
+            GatewayError::MissingToken => "missing_token",
+            GatewayError::MissingSignature => "missing_signature",
+            GatewayError::InvalidSignature => "invalid_signature",
+            GatewayError::UnknownOrigin => "unknown_origin",
+            GatewayError::BadDeviceId => "bad_device_id",
+            GatewayError::InvalidPayload => "invalid_payload",
+            GatewayError::Unauthorized => "unauthorized",
+            GatewayError::InternalServerError(_) => "internal_server_error",
+            GatewayError::ServiceUnavailable(_) => "service_unavailable",
+        }
+    }
+
+    /// True if sending the same request again later might succeed.
+    pub fn retryable(&self) -> bool {
+        match self {
+            GatewayError::InternalServerError(_) | GatewayError::ServiceUnavailable(_) => true,
+            _ => false,
+        }
+    }
+}
+
 impl fmt::Display for GatewayError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Error handling a request")
@@ -35,6 +66,8 @@ impl Error for GatewayError {
         match self {
             GatewayError::AppDoesNotExist =>
                 "Application does not exist",
+            GatewayError::PlatformNotConfigured =>
+                "The application has no secret for the platform",
             GatewayError::InvalidToken =>
                 "The given SDK token was invalid",
             GatewayError::MissingToken =>
@@ -49,6 +82,8 @@ impl Error for GatewayError {
                 "There is something fishy in the device id encryption",
             GatewayError::InvalidPayload =>
                 "The request JSON was faulty",
+            GatewayError::Unauthorized =>
+                "The admin token was missing or invalid",
             GatewayError::InternalServerError(reason) =>
                 reason,
             GatewayError::ServiceUnavailable(reason) =>
@@ -61,8 +96,8 @@ impl Error for GatewayError {
     }
 }
 
-fn response_builder_for(context: &Option<Context>) -> response::Builder {
-    if let Some(ref cors) = *CORS {
+fn response_builder_for(context: &Option<Context>, cors: Option<&Cors>) -> response::Builder {
+    if let Some(cors) = cors {
         if let Some(context) = context {
             return cors.response_builder_origin(
                 &context.app_id,
@@ -75,52 +110,106 @@ fn response_builder_for(context: &Option<Context>) -> response::Builder {
     Response::builder()
 }
 
+/// The JSON document of an error response.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    pub request_id: Option<String>,
+    pub retryable: bool,
+}
+
+/// The error response, a JSON document with the error code, a message, the
+/// request id if known and whether the request can be retried.
 pub fn into_response(
     error: &GatewayError,
     context: &Option<Context>,
+    cors: Option<&Cors>,
+    request_id: Option<&str>,
 ) -> Response<Body> {
-    let mut builder = response_builder_for(&context);
-
-    match error {
-        GatewayError::AppDoesNotExist => {
-            builder.status(StatusCode::FORBIDDEN);
-            builder.body("Unknown app".into()).unwrap()
-        },
-        GatewayError::InvalidToken => {
-            builder.status(StatusCode::PRECONDITION_FAILED);
-            builder.body("Invalid XORC-Api-Token".into()).unwrap()
-        },
-        GatewayError::MissingToken => {
-            builder.status(StatusCode::PRECONDITION_FAILED);
-            builder.body("Missing XORC-Api-Token".into()).unwrap()
-        },
-        GatewayError::MissingSignature => {
-            builder.status(StatusCode::PRECONDITION_FAILED);
-            builder.body("Missing XORC-Signature".into()).unwrap()
-        },
-        GatewayError::InvalidSignature => {
-            builder.status(StatusCode::PRECONDITION_FAILED);
-            builder.body("Invalid XORC-Signature".into()).unwrap()
-        },
-        GatewayError::UnknownOrigin => {
-            builder.status(StatusCode::FORBIDDEN);
-            builder.body("Unknown Origin".into()).unwrap()
-        },
-        GatewayError::BadDeviceId => {
-            builder.status(StatusCode::BAD_REQUEST);
-            builder.body("Bad XORC-Device-Id".into()).unwrap()
-        },
-        GatewayError::InvalidPayload => {
-            builder.status(StatusCode::BAD_REQUEST);
-            builder.body("Invalid payload".into()).unwrap()
-        },
-        GatewayError::InternalServerError(_) => {
-            builder.status(StatusCode::INTERNAL_SERVER_ERROR);
-            builder.body("Invalid Server Error".into()).unwrap()
-        },
-        GatewayError::ServiceUnavailable(_) => {
-            builder.status(StatusCode::SERVICE_UNAVAILABLE);
-            builder.body("Service unavailable".into()).unwrap()
-        },
+    let mut builder = response_builder_for(&context, cors);
+
+    let (status, message) = match error {
+        GatewayError::AppDoesNotExist =>
+            (StatusCode::FORBIDDEN, "Unknown app"),
+        GatewayError::PlatformNotConfigured =>
+            (StatusCode::FORBIDDEN, "Platform not configured for the app"),
+        GatewayError::InvalidToken =>
+            (StatusCode::PRECONDITION_FAILED, "Invalid XORC-Api-Token"),
+        GatewayError::MissingToken =>
+            (StatusCode::PRECONDITION_FAILED, "Missing XORC-Api-Token"),
+        GatewayError::MissingSignature =>
+            (StatusCode::PRECONDITION_FAILED, "Missing XORC-Signature"),
+        GatewayError::InvalidSignature =>
+            (StatusCode::PRECONDITION_FAILED, "Invalid XORC-Signature"),
+        GatewayError::UnknownOrigin =>
+            (StatusCode::FORBIDDEN, "Unknown Origin"),
+        GatewayError::BadDeviceId =>
+            (StatusCode::BAD_REQUEST, "Bad XORC-Device-Id"),
+        GatewayError::InvalidPayload =>
+            (StatusCode::BAD_REQUEST, "Invalid payload"),
+        GatewayError::Unauthorized =>
+            (StatusCode::UNAUTHORIZED, "Invalid XORC-Admin-Token"),
+        GatewayError::InternalServerError(_) =>
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error"),
+        GatewayError::ServiceUnavailable(_) =>
+            (StatusCode::SERVICE_UNAVAILABLE, "Service unavailable"),
+    };
+
+    let body = ErrorBody {
+        code: error.kind().to_string(),
+        message: message.to_string(),
+        request_id: request_id.map(String::from),
+        retryable: error.retryable(),
+    };
+
+    builder.status(status);
+    builder.header(header::CONTENT_TYPE, "application/json");
+    builder.body(serde_json::to_string(&body).unwrap().into()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Future, Stream};
+
+    fn body(response: Response<Body>) -> ErrorBody {
+        let body = response.into_body().concat2().wait().unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn test_json_error() {
+        let response = into_response(&GatewayError::InvalidSignature, &None, None, Some("abc"));
+
+        assert_eq!(StatusCode::PRECONDITION_FAILED, response.status());
+        assert_eq!("application/json", response.headers().get(header::CONTENT_TYPE).unwrap());
+
+        let expected = ErrorBody {
+            code: String::from("invalid_signature"),
+            message: String::from("Invalid XORC-Signature"),
+            request_id: Some(String::from("abc")),
+            retryable: false,
+        };
+
+        assert_eq!(expected, body(response));
+    }
+
+    #[test]
+    fn test_retryable_error() {
+        let error = GatewayError::ServiceUnavailable("Could not send to kafka");
+        let body = body(into_response(&error, &None, None, None));
+
+        assert_eq!("service_unavailable", body.code);
+        assert_eq!(None, body.request_id);
+        assert!(body.retryable);
+    }
+
+    #[test]
+    fn test_platform_not_configured() {
+        let response = into_response(&GatewayError::PlatformNotConfigured, &None, None, None);
+
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+        assert_eq!("platform_not_configured", body(response).code);
     }
 }
@@ -1,9 +1,11 @@
+mod sdk_consent;
 mod sdk_device;
 mod sdk_environment;
 mod sdk_event;
 mod sdk_event_batch;
 mod sdk_response;
 
+pub use self::sdk_consent::SDKConsent;
 pub use self::sdk_device::{SDKDevice, Platform};
 pub use self::sdk_environment::SDKEnvironment;
 pub use self::sdk_event::SDKEvent;
@@ -0,0 +1,8 @@
+/// The user's consent as given by the SDK. A missing flag means the SDK
+/// doesn't know, and is handled as if the consent was given.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SDKConsent {
+    pub analytics: Option<bool>,
+    pub advertising: Option<bool>,
+    pub personalization: Option<bool>,
+}
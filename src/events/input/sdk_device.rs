@@ -1,9 +1,7 @@
 use events::output::common;
-use std::net::IpAddr;
-use blake2::{Blake2b, Digest};
-use base64;
-use maxminddb::geoip2::Country;
-use ::GEOIP;
+use geoip::Geo;
+use ip_hash::HashedIp;
+use user_agent::UserAgent;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Platform {
@@ -55,19 +53,34 @@ pub struct SDKDevice
 }
 
 impl SDKDevice {
-    pub fn set_location(&mut self, ip: &IpAddr) {
-        let data = format!("{}", ip);
-
-        let mut hasher = Blake2b::new();
-        hasher.input(data.as_bytes());
+    /// Sets the IP hash, the country and, if the SDK sent none, the time
+    /// zone from an earlier hashing and lookup. A truncated address leaves
+    /// the hash empty.
+    pub fn set_location_from(&mut self, hashed: &HashedIp, geo: &Geo) {
+        self.ip_hashed_blake2 = hashed.hash();
+        self.country = geo.country.clone();
+
+        if self.time_zone.is_none() {
+            self.time_zone = geo.time_zone.clone();
+        }
+    }
 
-        let hash = hasher.result();
-        self.ip_hashed_blake2 = Some(base64::encode(hash.as_slice()));
+    /// Fills the browser, OS and device fields the SDK didn't set from the
+    /// parsed user agent.
+    pub fn set_user_agent(&mut self, user_agent: &UserAgent) {
+        fn fill(field: &mut Option<String>, value: &Option<String>) {
+            if field.is_none() {
+                *field = value.clone();
+            }
+        }
 
-        self.country = GEOIP.lookup(ip.clone())
-            .ok()
-            .and_then(|res: Country| res.country)
-            .and_then(|res| res.iso_code);
+        fill(&mut self.browser_ua, &Some(user_agent.raw.clone()));
+        fill(&mut self.browser_name, &user_agent.browser_name);
+        fill(&mut self.browser_version, &user_agent.browser_version);
+        fill(&mut self.os_name, &user_agent.os_name);
+        fill(&mut self.os_version, &user_agent.os_version);
+        fill(&mut self.manufacturer, &user_agent.vendor);
+        fill(&mut self.model, &user_agent.model);
     }
 }
 
@@ -143,6 +156,21 @@ impl Into<common::Device> for SDKDevice {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use user_agent::DeviceType;
+    use std::net::IpAddr;
+    use ip_hash::{IpHashing, IpHashPolicy};
+    use geoip::GeoIp;
+    use config::Config;
+
+    /// Sets the location like `handle_event` does, with the country
+    /// database and the unkeyed digest.
+    fn locate(device: &mut SDKDevice, ip: &IpAddr) {
+        let config = Config::parse("./config/config.toml.tests");
+        let geoip = GeoIp::from_config("./resources/GeoLite2-Country.mmdb", &config).unwrap();
+        let hashed = IpHashing::with_policy(IpHashPolicy::Digest).hash(ip);
+
+        device.set_location_from(&hashed, &geoip.lookup(ip));
+    }
 
     use events::output::common;
     use serde_json;
@@ -317,6 +345,77 @@ mod tests {
         assert_eq!(Some(String::from("web")), proto.platform);
     }
 
+    #[test]
+    fn test_set_user_agent_fills_missing_fields() {
+        let json = json!({
+            "platform": "web",
+            "browser_name": "Chromium",
+        });
+
+        let user_agent = UserAgent {
+            raw: String::from("Mozilla/5.0 (Windows NT 10.0; Win64; x64) Chrome/74.0.3729.169"),
+            browser_name: Some(String::from("Chrome")),
+            browser_version: Some(String::from("74.0.3729")),
+            os_name: Some(String::from("Windows")),
+            os_version: Some(String::from("10")),
+            vendor: None,
+            model: None,
+            device_type: DeviceType::Desktop,
+            bot: false,
+        };
+
+        let mut device: SDKDevice = serde_json::from_value(json).unwrap();
+        device.set_user_agent(&user_agent);
+
+        assert_eq!(Platform::Web, device.platform());
+
+        let proto: common::Device = device.into();
+        let browser = proto.browser.unwrap();
+
+        assert_eq!(Some(String::from("Chromium")), browser.name);
+        assert_eq!(Some(String::from("74.0.3729")), browser.version);
+        assert_eq!(Some(user_agent.raw), browser.ua);
+        assert_eq!(Some(String::from("Windows")), proto.os);
+        assert_eq!(Some(String::from("10")), proto.osv);
+    }
+
+    #[test]
+    fn test_time_zone_from_geo() {
+        let geo = Geo {
+            country: Some(String::from("DE")),
+            time_zone: Some(String::from("Europe/Berlin")),
+            ..Default::default()
+        };
+
+        let hashed = IpHashing::with_policy(IpHashPolicy::Truncate).hash(&"109.68.226.154".parse().unwrap());
+
+        let mut device: SDKDevice = serde_json::from_value(json!({ "platform": "web" })).unwrap();
+        device.set_location_from(&hashed, &geo);
+
+        let proto: common::Device = device.into();
+
+        assert_eq!(Some(String::from("Europe/Berlin")), proto.timezone);
+        assert_eq!(Some(String::from("DE")), proto.country);
+    }
+
+    #[test]
+    fn test_time_zone_from_sdk_kept() {
+        let geo = Geo {
+            time_zone: Some(String::from("Europe/Berlin")),
+            ..Default::default()
+        };
+
+        let hashed = IpHashing::with_policy(IpHashPolicy::Truncate).hash(&"109.68.226.154".parse().unwrap());
+
+        let json = json!({ "platform": "ios", "time_zone": "Europe/Helsinki" });
+        let mut device: SDKDevice = serde_json::from_value(json).unwrap();
+        device.set_location_from(&hashed, &geo);
+
+        let proto: common::Device = device.into();
+
+        assert_eq!(Some(String::from("Europe/Helsinki")), proto.timezone);
+    }
+
     #[test]
     fn test_set_real_location_ipv4() {
         let json = json!({
@@ -326,7 +425,7 @@ mod tests {
         let ip_addr: IpAddr = "109.68.226.154".parse().unwrap();
 
         let mut device: SDKDevice = serde_json::from_value(json).unwrap();
-        device.set_location(&ip_addr);
+        locate(&mut device, &ip_addr);
 
         let proto: common::Device = device.into();
 
@@ -350,7 +449,7 @@ mod tests {
         let ip_addr: IpAddr = "2001:4860:4860::8844".parse().unwrap();
 
         let mut device: SDKDevice = serde_json::from_value(json).unwrap();
-        device.set_location(&ip_addr);
+        locate(&mut device, &ip_addr);
 
         let proto: common::Device = device.into();
 
@@ -374,7 +473,7 @@ mod tests {
         let ip_addr: IpAddr = "127.0.0.1".parse().unwrap();
 
         let mut device: SDKDevice = serde_json::from_value(json).unwrap();
-        device.set_location(&ip_addr);
+        locate(&mut device, &ip_addr);
 
         let proto: common::Device = device.into();
 
@@ -398,7 +497,7 @@ mod tests {
         let ip_addr: IpAddr = "::1".parse().unwrap();
 
         let mut device: SDKDevice = serde_json::from_value(json).unwrap();
-        device.set_location(&ip_addr);
+        locate(&mut device, &ip_addr);
 
         let proto: common::Device = device.into();
 
@@ -116,7 +116,7 @@ impl SDKEvent
                     });
                 },
                 Value::Object(map) => {
-                    let prefix = format!("{}{}__", prefix, prefixed_key);
+                    let prefix = format!("{}__", prefixed_key);
                     Self::flatten_properties(&prefix, map, &mut container);
                 },
                 other => {
@@ -240,4 +240,47 @@ mod tests {
             proto.properties[0].type_,
         );
     }
+
+    /// Found by the `flatten_properties` fuzz target, the prefix was
+    /// repeated on every level and the keys grew exponentially.
+    #[test]
+    fn test_with_nested_object_property() {
+        let json = json!({
+            "timestamp": "1527092525607",
+            "name": "test_event",
+            "properties": {
+                "foo": {
+                    "bar": {
+                        "baz": 1,
+                    },
+                },
+            }
+        });
+
+        let event: SDKEvent = serde_json::from_value(json).unwrap();
+        let proto: output::events::SdkEventData = event.into();
+
+        assert_eq!("foo__bar__baz", proto.properties[0].key);
+    }
+
+    #[test]
+    fn test_with_deeply_nested_object_property() {
+        let mut properties = json!({ "leaf": true });
+
+        for _ in 0..64 {
+            properties = json!({ "a": properties });
+        }
+
+        let json = json!({
+            "timestamp": "1527092525607",
+            "name": "test_event",
+            "properties": properties,
+        });
+
+        let event: SDKEvent = serde_json::from_value(json).unwrap();
+        let proto: output::events::SdkEventData = event.into();
+
+        assert_eq!(1, proto.properties.len());
+        assert_eq!(64 * "a__".len() + "leaf".len(), proto.properties[0].key.len());
+    }
 }
@@ -1,6 +1,8 @@
 use events::{input, output};
 use chrono::offset::Utc;
 use context::Context;
+use consent::ConsentAction;
+use geoip::Geo;
 
 #[derive(Deserialize, Debug)]
 pub struct SDKEventBatch
@@ -9,6 +11,7 @@ pub struct SDKEventBatch
     pub events: Vec<input::SDKEvent>,
     pub device: input::SDKDevice,
     pub recipient_id: Option<String>,
+    pub consent: Option<input::SDKConsent>,
 }
 
 impl SDKEventBatch
@@ -18,15 +21,28 @@ impl SDKEventBatch
             self.recipient_id = Some(device_id.cleartext.clone().into());
         }
 
-        if let Some(ref ip) = context.ip {
-            self.device.set_location(ip);
+        // Hashed and looked up in `handle_event` for validated requests
+        // with a client address, otherwise the fields stay empty.
+        match (&context.ip_hash, &context.geo) {
+            // The time zone of the address locates the user as well as the
+            // country does.
+            (Some(hashed), Some(geo)) if context.consent.has(ConsentAction::StripCountry) => {
+                let geo = Geo { time_zone: None, ..geo.clone() };
+                self.device.set_location_from(hashed, &geo)
+            },
+            (Some(hashed), Some(geo)) => self.device.set_location_from(hashed, geo),
+            _ => (),
+        }
+
+        if let Some(ref user_agent) = context.user_agent {
+            self.device.set_user_agent(user_agent);
         }
 
         self.events.sort_unstable_by(|e1, e2| {
             e1.timestamp.cmp(&e2.timestamp)
         });
 
-        output::events::SdkEventBatch {
+        let mut proto = output::events::SdkEventBatch {
             header: output::common::Header {
                 created_at: Utc::now().timestamp_millis(),
                 source: self.environment.app_id.clone(),
@@ -37,7 +53,11 @@ impl SDKEventBatch
             environment: Some(self.environment.into()),
             device: Some(self.device.into()),
             event: self.events.into_iter().map(|ev| ev.into()).collect(),
-        }
+        };
+
+        context.consent.strip(&mut proto);
+
+        proto
     }
 }
 
@@ -51,6 +71,21 @@ mod tests {
     use http::header::HeaderValue;
     use context::Context;
     use events::input::Platform;
+    use consent::ConsentAction;
+    use ip_hash::{IpHashing, IpHashPolicy};
+    use geoip::GeoIp;
+    use config::Config;
+
+    /// Locates the client like `handle_event` does, with the country
+    /// database and the unkeyed digest.
+    fn locate(context: &mut Context) {
+        let ip = context.ip.unwrap();
+        let config = Config::parse("./config/config.toml.tests");
+        let geoip = GeoIp::from_config("./resources/GeoLite2-Country.mmdb", &config).unwrap();
+
+        context.geo = Some(geoip.lookup(&ip));
+        context.ip_hash = Some(IpHashing::with_policy(IpHashPolicy::Digest).hash(&ip));
+    }
 
     #[test]
     fn test_empty_header_values() {
@@ -74,7 +109,8 @@ mod tests {
             HeaderValue::from_static(cipher),
         );
 
-        let context = Context::new(&header_map, "123", Platform::Ios);
+        let mut context = Context::new(&header_map, "123", Platform::Ios);
+        locate(&mut context);
 
         let batch: SDKEventBatch = serde_json::from_value(json).unwrap();
         let proto: output::events::SdkEventBatch = batch.into_proto(&context);
@@ -124,4 +160,107 @@ mod tests {
         assert!(device.ip_hashed_blake2.is_none());
         assert!(header.recipient_id.is_none());
     }
+
+    #[test]
+    fn test_with_consent() {
+        let json = json!({
+            "environment": {},
+            "device": {},
+            "events": [],
+            "consent": {
+                "analytics": true,
+                "advertising": false,
+            }
+        });
+
+        let batch: SDKEventBatch = serde_json::from_value(json).unwrap();
+        let consent = batch.consent.unwrap();
+
+        assert_eq!(Some(true), consent.analytics);
+        assert_eq!(Some(false), consent.advertising);
+        assert!(consent.personalization.is_none());
+    }
+
+    #[test]
+    fn test_consent_strips_location() {
+        let json = json!({
+            "environment": {},
+            "device": {},
+            "events": []
+        });
+
+        let mut header_map = HeaderMap::new();
+
+        header_map.insert(
+            "x-real-ip",
+            HeaderValue::from_static("109.68.226.154"),
+        );
+
+        let mut context = Context::new(&header_map, "123", Platform::Ios);
+        locate(&mut context);
+        context.consent.actions = vec![ConsentAction::StripIpHash, ConsentAction::StripCountry];
+
+        let batch: SDKEventBatch = serde_json::from_value(json).unwrap();
+        let device = batch.into_proto(&context).device.unwrap();
+
+        assert!(device.country.is_none());
+        assert!(device.ip_hashed_blake2.is_none());
+    }
+
+    #[test]
+    fn test_without_location() {
+        let json = json!({
+            "environment": {},
+            "device": {},
+            "events": []
+        });
+
+        let mut header_map = HeaderMap::new();
+
+        header_map.insert(
+            "x-real-ip",
+            HeaderValue::from_static("109.68.226.154"),
+        );
+
+        let context = Context::new(&header_map, "123", Platform::Ios);
+
+        let batch: SDKEventBatch = serde_json::from_value(json).unwrap();
+        let device = batch.into_proto(&context).device.unwrap();
+
+        assert!(device.country.is_none());
+        assert!(device.ip_hashed_blake2.is_none());
+    }
+
+    #[test]
+    fn test_consent_strips_geo_time_zone() {
+        let json = json!({
+            "environment": {},
+            "device": {},
+            "events": []
+        });
+
+        let ip = "109.68.226.154".parse().unwrap();
+        let mut context = Context::new(&HeaderMap::new(), "123", Platform::Ios);
+
+        context.ip = Some(ip);
+        context.ip_hash = Some(IpHashing::with_policy(IpHashPolicy::Truncate).hash(&ip));
+        context.geo = Some(Geo {
+            country: Some(String::from("DE")),
+            time_zone: Some(String::from("Europe/Berlin")),
+            ..Default::default()
+        });
+
+        let batch: SDKEventBatch = serde_json::from_value(json.clone()).unwrap();
+        let device = batch.into_proto(&context).device.unwrap();
+
+        assert_eq!(Some(String::from("Europe/Berlin")), device.timezone);
+
+        context.consent.actions = vec![ConsentAction::StripCountry];
+
+        let batch: SDKEventBatch = serde_json::from_value(json).unwrap();
+        let device = batch.into_proto(&context).device.unwrap();
+
+        assert!(device.country.is_none());
+        assert!(device.timezone.is_none());
+    }
 }
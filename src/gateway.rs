@@ -4,18 +4,20 @@ use prometheus::{
     TextEncoder
 };
 
-use http::{header, HeaderMap};
+use http::{header::{self, HeaderValue}, HeaderMap};
 
 use hyper::{
-    Body, Method, Request, Response, Server, StatusCode,
-    service::service_fn,
+    Body, Chunk, Method, Request, Response, Server, StatusCode,
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
 };
 
 use std::{
-    net::ToSocketAddrs,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
     sync::Arc,
     error::Error,
     env,
+    time::Instant,
 };
 
 use futures::{
@@ -45,55 +47,147 @@ use events::{
 
 use tokio_threadpool::blocking;
 use bus;
+use ifa_store::IfaStore;
+use app_registry::AppRegistry;
+use config::Config;
+use cors::Cors;
+use consent::{Consent, ConsentAction};
 use serde_json;
 use error::{self, GatewayError};
-use context::{Context, DeviceId};
-use tokio::runtime::{Builder as RuntimeBuilder};
+use context::{Context, DeviceId, request_id, REQUEST_ID};
+use tokio::runtime::{Builder as RuntimeBuilder, Runtime};
 use encryption::{Cleartext, Ciphertext};
+use erasure::Erasure;
 use prost::Message;
+use archive::{Archive, Entry, Outcome};
+use chrono::{DateTime, offset::Utc};
 use metrics::*;
+use tracing::{Span, TraceContext, Tracing, TRACEPARENT};
+use access_log::{AccessLog, RequestInfo, RequestStats, Stage};
+use client_ip::ClientIpResolver;
+use user_agent::UserAgents;
+use geoip::GeoIp;
+use ip_hash::IpHashing;
+use redaction::LogRedaction;
 
 use ::{
     GLOG,
+    GEOIP,
+    IP_HASHING,
+    LOG_REDACTION,
     APP_REGISTRY,
     CORS,
     CONFIG,
+    CONSENT,
     IFA_MATCHING,
+    ARCHIVE,
+    SDK_METRICS,
+    TRACING,
+    ACCESS_LOG,
+    CLIENT_IP,
+    USER_AGENTS,
 };
 
-struct BusConnections {
-    pub kafka: bus::Kafka,
+/// Everything the gateway uses for handling the requests.
+pub struct Dependencies {
+    pub config: Arc<Config>,
+    pub app_registry: Arc<AppRegistry>,
+    pub cors: Option<Arc<Cors>>,
+    pub consent: Arc<Consent>,
+    pub archive: Option<Arc<Archive>>,
+    pub sdk_metrics: Arc<SdkMetrics>,
+    pub tracing: Arc<Tracing>,
+    pub access_log: Option<Arc<AccessLog>>,
+    pub client_ip: Arc<ClientIpResolver>,
+    pub user_agents: Option<Arc<UserAgents>>,
+    pub geoip: Arc<GeoIp>,
+    pub ip_hashing: Arc<IpHashing>,
+    pub log_redaction: Arc<LogRedaction>,
+    pub ifa_store: Arc<IfaStore>,
+    pub bus: Arc<bus::Bus>,
+}
+
+/// Where the request handled comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Source {
+    /// A request from the SDK, authenticated with the token and signature.
+    Request,
+    /// A request replayed from the archive. It was authenticated when it
+    /// was received, and the archive has the credentials redacted.
+    Replay,
+}
+
+/// What's known of an SDK request besides its headers and body.
+struct RequestMeta {
+    trace: TraceContext,
+    client_ip: Option<IpAddr>,
+    stats: RequestStats,
+}
+
+impl Dependencies {
+    /// The global configuration, app registry, CORS and consent policies,
+    /// archive, SDK metrics, tracing, access log, client address resolver,
+    /// user agent parser, GeoIP, IP hashing and log redaction with the given
+    /// bus and IFA store.
+    pub fn new(bus: Arc<bus::Bus>, ifa_store: Arc<IfaStore>) -> Dependencies {
+        Dependencies {
+            config: CONFIG.clone(),
+            app_registry: APP_REGISTRY.clone(),
+            cors: CORS.clone(),
+            consent: CONSENT.clone(),
+            archive: ARCHIVE.clone(),
+            sdk_metrics: SDK_METRICS.clone(),
+            tracing: TRACING.clone(),
+            access_log: ACCESS_LOG.clone(),
+            client_ip: CLIENT_IP.clone(),
+            user_agents: USER_AGENTS.clone(),
+            geoip: GEOIP.clone(),
+            ip_hashing: IP_HASHING.clone(),
+            log_redaction: LOG_REDACTION.clone(),
+            ifa_store,
+            bus,
+        }
+    }
+
+    fn cors(&self) -> Option<&Cors> {
+        self.cors.as_ref().map(|cors| &**cors)
+    }
 }
 
 pub struct Gateway {
-    connections: Arc<BusConnections>,
+    deps: Arc<Dependencies>,
 }
 
 impl Clone for Gateway {
     fn clone(&self) -> Gateway {
         Gateway {
-            connections: self.connections.clone(),
+            deps: self.deps.clone(),
         }
     }
 }
 
 impl Gateway {
+    /// The gateway sending to Kafka and matching IFAs from ScyllaDB.
     fn new() -> Gateway {
-        let connections = Arc::new(BusConnections {
-            kafka: bus::Kafka::new(),
-        });
+        Self::with(Dependencies::new(Arc::new(bus::Kafka::new()), IFA_MATCHING.clone()))
+    }
 
-        Gateway { connections }
+    pub fn with(deps: Dependencies) -> Gateway {
+        Gateway { deps: Arc::new(deps) }
     }
 
     /// ROUTES
     ///
-    /// - OPTIONS to /xray/events/xorc/sdk/v1 :: for CORS/web-push
+    /// - OPTIONS to /xray/events/xorc/sdk/v1 :: for CORS/web-push, app_id in query
     /// - POST to /xray/events/xorc/sdk/v1    :: SDK Events, sent to kafka/rmq
+    /// - POST to /xray/admin/v1/erasure           :: Remove device identity mappings
     /// - GET to /metrics                          :: Prometheus metrics
-    fn service(
+    ///
+    /// The peer is the address of the connection, if known.
+    pub fn service(
         &self,
         req: Request<Body>,
+        peer: Option<IpAddr>,
     ) -> Box<Future<Item=Response<Body>, Error=GatewayError> + Send + 'static>
     {
         match (req.method(), req.uri().path()) {
@@ -104,17 +198,35 @@ impl Gateway {
                     "options",
                 ]).inc();
 
-                Box::new(Self::handle_options())
+                Box::new(Self::handle_options(req, self.deps.clone()))
             },
             // SDK events main path
             (&Method::POST, "/xray/events/xorc/sdk/v1") => {
                 let timer = RESPONSE_TIMES_HISTOGRAM.start_timer();
+                let client_ip = self.deps.client_ip.resolve(req.headers(), peer);
+                let trusted_peer = self.deps.client_ip.trusted_peer(peer);
+                let access_log = self.deps.access_log.clone().map(|log| (log, RequestInfo::new(&req, client_ip)));
+                let ip_hashing = self.deps.ip_hashing.clone();
+                let stats = RequestStats::new();
 
-                Box::new(Self::handle_sdk(req, self.connections.clone()).then(|response| {
+                Box::new(Self::handle_sdk(req, client_ip, trusted_peer, stats.clone(), self.deps.clone()).then(move |response| {
                     timer.observe_duration();
+
+                    if let Some((access_log, request)) = access_log {
+                        if let Ok(ref response) = response {
+                            if access_log.sample(response) {
+                                access_log.write(&request, response, &stats.get(), &ip_hashing);
+                            }
+                        }
+                    }
+
                     response
                 }))
             },
+            // GDPR erasure, only if `[admin]` is configured
+            (&Method::POST, "/xray/admin/v1/erasure") if self.deps.config.admin.is_some() => {
+                Box::new(Self::handle_erasure(req, self.deps.clone()))
+            },
             // Prometheus metrics
             (&Method::GET, "/metrics") => {
                 Box::new(Self::handle_metrics())
@@ -132,31 +244,36 @@ impl Gateway {
         }
     }
 
+    /// The listening address from `[gateway].address`, defaulting to
+    /// `0.0.0.0:1337`. The `PORT` environment variable overrides the port.
+    fn address(address: Option<&str>, port: Option<String>) -> SocketAddr {
+        let mut addr = address
+            .unwrap_or("0.0.0.0:1337")
+            .to_socket_addrs()
+            .unwrap()
+            .next()
+            .unwrap();
+
+        if let Some(port) = port.and_then(|p| p.parse().ok()) {
+            addr.set_port(port);
+        }
+
+        addr
+    }
+
     /// Run the service, keeps running until a signal is sent through rx
     pub fn run(rx: oneshot::Receiver<()>) {
-        let port = match env::var("PORT") {
-            Ok(val) => val,
-            Err(_) => String::from("1337"),
-        };
-
-        let mut addr_iter = format!("0.0.0.0:{}", port).to_socket_addrs().unwrap();
-        let addr = addr_iter.next().unwrap();
+        let addr = Self::address(
+            CONFIG.gateway.address.as_ref().map(|a| a.as_str()),
+            env::var("PORT").ok(),
+        );
 
         let mut runtime = RuntimeBuilder::new()
             .name_prefix(CONFIG.gateway.process_name_prefix.clone())
             .core_threads(CONFIG.gateway.threads)
             .build().unwrap();
 
-        let gateway = Self::new();
-
-        let server = Server::bind(&addr)
-            .serve(move || {
-                let gw = gateway.clone();
-                service_fn(move |req: Request<Body>| {
-                    gw.service(req)
-                })
-            })
-            .map_err(|e| error!(*GLOG, "Critical server error, exiting: {}", e));
+        let addr = Self::new().spawn(&addr, &mut runtime, rx);
 
         info!(
             *GLOG,
@@ -164,10 +281,39 @@ impl Gateway {
             &addr
         );
 
-        runtime.spawn(server.select2(rx).then(move |_| Ok(())));
         runtime.shutdown_on_idle().wait().unwrap();
     }
 
+    /// Binds the server to the address and runs it in the runtime until a
+    /// signal is sent through rx. Returns the bound address, useful when
+    /// binding to port 0.
+    pub fn spawn(
+        self,
+        addr: &SocketAddr,
+        runtime: &mut Runtime,
+        rx: oneshot::Receiver<()>,
+    ) -> SocketAddr
+    {
+        let server = Server::bind(addr)
+            .serve(make_service_fn(move |socket: &AddrStream| {
+                let gw = self.clone();
+                let peer = socket.remote_addr().ip();
+
+                service_fn(move |req: Request<Body>| {
+                    gw.service(req, Some(peer))
+                })
+            }));
+
+        let local_addr = server.local_addr();
+
+        let server = server
+            .map_err(|e| error!(*GLOG, "Critical server error, exiting: {}", e));
+
+        runtime.spawn(server.select2(rx).then(move |_| Ok(())));
+
+        local_addr
+    }
+
     /// Prometheus endpoint
     fn handle_metrics(
     ) -> impl Future<Item=Response<Body>, Error=GatewayError> + 'static + Send
@@ -188,13 +334,94 @@ impl Gateway {
         ok(builder.body(buffer.into()).unwrap())
     }
 
-    /// OPTIONS requests
+    /// Erasure of device identity mappings, see `erasure.rs`
+    fn handle_erasure(
+        req: Request<Body>,
+        deps: Arc<Dependencies>
+    ) -> impl Future<Item=Response<Body>, Error=GatewayError> + 'static + Send
+    {
+        let (head, body) = req.into_parts();
+        let request_id = request_id(&head.headers);
+        let authorization = Erasure::authorize(&head.headers, deps.config.admin.as_ref());
+
+        body
+            .concat2()
+            .map_err(|_| GatewayError::InternalServerError("body concat"))
+            .and_then(move |body| {
+                match authorization.and_then(|_| Erasure::parse(&body)) {
+                    Ok(request) => {
+                        let app_id = request.app_id.clone();
+
+                        let response = Erasure::run(request, deps.ifa_store.clone()).and_then(move |(result, audit_event)| {
+                            let audit_topic = &deps.config.admin.as_ref().unwrap().audit_topic;
+
+                            deps
+                                .bus
+                                .publish_audit(audit_topic, &app_id, &audit_event)
+                                .map(move |_| serde_json::to_string(&result).unwrap())
+                        });
+
+                        Either::A(response)
+                    },
+                    Err(e) => Either::B(err(e)),
+                }
+            })
+            .then(move |res| {
+                let mut response = match res {
+                    Ok(json_body) => {
+                        REQUEST_COUNTER.with_label_values(&[
+                            "200",
+                            "erasure",
+                        ]).inc();
+
+                        let mut builder = Response::builder();
+
+                        builder.header(
+                            header::CONTENT_TYPE,
+                            "application/json"
+                        );
+
+                        builder.status(StatusCode::OK);
+                        builder.body(json_body.into()).unwrap()
+                    },
+                    Err(e) => {
+                        error!(
+                            *GLOG, "Erasure error: {}", e.description();
+                            "request_id" => &request_id
+                        );
+
+                        let response = error::into_response(&e, &None, None, Some(&request_id));
+
+                        REQUEST_COUNTER.with_label_values(&[
+                            response.status().as_str(),
+                            "erasure",
+                        ]).inc();
+
+                        response
+                    },
+                };
+
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    response.headers_mut().insert(REQUEST_ID, value);
+                }
+
+                ok(response)
+            })
+    }
+
+    /// OPTIONS requests, the app is identified with the `app_id` query
+    /// parameter
     fn handle_options(
+        req: Request<Body>,
+        deps: Arc<Dependencies>,
     ) -> impl Future<Item=Response<Body>, Error=GatewayError> + Send + 'static
     {
         let mut builder =
-            if let Some(ref cors) = *CORS {
-                cors.response_builder_wildcard()
+            if let Some(cors) = deps.cors() {
+                let app_id = Self::query_value(req.uri().query(), "app_id");
+                let origin = req.headers().get(header::ORIGIN).and_then(|h| h.to_str().ok());
+
+                cors.response_builder_preflight(app_id, origin)
             } else {
                 Response::builder()
             };
@@ -203,23 +430,52 @@ impl Gateway {
         ok(builder.body("".into()).unwrap())
     }
 
+    fn query_value<'a>(query: Option<&'a str>, key: &str) -> Option<&'a str> {
+        query?
+            .split('&')
+            .filter_map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                Some((parts.next()?, parts.next()?))
+            })
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+    }
+
     fn create_new_device(
         mut context: Context,
         event: SDKEventBatch,
         event_id: String,
+        stats: RequestStats,
+        deps: Arc<Dependencies>,
     ) -> impl Future<Item=(Vec<EventResult>, Context, SDKEventBatch), Error=GatewayError>
     {
         let fetch_id = match context.device_id {
             Some(ref device_id) => {
                 Either::A(ok(device_id.clone()))
             },
+            _ if context.consent.has(ConsentAction::SkipIfaMatching) => {
+                Either::A(ok(DeviceId::generate()))
+            },
             _ => {
                 let app_id = context.app_id.clone();
+                let ifa_store = deps.ifa_store.clone();
                 let ifa = event.device.ifa.clone();
+                let idfv = event.device.idfv.clone();
                 let tracking_enabled = event.device.ifa_tracking_enabled;
+                let tracing = deps.tracing.clone();
+                let trace = context.trace;
+
+                let get_id = lazy(move || poll_fn(move || blocking(|| stats.time(Stage::IfaMatching, || {
+                    let _span = tracing.span("ifa_matching", trace.as_ref());
 
-                let get_id = lazy(move || poll_fn(move || blocking(|| {
-                    let device_id = IFA_MATCHING
+                    // Erased identifiers are never linked to an entity again,
+                    // and ones we couldn't check are treated as erased.
+                    match ifa_store.is_erased(&app_id, &ifa, &idfv) {
+                        Ok(false) => (),
+                        _ => return DeviceId::generate(),
+                    }
+
+                    let device_id = ifa_store
                         .get_id_for_ifa(&app_id, &ifa, tracking_enabled)
                         .map(move |device_id| {
                             let cleartext = Cleartext::from(device_id);
@@ -230,7 +486,7 @@ impl Gateway {
                             DeviceId::generate()
                         });
 
-                    let _ = IFA_MATCHING.put_id_for_ifa(
+                    let _ = ifa_store.put_id_for_ifa(
                         &app_id,
                         &device_id.cleartext,
                         &ifa,
@@ -238,24 +494,18 @@ impl Gateway {
                     );
 
                     device_id
-                })));
+                }))));
 
                 Either::B(get_id)
             }
         };
 
-        fetch_id.map(|device_id: DeviceId| {
-            let ciphertext = if context.device_id.is_none() {
-                let ciphertext = device_id.ciphertext.clone();
-                context.device_id = Some(device_id);
-
-                ciphertext
-            } else {
-                device_id.ciphertext
-            };
+        fetch_id.map(move |device_id: DeviceId| {
+            let ciphertext = device_id.ciphertext.clone();
+            context.device_id = Some(device_id);
 
             if context.api_token.is_none() {
-                context.api_token = APP_REGISTRY.token_for(&context.app_id);
+                context.api_token = deps.app_registry.token_for(&context.app_id);
             }
 
             let results = vec![EventResult::register(
@@ -269,27 +519,63 @@ impl Gateway {
         }).map_err(|_| GatewayError::ServiceUnavailable("Aerospike is acting slow today"))
     }
 
+    /// An erased entity doesn't get events again. A register batch is given
+    /// a new entity, other batches are rejected.
+    fn check_erased_entity(
+        mut context: Context,
+        register: bool,
+        deps: Arc<Dependencies>,
+    ) -> impl Future<Item=Context, Error=GatewayError>
+    {
+        let entity_id = match context.device_id {
+            Some(ref device_id) => device_id.cleartext.to_string(),
+            None => return Either::A(ok(context)),
+        };
+
+        let app_id = context.app_id.clone();
+        let ifa_store = deps.ifa_store.clone();
+
+        let check = lazy(move || poll_fn(move || blocking(|| {
+            ifa_store.is_entity_erased(&app_id, &entity_id)
+        })));
+
+        Either::B(check.then(move |res| match res {
+            Ok(Ok(false)) => Ok(context),
+            Ok(Ok(true)) if register => {
+                context.device_id = None;
+                Ok(context)
+            },
+            Ok(Ok(true)) => Err(GatewayError::BadDeviceId),
+            _ => Err(GatewayError::ServiceUnavailable("Aerospike is acting slow today")),
+        }))
+    }
+
     fn generate_event_results(
         context: Context,
         event: SDKEventBatch,
+        stats: RequestStats,
+        deps: Arc<Dependencies>,
     ) -> impl Future<Item=(Vec<EventResult>, Context, SDKEventBatch), Error=GatewayError>
     {
         let find_register_event = event.events.iter()
             .find(|ref e| e.is_register())
             .map(|e| e.id.clone());
 
-        if let Some(event_id) = find_register_event {
-            Either::A(Self::create_new_device(context, event, event_id))
-        } else {
-            let results = event.events.iter().map(|e| {
-                EventResult::new(
-                    e.id.clone(),
-                    EventStatus::Success,
-                )
-            }).collect();
-
-            Either::B(ok((results, context, event)))
-        }
+        Self::check_erased_entity(context, find_register_event.is_some(), deps.clone())
+            .and_then(move |context| {
+                if let Some(event_id) = find_register_event {
+                    Either::A(Self::create_new_device(context, event, event_id, stats, deps))
+                } else {
+                    let results = event.events.iter().map(|e| {
+                        EventResult::new(
+                            e.id.clone(),
+                            EventStatus::Success,
+                        )
+                    }).collect();
+
+                    Either::B(ok((results, context, event)))
+                }
+            })
     }
 
     /// SDK event handling is here
@@ -297,16 +583,33 @@ impl Gateway {
         body: &[u8],
         event: SDKEventBatch,
         headers: &HeaderMap,
-        connections: Arc<BusConnections>
+        source: Source,
+        meta: RequestMeta,
+        deps: Arc<Dependencies>
     ) -> impl Future<Item=(String, Context), Error=(GatewayError, Option<Context>)> + 'static + Send
     {
-        let context = Context::new(
+        let RequestMeta { trace, client_ip, stats } = meta;
+
+        let mut context = Context::new(
             &headers,
             &event.environment.app_id,
             event.device.platform(),
         );
 
-        if let Some(ref cors) = *CORS {
+        context.sdk_version = event.environment.sdk_version.clone();
+        context.trace = Some(trace);
+        context.ip = client_ip;
+        context.request_id = Some(request_id(headers));
+        context.log_redaction = Some(deps.log_redaction.policy());
+        context.consent = deps.consent.apply(&event.environment.app_id, &event.consent);
+
+        stats.update(|stats| {
+            stats.app_id = Some(context.app_id.clone());
+            stats.platform = Some(String::from(&context.platform));
+            stats.events = Some(event.events.len());
+        });
+
+        if let Some(cors) = deps.cors() {
             if event.device.platform() == Platform::Web {
                 let app_id = &event.environment.app_id;
                 let origin = headers.get(header::ORIGIN).and_then(|h| h.to_str().ok());
@@ -317,21 +620,71 @@ impl Gateway {
             }
         };
 
-        match APP_REGISTRY.validate(&event, &context, &body) {
+        let validation = {
+            let _span = deps.tracing.span("validate", context.trace.as_ref());
+
+            stats.time(Stage::Validate, || match source {
+                Source::Request => deps.app_registry.validate(&event, &context, &body),
+                Source::Replay => deps.app_registry.validate_app(&event),
+            })
+        };
+
+        match validation {
             Ok(()) => {
-                let response = Self::generate_event_results(context, event)
+                context.validated = true;
+                context.geo = context.ip.as_ref().map(|ip| deps.geoip.lookup(ip));
+                context.ip_hash = context.ip.as_ref().map(|ip| deps.ip_hashing.hash(ip));
+
+                // The SDK can't be trusted to describe web browsers
+                // consistently. Parsed only for validated requests, the
+                // parser is too expensive to run for anybody.
+                if let (Some(user_agents), Platform::Web) = (deps.user_agents.as_ref(), event.device.platform()) {
+                    let raw = event.device.browser_ua
+                        .as_ref()
+                        .map(|ua| ua.as_str())
+                        .or_else(|| headers.get(header::USER_AGENT).and_then(|h| h.to_str().ok()));
+
+                    context.user_agent = raw.map(|raw| user_agents.parse(raw));
+                }
+
+                let response = Self::generate_event_results(context, event, stats.clone(), deps.clone())
                     .map_err(|e| (e, None))
                     .and_then(move |(results, context, event)| {
                         let event_count = event.events.len();
+
+                        if context.consent.has(ConsentAction::DropEvents) {
+                            info!(
+                                *GLOG,
+                                "Dropped {} event(s) without consent", event_count;
+                                &context
+                            );
+
+                            let json = serde_json::to_string(&SDKResponse::from(results)).unwrap();
+                            return Either::A(ok((json, context)))
+                        }
+
                         let proto_event: output::events::SdkEventBatch =
                             event.into_proto(&context);
 
                         let mut payload = Vec::new();
                         proto_event.encode(&mut payload).unwrap();
 
-                        connections
-                            .kafka
+                        let sdk_metrics = deps.sdk_metrics.clone();
+                        let mut span = deps.tracing.span("kafka_produce", context.trace.as_ref());
+                        let publish_started = Instant::now();
+
+                        let publish = deps
+                            .bus
                             .publish(&payload, &context)
+                            .then(move |res| {
+                                stats.record(Stage::Publish, publish_started.elapsed());
+
+                                if res.is_err() {
+                                    span.set_error();
+                                }
+
+                                res
+                            })
                             .or_else(|e| { err((e, None)) })
                             .map(move |_| {
                                 info!(
@@ -341,12 +694,15 @@ impl Gateway {
                                 );
 
                                 EVENTS_COUNTER.inc_by(results.len() as f64);
+                                sdk_metrics.count_events(&context, results.len());
 
                                 (
                                     serde_json::to_string(&SDKResponse::from(results)).unwrap(),
                                     context
                                 )
-                            })
+                            });
+
+                        Either::B(publish)
                     });
 
                 Either::A(response)
@@ -360,73 +716,986 @@ impl Gateway {
     /// The request level SDK event handling
     fn handle_sdk(
         req: Request<Body>,
-        connections: Arc<BusConnections>
+        client_ip: Option<IpAddr>,
+        trusted_peer: bool,
+        stats: RequestStats,
+        deps: Arc<Dependencies>
     ) -> impl Future<Item=Response<Body>, Error=GatewayError> + 'static + Send
     {
+        let received_at = Utc::now();
         let (head, body) = req.into_parts();
-        let headers = head.headers;
+        let mut headers = head.headers;
+        let parent = TraceContext::from_headers(&headers);
+        let span = deps.tracing.request_span("sdk_request", parent.as_ref(), trusted_peer);
+
+        // A generated id is stored to the headers, so it's archived with the
+        // request and found by the handlers
+        let request_id = request_id(&headers);
+        headers.insert(REQUEST_ID, HeaderValue::from_str(&request_id).unwrap());
 
         body
             .concat2()
-            .or_else(|_| err((GatewayError::InternalServerError("body concat"), None)))
-            .and_then(move |body| {
-                if let Ok(event) = serde_json::from_slice::<SDKEventBatch>(&body) {
-                    Either::A(Self::handle_event(
-                        body.as_ref(),
-                        event,
-                        &headers,
-                        connections
-                    ))
-                } else {
-                    Either::B(err((GatewayError::InvalidPayload, None)))
+            .then(move |body| {
+                match body {
+                    Ok(body) => {
+                        Either::A(Self::handle_body(body, headers, received_at, span, client_ip, stats, deps))
+                    },
+                    Err(_) => {
+                        let error = GatewayError::InternalServerError("body concat");
+                        let response = Self::sdk_response(Err((error, None)), &request_id, &deps);
+
+                        Either::B(ok(Self::finish(response, span, &request_id)))
+                    },
                 }
             })
-            .then(move |res| {
-                match res {
-                    Ok((json_body, context)) => {
-                        let mut builder =
-                            if let Some(ref cors) = *CORS {
-                                cors.response_builder_origin(
-                                    &context.app_id,
-                                    context.origin.as_ref().map(|x| &**x),
-                                    &context.platform
-                                )
-                            } else {
-                                Response::builder()
-                            };
+    }
 
-                        builder.header(
-                            header::CONTENT_TYPE,
-                            "application/json"
-                        );
+    /// Ends the request span with the response status, returning the trace
+    /// context in the `traceparent` and the request id in the `X-Request-Id`
+    /// header.
+    fn finish(mut response: Response<Body>, mut span: Span, request_id: &str) -> Response<Body> {
+        let status = response.status();
 
-                        REQUEST_COUNTER.with_label_values(&[
-                            "200",
-                            "sdk_events",
-                        ]).inc();
+        span.set_tag("http.status_code", status.as_str().to_string());
+        span.set_tag("request_id", request_id.to_string());
 
-                        builder.status(StatusCode::OK);
-                        ok(builder.body(json_body.into()).unwrap())
-                    },
-                    Err((e, context)) => {
-                        match context {
-                            Some(ref context) =>
-                                error!(*GLOG, "Response error: {}", e.description(); context),
-                            _ => {
-                                error!(*GLOG, "Response error: {}", e.description());
-                            }
-                        }
+        if !status.is_success() {
+            span.set_error();
+        }
 
-                        let response = error::into_response(&e, &context);
+        if let Ok(value) = HeaderValue::from_str(&span.context().traceparent()) {
+            response.headers_mut().insert(TRACEPARENT, value);
+        }
 
-                        REQUEST_COUNTER.with_label_values(&[
-                            response.status().as_str(),
-                            "sdk_events",
-                        ]).inc();
+        if let Ok(value) = HeaderValue::from_str(request_id) {
+            response.headers_mut().insert(REQUEST_ID, value);
+        }
 
-                        ok(response)
-                    },
+        response
+    }
+
+    /// Handles the received body, archiving the request with the outcome if
+    /// it's sampled.
+    fn handle_body(
+        body: Chunk,
+        headers: HeaderMap,
+        received_at: DateTime<Utc>,
+        mut span: Span,
+        client_ip: Option<IpAddr>,
+        stats: RequestStats,
+        deps: Arc<Dependencies>
+    ) -> impl Future<Item=Response<Body>, Error=GatewayError> + 'static + Send
+    {
+        let trace = span.context();
+        let request_id = request_id(&headers);
+
+        stats.update(|stats| stats.bytes_in = Some(body.len()));
+
+        let parsed = {
+            let _span = deps.tracing.span("parse", Some(&trace));
+            stats.time(Stage::Parse, || serde_json::from_slice::<SDKEventBatch>(&body))
+        };
+
+        let handled = if let Ok(event) = parsed {
+            Either::A(Self::handle_event(
+                body.as_ref(),
+                event,
+                &headers,
+                Source::Request,
+                RequestMeta { trace, client_ip, stats },
+                deps.clone(),
+            ))
+        } else {
+            Either::B(err((GatewayError::InvalidPayload, None)))
+        };
+
+        handled.then(move |res| {
+            let app_id = match res {
+                Ok((_, ref context)) | Err((_, Some(ref context))) => Some(context.app_id.clone()),
+                _ => None,
+            };
+
+            let error = res.as_ref().err().map(|(e, _)| e.description().to_string());
+            let response = Self::sdk_response(res, &request_id, &deps);
+
+            if let Some(ref app_id) = app_id {
+                span.set_tag("app_id", app_id.clone());
+            }
+
+            if let Some(ref archive) = deps.archive {
+                if archive.sample(app_id.as_ref().map(|a| a.as_str())) {
+                    let outcome = Outcome { status: response.status().as_u16(), error };
+                    archive.write(Entry::new(received_at, app_id, &headers, &body, outcome));
                 }
-            })
+            }
+
+            ok(Self::finish(response, span, &request_id))
+        })
+    }
+
+    fn sdk_response(
+        res: Result<(String, Context), (GatewayError, Option<Context>)>,
+        request_id: &str,
+        deps: &Dependencies,
+    ) -> Response<Body>
+    {
+        match res {
+            Ok((json_body, context)) => {
+                let mut builder =
+                    if let Some(cors) = deps.cors() {
+                        cors.response_builder_origin(
+                            &context.app_id,
+                            context.origin.as_ref().map(|x| &**x),
+                            &context.platform
+                        )
+                    } else {
+                        Response::builder()
+                    };
+
+                builder.header(
+                    header::CONTENT_TYPE,
+                    "application/json"
+                );
+
+                REQUEST_COUNTER.with_label_values(&[
+                    "200",
+                    "sdk_events",
+                ]).inc();
+
+                deps.sdk_metrics.count_request(Some(&context), true, "200", None);
+
+                builder.status(StatusCode::OK);
+                builder.body(json_body.into()).unwrap()
+            },
+            Err((e, context)) => {
+                match context {
+                    Some(ref context) =>
+                        error!(*GLOG, "Response error: {}", e.description(); context),
+                    _ => {
+                        error!(*GLOG, "Response error: {}", e.description());
+                    }
+                }
+
+                let response = error::into_response(&e, &context, deps.cors(), Some(request_id));
+
+                REQUEST_COUNTER.with_label_values(&[
+                    response.status().as_str(),
+                    "sdk_events",
+                ]).inc();
+
+                let known_app = context
+                    .as_ref()
+                    .map(|context| deps.app_registry.exists(&context.app_id))
+                    .unwrap_or(false);
+
+                deps.sdk_metrics.count_request(
+                    context.as_ref(),
+                    known_app,
+                    response.status().as_str(),
+                    Some(&e),
+                );
+
+                response
+            },
+        }
+    }
+
+    /// Sends an archived request through the pipeline again, without the
+    /// token and signature checks. Resolves to the JSON response body.
+    pub fn replay(
+        body: &[u8],
+        headers: &HeaderMap,
+        deps: Arc<Dependencies>
+    ) -> impl Future<Item=String, Error=GatewayError> + 'static + Send
+    {
+        if let Ok(event) = serde_json::from_slice::<SDKEventBatch>(body) {
+            let span = deps.tracing.span("sdk_replay", None);
+            let meta = RequestMeta {
+                trace: span.context(),
+                client_ip: deps.client_ip.resolve(headers, None),
+                stats: RequestStats::new(),
+            };
+
+            let replayed = Self::handle_event(body, event, headers, Source::Replay, meta, deps)
+                .then(move |res| {
+                    drop(span);
+                    res
+                })
+                .map(|(json_body, _)| json_body)
+                .map_err(|(e, _)| e);
+
+            Either::A(replayed)
+        } else {
+            Either::B(err(GatewayError::InvalidPayload))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bus::{Memory, Record};
+    use ifa_store::MemoryIfaStore;
+    use config::{OriginConfig, ArchiveConfig, ClientIpConfig, AdminConfig};
+    use std::{fs, thread, time::Duration};
+    use uuid::Uuid;
+    use ring::{hmac, digest};
+    use hyper::Client;
+    use serde_json::Value;
+    use base64;
+    use hex;
+
+    const APP_ID: &'static str = "22222222-0000-0000-0000-000000000000";
+    const TOKEN: &'static str = "46732a28cd445366c6c8dcbd57500af4e69597c8ebe224634d6ccab812275c9c";
+    const IOS_SECRET: &'static str = "1b66af517dd60807aeff8b4582d202ef500085bc0cec92bc3e67f0c58d6203b5";
+    const WEB_SECRET: &'static str = "4c553960fdc2a82f90b84f6ef188e836818fcee2c43a6c32bd6c91f41772657f";
+    const IFA: &'static str = "f3b7e2a0-3e5c-4c8e-9d3a-5b1f1c6e9a42";
+    const ADMIN_TOKEN: &'static str = "c1f0a8e2d4b6971357e9f2a4c6b8d0e1";
+
+    /// A gateway with in-memory dependencies, listening on a random port.
+    struct TestServer {
+        addr: SocketAddr,
+        bus: Arc<Memory>,
+        ifa_store: Arc<MemoryIfaStore>,
+        runtime: Option<Runtime>,
+        shutdown: Option<oneshot::Sender<()>>,
+        client_runtime: Runtime,
+    }
+
+    impl TestServer {
+        fn start(config: Config) -> TestServer {
+            let config = Arc::new(config);
+            let bus = Arc::new(Memory::new());
+            let ifa_store = Arc::new(MemoryIfaStore::new());
+
+            let deps = Dependencies {
+                app_registry: Arc::new(AppRegistry::from_config(&config)),
+                cors: Cors::from_config(&config).map(Arc::new),
+                consent: Arc::new(Consent::from_config(&config)),
+                archive: Archive::from_config(&config).map(Arc::new),
+                sdk_metrics: Arc::new(SdkMetrics::from_config(&config)),
+                tracing: Arc::new(Tracing::from_config(&config)),
+                access_log: AccessLog::from_config(&config).map(Arc::new),
+                client_ip: Arc::new(ClientIpResolver::from_config(&config)),
+                user_agents: UserAgents::from_config(&config).map(Arc::new),
+                geoip: Arc::new(GeoIp::from_config("./resources/GeoLite2-Country.mmdb", &config).unwrap()),
+                ip_hashing: Arc::new(IpHashing::from_config(&config)),
+                log_redaction: Arc::new(LogRedaction::from_config(&config)),
+                ifa_store: ifa_store.clone(),
+                bus: bus.clone(),
+                config,
+            };
+
+            let mut runtime = RuntimeBuilder::new().core_threads(2).build().unwrap();
+            let (shutdown, rx) = oneshot::channel();
+            let addr = Gateway::with(deps).spawn(&"127.0.0.1:0".parse().unwrap(), &mut runtime, rx);
+
+            TestServer {
+                addr,
+                bus,
+                ifa_store,
+                runtime: Some(runtime),
+                shutdown: Some(shutdown),
+                client_runtime: Runtime::new().unwrap(),
+            }
+        }
+
+        fn request(
+            &mut self,
+            method: Method,
+            path: &str,
+            headers: &[(&str, &str)],
+            body: Vec<u8>,
+        ) -> (StatusCode, HeaderMap, String)
+        {
+            let mut builder = Request::builder();
+
+            builder
+                .method(method)
+                .uri(format!("http://{}{}", self.addr, path).as_str());
+
+            for (key, value) in headers {
+                builder.header(*key, *value);
+            }
+
+            let request = builder.body(Body::from(body)).unwrap();
+
+            let response = Client::new().request(request).and_then(|res| {
+                let (head, body) = res.into_parts();
+                body.concat2().map(move |body| (head, body))
+            });
+
+            let (head, body) = self.client_runtime.block_on(response).unwrap();
+
+            (head.status, head.headers, String::from_utf8(body.to_vec()).unwrap())
+        }
+
+        /// Sends the batch signed with the secret, with the app token.
+        fn send_batch(
+            &mut self,
+            batch: &Value,
+            secret: Option<&str>,
+            extra_headers: &[(&str, &str)],
+        ) -> (StatusCode, HeaderMap, String)
+        {
+            let payload = batch.to_string().into_bytes();
+            let signature = secret.map(|secret| sign(secret, &payload));
+
+            let mut headers = vec![
+                ("Content-Type", "application/json"),
+                ("XORC-Api-Token", TOKEN),
+            ];
+
+            if let Some(ref signature) = signature {
+                headers.push(("XORC-Signature", signature.as_str()));
+            }
+
+            headers.extend_from_slice(extra_headers);
+
+            self.request(Method::POST, "/xray/events/xorc/sdk/v1", &headers, payload)
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            if let Some(shutdown) = self.shutdown.take() {
+                let _ = shutdown.send(());
+            }
+
+            if let Some(runtime) = self.runtime.take() {
+                let _ = runtime.shutdown_now().wait();
+            }
+        }
+    }
+
+    fn config() -> Config {
+        Config::load("./config/config.toml.tests").unwrap()
+    }
+
+    fn sign(secret: &str, payload: &[u8]) -> String {
+        let key = hmac::SigningKey::new(&digest::SHA512, &hex::decode(secret).unwrap());
+        base64::encode(hmac::sign(&key, payload).as_ref())
+    }
+
+    fn batch(platform: &str, register: bool) -> Value {
+        let mut events = vec![json!({
+            "id": "2",
+            "timestamp": "1527092525608",
+            "name": "view_item",
+            "properties": { "price": 9.99 },
+        })];
+
+        if register {
+            events.push(json!({
+                "id": "1",
+                "timestamp": "1527092525607",
+                "name": "xorc_register",
+                "properties": {},
+            }));
+        }
+
+        json!({
+            "environment": { "app_id": APP_ID },
+            "device": {
+                "platform": platform,
+                "ifa": IFA,
+                "ifa_tracking_enabled": true,
+            },
+            "events": events,
+        })
+    }
+
+    fn registered_device_id(body: &str) -> String {
+        let response: Value = serde_json::from_str(body).unwrap();
+
+        let ciphertext = response["events_status"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|status| status["registration_data"]["device_id"].as_str())
+            .next()
+            .unwrap();
+
+        Cleartext::decrypt(&Ciphertext::from(ciphertext)).unwrap().as_ref().to_string()
+    }
+
+    fn decode(record: &Record) -> output::events::SdkEventBatch {
+        output::events::SdkEventBatch::decode(&record.payload[..]).unwrap()
+    }
+
+    fn uuid(value: &str) -> Uuid {
+        Uuid::parse_str(value).unwrap()
+    }
+
+    fn admin_config() -> Config {
+        let mut config = config();
+
+        config.admin = Some(AdminConfig {
+            token: ADMIN_TOKEN.to_string(),
+            audit_topic: "audit".to_string(),
+        });
+
+        config
+    }
+
+    #[test]
+    fn test_register_new_device() {
+        let mut server = TestServer::start(config());
+        let (status, _, body) = server.send_batch(&batch("ios", true), Some(IOS_SECRET), &[]);
+
+        assert_eq!(StatusCode::OK, status);
+
+        let device_id = registered_device_id(&body);
+
+        assert_eq!(
+            Some(device_id.clone()),
+            server.ifa_store.get_id_for_ifa(APP_ID, &Some(IFA.to_string()), true)
+        );
+
+        let records = server.bus.records();
+
+        assert_eq!(1, records.len());
+        assert_eq!("sdk", records[0].topic);
+        assert_eq!(Some(format!("{}|{}", APP_ID, device_id).into_bytes()), records[0].key);
+
+        let proto = decode(&records[0]);
+
+        assert_eq!(Some(device_id), proto.header.recipient_id);
+        assert_eq!(2, proto.event.len());
+    }
+
+    #[test]
+    fn test_register_known_ifa() {
+        let mut server = TestServer::start(config());
+
+        let (_, _, first) = server.send_batch(&batch("ios", true), Some(IOS_SECRET), &[]);
+        let (_, _, second) = server.send_batch(&batch("ios", true), Some(IOS_SECRET), &[]);
+
+        assert_eq!(registered_device_id(&first), registered_device_id(&second));
+        assert_eq!(1, server.ifa_store.len());
+    }
+
+    #[test]
+    fn test_events_with_device_id() {
+        let mut server = TestServer::start(config());
+        let device_id = Ciphertext::encrypt(&Cleartext::from("8f7f5c07-5eb2-4695-870c-065d886cdc9e"));
+
+        let (status, _, _) = server.send_batch(
+            &batch("ios", false),
+            Some(IOS_SECRET),
+            &[("XORC-Device-Id", device_id.as_ref())],
+        );
+
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(0, server.ifa_store.len());
+
+        let proto = decode(&server.bus.records()[0]);
+
+        assert_eq!(
+            Some(String::from("8f7f5c07-5eb2-4695-870c-065d886cdc9e")),
+            proto.header.recipient_id
+        );
+    }
+
+    #[test]
+    fn test_events_with_erased_device_id() {
+        let mut server = TestServer::start(config());
+        let entity_id = "8f7f5c07-5eb2-4695-870c-065d886cdc9e";
+        let device_id = Ciphertext::encrypt(&Cleartext::from(entity_id));
+
+        server.ifa_store.erase_entity(uuid(APP_ID), uuid(entity_id)).unwrap();
+
+        let (status, _, _) = server.send_batch(
+            &batch("ios", false),
+            Some(IOS_SECRET),
+            &[("XORC-Device-Id", device_id.as_ref())],
+        );
+
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert_eq!(0, server.bus.published());
+    }
+
+    #[test]
+    fn test_register_with_erased_device_id() {
+        let mut server = TestServer::start(config());
+        let entity_id = "8f7f5c07-5eb2-4695-870c-065d886cdc9e";
+        let device_id = Ciphertext::encrypt(&Cleartext::from(entity_id));
+
+        server.ifa_store.erase_entity(uuid(APP_ID), uuid(entity_id)).unwrap();
+
+        let (status, _, body) = server.send_batch(
+            &batch("ios", true),
+            Some(IOS_SECRET),
+            &[("XORC-Device-Id", device_id.as_ref())],
+        );
+
+        assert_eq!(StatusCode::OK, status);
+        assert_ne!(entity_id, registered_device_id(&body));
+
+        let proto = decode(&server.bus.records()[0]);
+
+        assert_ne!(Some(String::from(entity_id)), proto.header.recipient_id);
+    }
+
+    #[test]
+    fn test_erasure_by_ifa() {
+        let mut server = TestServer::start(admin_config());
+        let (_, _, body) = server.send_batch(&batch("ios", true), Some(IOS_SECRET), &[]);
+        let device_id = registered_device_id(&body);
+
+        let request = json!({ "app_id": APP_ID, "ifa": IFA }).to_string().into_bytes();
+
+        let (status, _, body) = server.request(
+            Method::POST,
+            "/xray/admin/v1/erasure",
+            &[("Content-Type", "application/json"), ("XORC-Admin-Token", ADMIN_TOKEN)],
+            request,
+        );
+
+        assert_eq!(StatusCode::OK, status);
+
+        let response: Value = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(json!(1), response["deleted_mappings"]);
+        assert_eq!(json!([device_id]), response["entity_ids"]);
+        assert_eq!(json!(false), response["idfv_erased"]);
+
+        assert_eq!(0, server.ifa_store.len());
+        assert!(server.ifa_store.is_erased(APP_ID, &Some(IFA.to_string()), &None).unwrap());
+
+        let audit: Vec<Record> = server
+            .bus
+            .records()
+            .into_iter()
+            .filter(|record| record.topic == "audit")
+            .collect();
+
+        assert_eq!(1, audit.len());
+        assert_eq!(Some(APP_ID.as_bytes().to_vec()), audit[0].key);
+    }
+
+    #[test]
+    fn test_erasure_request_id() {
+        let mut server = TestServer::start(admin_config());
+        let request = json!({ "app_id": APP_ID, "ifa": IFA }).to_string().into_bytes();
+
+        let (status, headers, _) = server.request(
+            Method::POST,
+            "/xray/admin/v1/erasure",
+            &[("XORC-Admin-Token", ADMIN_TOKEN), ("X-Request-Id", "erasure-1")],
+            request,
+        );
+
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("erasure-1", headers.get(REQUEST_ID).unwrap());
+
+        let (status, headers, body) = server.request(
+            Method::POST,
+            "/xray/admin/v1/erasure",
+            &[("XORC-Admin-Token", ADMIN_TOKEN)],
+            b"kulli".to_vec(),
+        );
+
+        let request_id = headers.get(REQUEST_ID).unwrap().to_str().unwrap();
+        let body: Value = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert_eq!(36, request_id.len());
+        assert_eq!(json!(request_id), body["request_id"]);
+    }
+
+    #[test]
+    fn test_erasure_unauthorized() {
+        let mut server = TestServer::start(admin_config());
+        server.send_batch(&batch("ios", true), Some(IOS_SECRET), &[]);
+
+        let request = json!({ "app_id": APP_ID, "ifa": IFA }).to_string().into_bytes();
+
+        let (status, _, _) = server.request(
+            Method::POST,
+            "/xray/admin/v1/erasure",
+            &[("Content-Type", "application/json"), ("XORC-Admin-Token", "wrong")],
+            request,
+        );
+
+        assert_eq!(StatusCode::UNAUTHORIZED, status);
+        assert_eq!(1, server.ifa_store.len());
+        assert!(server.bus.records().iter().all(|record| record.topic != "audit"));
+    }
+
+    #[test]
+    fn test_invalid_signature() {
+        let mut server = TestServer::start(config());
+        let (status, _, _) = server.send_batch(&batch("ios", true), Some(WEB_SECRET), &[]);
+
+        assert_eq!(StatusCode::PRECONDITION_FAILED, status);
+        assert_eq!(0, server.bus.published());
+        assert_eq!(0, server.ifa_store.len());
+    }
+
+    #[test]
+    fn test_missing_signature() {
+        let mut server = TestServer::start(config());
+        let (status, _, _) = server.send_batch(&batch("ios", false), None, &[]);
+
+        assert_eq!(StatusCode::PRECONDITION_FAILED, status);
+        assert_eq!(0, server.bus.published());
+    }
+
+    #[test]
+    fn test_client_ip_from_trusted_proxy() {
+        let mut config = config();
+        config.client_ip = Some(ClientIpConfig { trusted_proxies: vec![String::from("127.0.0.1")] });
+
+        let mut server = TestServer::start(config);
+        let (status, _, _) = server.send_batch(
+            &batch("ios", false),
+            Some(IOS_SECRET),
+            &[("X-Forwarded-For", "6.6.6.6, 109.68.226.154")],
+        );
+
+        assert_eq!(StatusCode::OK, status);
+
+        let device = decode(&server.bus.records()[0]).device.unwrap();
+        assert_eq!(Some(String::from("DE")), device.country);
+    }
+
+    #[test]
+    fn test_client_ip_from_untrusted_peer() {
+        let mut config = config();
+        config.client_ip = Some(ClientIpConfig { trusted_proxies: vec![String::from("10.0.0.0/8")] });
+
+        let mut server = TestServer::start(config);
+        let (status, _, _) = server.send_batch(
+            &batch("ios", false),
+            Some(IOS_SECRET),
+            &[("X-Forwarded-For", "109.68.226.154"), ("X-Real-IP", "109.68.226.154")],
+        );
+
+        assert_eq!(StatusCode::OK, status);
+
+        let device = decode(&server.bus.records()[0]).device.unwrap();
+        assert_eq!(None, device.country);
+    }
+
+    #[test]
+    fn test_allow_empty_signature() {
+        let mut config = config();
+        config.gateway.allow_empty_signature = true;
+
+        let mut server = TestServer::start(config);
+        let (status, _, _) = server.send_batch(&batch("ios", false), None, &[]);
+
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(1, server.bus.published());
+    }
+
+    #[test]
+    fn test_unknown_app() {
+        let mut server = TestServer::start(config());
+        let mut batch = batch("ios", false);
+        batch["environment"]["app_id"] = json!("99999999-0000-0000-0000-000000000000");
+
+        let (status, _, _) = server.send_batch(&batch, Some(IOS_SECRET), &[]);
+
+        assert_eq!(StatusCode::FORBIDDEN, status);
+    }
+
+    #[test]
+    fn test_invalid_payload() {
+        let mut server = TestServer::start(config());
+
+        let (status, _, _) = server.request(
+            Method::POST,
+            "/xray/events/xorc/sdk/v1",
+            &[("Content-Type", "application/json")],
+            b"kulli".to_vec(),
+        );
+
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+    }
+
+    #[test]
+    fn test_request_id_from_client() {
+        let mut server = TestServer::start(config());
+
+        let (status, headers, _) = server.send_batch(
+            &batch("ios", false),
+            Some(IOS_SECRET),
+            &[("X-Request-Id", "client-request-1")],
+        );
+
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("client-request-1", headers.get(REQUEST_ID).unwrap().to_str().unwrap());
+
+        assert!(server.bus.records()[0].headers.contains(&(
+            String::from("request_id"),
+            String::from("client-request-1"),
+        )));
+    }
+
+    #[test]
+    fn test_generated_request_id_in_error() {
+        let mut server = TestServer::start(config());
+
+        let (status, headers, body) = server.request(
+            Method::POST,
+            "/xray/events/xorc/sdk/v1",
+            &[("Content-Type", "application/json")],
+            b"kulli".to_vec(),
+        );
+
+        let request_id = headers.get(REQUEST_ID).unwrap().to_str().unwrap();
+        let body: Value = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert_eq!(36, request_id.len());
+        assert_eq!(json!(request_id), body["request_id"]);
+        assert_eq!(json!("invalid_payload"), body["code"]);
+        assert_eq!(json!(false), body["retryable"]);
+    }
+
+    fn cors_config() -> Config {
+        let mut config = config();
+
+        config.origins.as_mut().unwrap().push(OriginConfig {
+            app_id: String::from(APP_ID),
+            allowed: vec![String::from("https://*.example.com")],
+            allowed_methods: None,
+            allowed_headers: None,
+        });
+
+        config
+    }
+
+    #[test]
+    fn test_cors_allowed_origin() {
+        let mut server = TestServer::start(cors_config());
+
+        let (status, headers, _) = server.send_batch(
+            &batch("web", false),
+            Some(WEB_SECRET),
+            &[("Origin", "https://shop.example.com")],
+        );
+
+        assert_eq!(StatusCode::OK, status);
+
+        assert_eq!(
+            "https://shop.example.com",
+            headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap().to_str().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cors_unknown_origin() {
+        let mut server = TestServer::start(cors_config());
+
+        let (status, headers, _) = server.send_batch(
+            &batch("web", false),
+            Some(WEB_SECRET),
+            &[("Origin", "https://evil.com")],
+        );
+
+        assert_eq!(StatusCode::FORBIDDEN, status);
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+        assert_eq!(0, server.bus.published());
+    }
+
+    #[test]
+    fn test_cors_preflight() {
+        let mut server = TestServer::start(cors_config());
+
+        let (status, headers, _) = server.request(
+            Method::OPTIONS,
+            &format!("/xray/events/xorc/sdk/v1?app_id={}", APP_ID),
+            &[("Origin", "https://shop.example.com")],
+            Vec::new(),
+        );
+
+        assert_eq!(StatusCode::OK, status);
+
+        assert_eq!(
+            "https://shop.example.com",
+            headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap().to_str().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_trace_context_propagation() {
+        let mut config = config();
+        config.client_ip = Some(ClientIpConfig { trusted_proxies: vec![String::from("127.0.0.1")] });
+
+        let mut server = TestServer::start(config);
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+        let (status, headers, _) = server.send_batch(
+            &batch("ios", false),
+            Some(IOS_SECRET),
+            &[("traceparent", traceparent)],
+        );
+
+        assert_eq!(StatusCode::OK, status);
+
+        let response = TraceContext::from_headers(&headers).unwrap();
+
+        assert_eq!(0x0af7651916cd43dd8448eb211c80319c, response.trace_id);
+        assert_ne!(0xb7ad6b7169203331, response.span_id);
+        assert!(response.sampled);
+
+        let record = &server.bus.records()[0];
+
+        assert!(record.headers.contains(&(
+            String::from("trace_id"),
+            String::from("0af7651916cd43dd8448eb211c80319c"),
+        )));
+    }
+
+    #[test]
+    fn test_untrusted_traceparent_not_sampled() {
+        let mut server = TestServer::start(config());
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+        let (_, headers, _) = server.send_batch(
+            &batch("ios", false),
+            Some(IOS_SECRET),
+            &[("traceparent", traceparent)],
+        );
+
+        let response = TraceContext::from_headers(&headers).unwrap();
+
+        assert_eq!(0x0af7651916cd43dd8448eb211c80319c, response.trace_id);
+        assert!(!response.sampled);
+    }
+
+    #[test]
+    fn test_new_trace_without_traceparent() {
+        let mut server = TestServer::start(config());
+        let (_, headers, _) = server.send_batch(&batch("ios", false), Some(IOS_SECRET), &[]);
+
+        let response = TraceContext::from_headers(&headers).unwrap();
+        let record = &server.bus.records()[0];
+
+        assert!(record.headers.contains(&(
+            String::from("trace_id"),
+            response.trace_id_hex(),
+        )));
+    }
+
+    #[test]
+    fn test_consent_in_bus_output() {
+        let mut server = TestServer::start(config());
+        let mut batch = batch("ios", true);
+        batch["consent"] = json!({ "advertising": false });
+
+        let (status, _, _) = server.send_batch(&batch, Some(IOS_SECRET), &[]);
+
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(0, server.ifa_store.len());
+
+        let record = &server.bus.records()[0];
+
+        assert!(record.headers.contains(&(
+            String::from("consent_advertising"),
+            String::from("denied"),
+        )));
+
+        assert!(decode(record).device.unwrap().ifa.is_none());
+    }
+
+    #[test]
+    fn test_dropped_without_consent() {
+        let mut server = TestServer::start(config());
+        let mut batch = batch("ios", false);
+        batch["consent"] = json!({ "analytics": false });
+
+        let (status, _, _) = server.send_batch(&batch, Some(IOS_SECRET), &[]);
+
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(0, server.bus.published());
+    }
+
+    #[test]
+    fn test_dropped_register_without_consent() {
+        let mut server = TestServer::start(config());
+        let mut batch = batch("ios", true);
+        batch["consent"] = json!({ "analytics": false });
+
+        let (status, _, body) = server.send_batch(&batch, Some(IOS_SECRET), &[]);
+
+        assert_eq!(StatusCode::OK, status);
+        assert!(!registered_device_id(&body).is_empty());
+        assert_eq!(0, server.ifa_store.len());
+        assert_eq!(0, server.bus.published());
+    }
+
+    #[test]
+    fn test_not_found() {
+        let mut server = TestServer::start(config());
+        let (status, _, _) = server.request(Method::GET, "/kulli", &[], Vec::new());
+
+        assert_eq!(StatusCode::NOT_FOUND, status);
+    }
+
+    /// Waits for the archive writer thread to write the entries.
+    fn archived_entries(directory: &str, count: usize) -> Vec<Entry> {
+        let path = format!("{}/requests.jsonl", directory);
+
+        for _ in 0..100 {
+            match Entry::read(&path) {
+                Ok(ref entries) if entries.len() >= count => break,
+                _ => thread::sleep(Duration::from_millis(20)),
+            }
+        }
+
+        Entry::read(&path).unwrap()
+    }
+
+    #[test]
+    fn test_archive() {
+        let directory = env::temp_dir()
+            .join(format!("xorc-archive-{}", Uuid::new_v4()))
+            .to_string_lossy()
+            .to_string();
+
+        let mut config = config();
+
+        config.archive = Some(ArchiveConfig {
+            directory: directory.clone(),
+            sample_rate: 1.0,
+            max_file_size: None,
+            max_files: None,
+            apps: Vec::new(),
+        });
+
+        let mut server = TestServer::start(config);
+        let batch = batch("ios", false);
+
+        let (status, _, _) = server.send_batch(&batch, Some(IOS_SECRET), &[]);
+        assert_eq!(StatusCode::OK, status);
+
+        let (status, _, _) = server.request(
+            Method::POST,
+            "/xray/events/xorc/sdk/v1",
+            &[("XORC-Api-Token", TOKEN)],
+            b"kulli".to_vec(),
+        );
+
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+
+        let entries = archived_entries(&directory, 2);
+
+        assert_eq!(2, entries.len());
+
+        assert_eq!(Some(String::from(APP_ID)), entries[0].app_id);
+        assert_eq!(Some(batch.to_string()), entries[0].body);
+        assert_eq!(200, entries[0].outcome.status);
+        assert_eq!(Some(&String::from("[REDACTED]")), entries[0].headers.get("xorc-signature"));
+        assert_eq!(Some(&String::from("[REDACTED]")), entries[0].headers.get("xorc-api-token"));
+
+        assert!(entries[1].app_id.is_none());
+        assert_eq!(400, entries[1].outcome.status);
+        assert!(entries[1].outcome.error.is_some());
+
+        fs::remove_dir_all(&directory).unwrap();
     }
 }
@@ -0,0 +1,318 @@
+use std::{
+    env,
+    fs,
+    net::IpAddr,
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crossbeam::sync::ArcCell;
+use maxminddb::{self, geoip2::{City, Country, Isp}};
+use config::{Config, GeoIpConfig};
+
+use ::{CONFIG, GLOG};
+
+/// The country database, if not set with the `GEOIP` environment variable.
+const DEFAULT_COUNTRY_DATABASE: &'static str = "./resources/GeoLite2-Country.mmdb";
+
+/// Seconds between checking the database files for changes, if not set in
+/// `[geoip]`.
+const DEFAULT_CHECK_INTERVAL: u64 = 60;
+
+/// Where the client address is, as far as the databases know.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Geo {
+    /// ISO 3166-1 country code.
+    pub country: Option<String>,
+    /// ISO 3166-2 code of the first subdivision, without the country.
+    pub region: Option<String>,
+    /// The English name of the city.
+    pub city: Option<String>,
+    pub postal_code: Option<String>,
+    /// The IANA time zone, such as `Europe/Berlin`.
+    pub time_zone: Option<String>,
+    /// The autonomous system number of the network.
+    pub asn: Option<u32>,
+    /// The organization of the autonomous system, usually the ISP.
+    pub as_organization: Option<String>,
+}
+
+impl Geo {
+    /// The fields besides the country as message headers. The device proto
+    /// has no fields for the region, city, postal code and ASN, so they're
+    /// only sent here. The time zone also fills the device's empty
+    /// `timezone`.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let fields = vec![
+            ("geo_region", self.region.clone()),
+            ("geo_city", self.city.clone()),
+            ("geo_postal_code", self.postal_code.clone()),
+            ("geo_time_zone", self.time_zone.clone()),
+            ("geo_asn", self.asn.map(|asn| asn.to_string())),
+            ("geo_as_organization", self.as_organization.clone()),
+        ];
+
+        fields
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect()
+    }
+}
+
+/// A MaxMind database file, opened again when it changes on disk.
+struct Database {
+    path: String,
+    reader: ArcCell<maxminddb::Reader>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl Database {
+    fn open(path: &str) -> Result<Database, String> {
+        let modified = Self::modified(path);
+
+        let reader = maxminddb::Reader::open(path)
+            .map_err(|e| format!("Couldn't open GeoIP database {}: {:?}", path, e))?;
+
+        Ok(Database {
+            path: String::from(path),
+            reader: ArcCell::new(Arc::new(reader)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    fn modified(path: &str) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    fn reader(&self) -> Arc<maxminddb::Reader> {
+        self.reader.get()
+    }
+
+    /// Opens the file again if its modification time changed. If the new
+    /// file can't be read, the old one stays in use and opening is tried
+    /// again on the next check.
+    fn reload_if_changed(&self) -> Result<bool, String> {
+        let modified = Self::modified(&self.path);
+        let mut current = self.modified.lock().unwrap();
+
+        if modified.is_none() || modified == *current {
+            return Ok(false)
+        }
+
+        let reader = maxminddb::Reader::open(&self.path)
+            .map_err(|e| format!("Couldn't reload GeoIP database {}: {:?}", self.path, e))?;
+
+        self.reader.set(Arc::new(reader));
+        *current = modified;
+
+        Ok(true)
+    }
+}
+
+/// The country, and optionally City and ASN, databases for locating the
+/// clients. The files are checked for changes periodically and swapped
+/// without a restart.
+pub struct GeoIp {
+    country: Arc<Database>,
+    city: Option<Arc<Database>>,
+    asn: Option<Arc<Database>>,
+}
+
+impl GeoIp {
+    pub fn new() -> GeoIp {
+        let country = env::var("GEOIP").unwrap_or_else(|_| String::from(DEFAULT_COUNTRY_DATABASE));
+        Self::from_config(&country, &CONFIG).unwrap()
+    }
+
+    pub fn from_config(country: &str, config: &Config) -> Result<GeoIp, String> {
+        let config: Option<&GeoIpConfig> = config.geoip.as_ref();
+
+        let open = |path: Option<&String>| -> Result<Option<Arc<Database>>, String> {
+            match path {
+                Some(path) => Ok(Some(Arc::new(Database::open(path)?))),
+                None => Ok(None),
+            }
+        };
+
+        let geoip = GeoIp {
+            country: Arc::new(Database::open(country)?),
+            city: open(config.and_then(|c| c.city_database.as_ref()))?,
+            asn: open(config.and_then(|c| c.asn_database.as_ref()))?,
+        };
+
+        let interval = config
+            .and_then(|c| c.check_interval)
+            .unwrap_or(DEFAULT_CHECK_INTERVAL);
+
+        let databases: Vec<Weak<Database>> = Some(&geoip.country)
+            .into_iter()
+            .chain(geoip.city.as_ref())
+            .chain(geoip.asn.as_ref())
+            .map(Arc::downgrade)
+            .collect();
+
+        thread::Builder::new()
+            .name(String::from("geoip-watcher"))
+            .spawn(move || Self::watch(databases, Duration::from_secs(interval)))
+            .map_err(|e| format!("Couldn't start the GeoIP watcher thread: {}", e))?;
+
+        Ok(geoip)
+    }
+
+    /// Checks the databases until the `GeoIp` holding them is dropped.
+    fn watch(databases: Vec<Weak<Database>>, interval: Duration) {
+        loop {
+            thread::sleep(interval);
+
+            let databases: Vec<Arc<Database>> = databases
+                .iter()
+                .filter_map(|database| database.upgrade())
+                .collect();
+
+            if databases.is_empty() {
+                return
+            }
+
+            for database in databases.iter() {
+                match database.reload_if_changed() {
+                    Ok(true) => info!(*GLOG, "Reloaded GeoIP database {}", database.path),
+                    Ok(false) => (),
+                    Err(e) => error!(*GLOG, "{}", e),
+                }
+            }
+        }
+    }
+
+    /// Everything the databases know of the address. The country is taken
+    /// from the City database if configured, otherwise from the country
+    /// database.
+    pub fn lookup(&self, ip: &IpAddr) -> Geo {
+        let mut geo = Geo::default();
+
+        if let Some(ref city) = self.city {
+            let result: Result<City, _> = city.reader().lookup(*ip);
+
+            if let Ok(city) = result {
+                geo.country = city.country.and_then(|c| c.iso_code);
+                geo.region = city.subdivisions
+                    .and_then(|s| s.into_iter().next())
+                    .and_then(|s| s.iso_code);
+                geo.city = city.city
+                    .and_then(|c| c.names)
+                    .and_then(|mut names| names.remove("en"));
+                geo.postal_code = city.postal.and_then(|p| p.code);
+                geo.time_zone = city.location.and_then(|l| l.time_zone);
+            }
+        }
+
+        if geo.country.is_none() {
+            let result: Result<Country, _> = self.country.reader().lookup(*ip);
+
+            geo.country = result
+                .ok()
+                .and_then(|res| res.country)
+                .and_then(|res| res.iso_code);
+        }
+
+        if let Some(ref asn) = self.asn {
+            let result: Result<Isp, _> = asn.reader().lookup(*ip);
+
+            if let Ok(isp) = result {
+                geo.asn = isp.autonomous_system_number;
+                geo.as_organization = isp.autonomous_system_organization.or(isp.isp);
+            }
+        }
+
+        geo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn copy_database() -> String {
+        let path = env::temp_dir().join(format!("xorc-geoip-{}.mmdb", Uuid::new_v4()));
+        fs::copy(DEFAULT_COUNTRY_DATABASE, &path).unwrap();
+
+        path.to_string_lossy().to_string()
+    }
+
+    fn geoip(path: &str) -> GeoIp {
+        GeoIp {
+            country: Arc::new(Database::open(path).unwrap()),
+            city: None,
+            asn: None,
+        }
+    }
+
+    #[test]
+    fn test_country_lookup() {
+        let geo = geoip(DEFAULT_COUNTRY_DATABASE).lookup(&"109.68.226.154".parse().unwrap());
+
+        assert_eq!(Some(String::from("DE")), geo.country);
+        assert_eq!(None, geo.city);
+        assert!(geo.headers().is_empty());
+    }
+
+    #[test]
+    fn test_headers() {
+        let geo = Geo {
+            country: Some(String::from("DE")),
+            city: Some(String::from("Berlin")),
+            asn: Some(3320),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vec![("geo_city", String::from("Berlin")), ("geo_asn", String::from("3320"))],
+            geo.headers()
+        );
+    }
+
+    #[test]
+    fn test_watcher_stops_when_dropped() {
+        let database = Arc::new(Database::open(DEFAULT_COUNTRY_DATABASE).unwrap());
+        let weak = vec![Arc::downgrade(&database)];
+
+        let watcher = thread::spawn(move || GeoIp::watch(weak, Duration::from_millis(1)));
+
+        drop(database);
+
+        watcher.join().unwrap();
+    }
+
+    #[test]
+    fn test_reload_when_changed() {
+        let path = copy_database();
+        let database = Database::open(&path).unwrap();
+
+        assert_eq!(Ok(false), database.reload_if_changed());
+
+        thread::sleep(Duration::from_millis(10));
+        fs::copy(DEFAULT_COUNTRY_DATABASE, &path).unwrap();
+
+        assert_eq!(Ok(true), database.reload_if_changed());
+        assert_eq!(Ok(false), database.reload_if_changed());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_broken_file_keeps_the_old_database() {
+        let path = copy_database();
+        let geoip = geoip(&path);
+
+        thread::sleep(Duration::from_millis(10));
+        fs::write(&path, b"kulli").unwrap();
+
+        assert!(geoip.country.reload_if_changed().is_err());
+
+        let geo = geoip.lookup(&"109.68.226.154".parse().unwrap());
+        assert_eq!(Some(String::from("DE")), geo.country);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,10 +1,18 @@
 use encryption::Cleartext;
+use ifa_store::IfaStore;
 use uuid::Uuid;
 use r2d2;
-use std::io;
+use base64;
+use ring::{hmac, digest};
+use std::{
+    env,
+    io,
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
 
-use ::CONFIG;
-use ::GLOG;
+use ::{CONFIG, GLOG};
 
 use cdrs::{
     authenticators::NoneAuthenticator,
@@ -24,8 +32,41 @@ use metrics::{
 type CassandraPool =
     r2d2::Pool<ClusterConnectionManager<NoneAuthenticator, TransportTcp>>;
 
+/// Seconds an entity is known not to be erased before reading its tombstone
+/// again. An entity erased through another instance keeps getting events
+/// here at most this long.
+const ENTITY_CHECK_TTL: u64 = 60;
+
+/// Entities remembered, the cache is emptied when full.
+const MAX_ENTITY_CHECKS: usize = 100_000;
+
+/// The minimum length of the `IFA_PEPPER` in bytes.
+pub const MIN_PEPPER_LENGTH: usize = 32;
+
+/// The pepper used in development if `IFA_PEPPER` is not set. Outside of
+/// development the configuration is not valid without one.
+const DEVELOPMENT_PEPPER: [u8; 32] = [
+    59, 201, 17, 142, 220, 91, 3, 77, 164, 36, 250, 109, 8, 191, 66, 132,
+    25, 240, 118, 87, 203, 12, 49, 170, 97, 225, 140, 31, 208, 63, 154, 5,
+];
+
+/// Decodes the `IFA_PEPPER`, url-safe base64 without padding of at least
+/// `MIN_PEPPER_LENGTH` bytes.
+pub fn decode_pepper(pepper: &str) -> Result<Vec<u8>, String> {
+    let pepper = base64::decode_config(pepper, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| String::from("Not url-safe base64 without padding."))?;
+
+    if pepper.len() < MIN_PEPPER_LENGTH {
+        return Err(format!("Must be at least {} bytes.", MIN_PEPPER_LENGTH))
+    }
+
+    Ok(pepper)
+}
+
 pub struct IfaMatching {
     pool: CassandraPool,
+    pepper: Option<hmac::SigningKey>,
+    entity_checks: RwLock<HashMap<(Uuid, Uuid), (bool, Instant)>>,
 }
 
 impl Default for IfaMatching {
@@ -35,6 +76,10 @@ impl Default for IfaMatching {
 }
 
 impl IfaMatching {
+    /// Connects to ScyllaDB for IFA to entity id matching. If
+    /// `hash_ifas` is set in the `[cassandra]` section, the IFAs are stored
+    /// as a HMAC-SHA256 of the raw value keyed with the `IFA_PEPPER`
+    /// environment variable.
     pub fn new() -> IfaMatching {
         let config = &CONFIG.cassandra;
 
@@ -57,9 +102,30 @@ impl IfaMatching {
             .build(manager)
             .unwrap();
 
-        IfaMatching { pool, }
+        let pepper = if config.hash_ifas {
+            info!(*GLOG, "Storing IFAs hashed in ScyllaDB.");
+            Some(Self::pepper())
+        } else {
+            None
+        };
+
+        IfaMatching { pool, pepper, entity_checks: RwLock::new(HashMap::new()) }
+    }
+
+    /// The `IFA_PEPPER` as a key. Its format and presence outside of
+    /// development are checked with the configuration.
+    fn pepper() -> hmac::SigningKey {
+        let pepper = match env::var("IFA_PEPPER") {
+            Ok(ref pepper) => decode_pepper(pepper).expect("IFA_PEPPER is validated with the configuration"),
+            Err(_) => DEVELOPMENT_PEPPER.to_vec(),
+        };
+
+        hmac::SigningKey::new(&digest::SHA256, &pepper)
     }
 
+    /// Finds the entity id for the given IFA. With hashing enabled, the
+    /// hashed key is tried first. If the IFA is still stored raw, the row is
+    /// rewritten with the hashed key and the raw row removed.
     pub fn get_id_for_ifa(
         &self,
         app_id: &str,
@@ -70,31 +136,27 @@ impl IfaMatching {
         let ifa = Self::parse_ifa(ifa, ifa_tracking_enabled)?;
         let app_id = Uuid::parse_str(app_id).ok()?;
 
-        let values = vec![
-            app_id.into(),
-            ifa.into(),
-        ];
-
-        let query = QueryBuilder::new(
-            format!(
-                "SELECT entity_id FROM {}.gw_known_ifas WHERE app_id=? AND ifa=?",
-                CONFIG.cassandra.keyspace
-            )
-        ).values(values).finalize();
+        let entity_id = match self.pepper {
+            Some(ref pepper) => {
+                let hashed_ifa = Self::hash_ifa(pepper, &ifa);
 
-        let frame = self.run_query(query)
-            .or_else(|e| {
-                error!(*GLOG, "Could not read IFA from ScyllaDB: {:?}", e);
-                SCYLLADB_REQUEST_COUNTER.with_label_values(&["get", "error"]).inc();
-                Err(e)
-            })
-            .ok()?;
+                match self.get_entity_id(app_id, hashed_ifa)? {
+                    Some(entity_id) => Some(entity_id),
+                    None => {
+                        let entity_id = self.get_entity_id(app_id, ifa)?;
 
-        let body = frame.get_body().ok()?;
-        let rows = body.into_rows()?;
+                        if let Some(entity_id) = entity_id {
+                            if let Err(e) = self.rehash_ifa(app_id, ifa, hashed_ifa, entity_id) {
+                                error!(*GLOG, "Could not rewrite a raw IFA to ScyllaDB: {:?}", e);
+                            }
+                        }
 
-        let entity_id: Option<Uuid> = rows.first()
-            .and_then(|row| row.r_by_name("entity_id").ok());
+                        entity_id
+                    }
+                }
+            },
+            None => self.get_entity_id(app_id, ifa)?,
+        };
 
         match entity_id {
             Some(entity_id) => {
@@ -140,20 +202,12 @@ impl IfaMatching {
                 )
             })?;
 
-        let values = vec![
-            app_id.into(),
-            ifa.into(),
-            entity_id.into()
-        ];
+        let ifa = match self.pepper {
+            Some(ref pepper) => Self::hash_ifa(pepper, &ifa),
+            None => ifa,
+        };
 
-        let query = QueryBuilder::new(
-            format!(
-                "INSERT INTO {}.gw_known_ifas (app_id, ifa, entity_id) VALUES (?, ?, ?)",
-                CONFIG.cassandra.keyspace
-            )
-        ).values(values).finalize();
-
-        match self.run_query(query) {
+        match self.run_query(Self::insert_query(app_id, ifa, entity_id)) {
             Ok(_) => {
                 SCYLLADB_REQUEST_COUNTER.with_label_values(&["put", "ok"]).inc();
                 Ok(())
@@ -171,6 +225,320 @@ impl IfaMatching {
         }
     }
 
+    /// True if the IFA or IDFV has been erased for the app through the
+    /// erasure API, meaning they should not be linked to an entity again.
+    /// All the keys are read with one query.
+    pub fn is_erased(
+        &self,
+        app_id: &str,
+        ifa: &Option<String>,
+        idfv: &Option<String>,
+    ) -> Result<bool, io::Error>
+    {
+        let app_id = match Uuid::parse_str(app_id) {
+            Ok(app_id) => app_id,
+            Err(_) => return Ok(false),
+        };
+
+        let mut identifiers: Vec<String> = ifa.as_ref()
+            .and_then(|ifa| Uuid::parse_str(ifa).ok())
+            .map(|ifa| {
+                self.storage_keys(ifa).iter().map(|k| k.hyphenated().to_string()).collect()
+            })
+            .unwrap_or_else(Vec::new);
+
+        if let Some(ref idfv) = idfv {
+            identifiers.push(self.identifier_key(idfv));
+        }
+
+        if identifiers.is_empty() {
+            return Ok(false)
+        }
+
+        self.has_tombstone(app_id, &["ifa", "idfv"], identifiers)
+    }
+
+    /// True if the entity has been erased for the app. The answer is cached
+    /// for `ENTITY_CHECK_TTL` seconds, an erased entity for good.
+    pub fn is_entity_erased(&self, app_id: &str, entity_id: &str) -> Result<bool, io::Error> {
+        let key = match (Uuid::parse_str(app_id), Uuid::parse_str(entity_id)) {
+            (Ok(app_id), Ok(entity_id)) => (app_id, entity_id),
+            _ => return Ok(false),
+        };
+
+        if let Some(&(erased, checked_at)) = self.entity_checks.read().unwrap().get(&key) {
+            if erased || checked_at.elapsed() < Duration::from_secs(ENTITY_CHECK_TTL) {
+                return Ok(erased)
+            }
+        }
+
+        let erased = self.has_tombstone(key.0, &["device_id"], vec![key.1.hyphenated().to_string()])?;
+        self.remember_entity(key, erased);
+
+        Ok(erased)
+    }
+
+    fn remember_entity(&self, key: (Uuid, Uuid), erased: bool) {
+        let mut checks = self.entity_checks.write().unwrap();
+
+        if checks.len() >= MAX_ENTITY_CHECKS {
+            checks.clear();
+        }
+
+        checks.insert(key, (erased, Instant::now()));
+    }
+
+    /// Removes the IFA mapping, both the raw and the hashed row, and stores a
+    /// tombstone for it. Returns the entity id the IFA was linked to.
+    pub fn erase_ifa(&self, app_id: Uuid, ifa: Uuid) -> Result<Option<Uuid>, io::Error> {
+        let mut entity_id = None;
+
+        for key in self.storage_keys(ifa) {
+            if let Some(found) = self.get_entity_id(app_id, key).and_then(|id| id) {
+                entity_id = Some(found);
+            }
+
+            self.delete_key(app_id, key)?;
+        }
+
+        let key = self.storage_keys(ifa)[0];
+        self.put_tombstone(app_id, "ifa", key.hyphenated().to_string())?;
+
+        Ok(entity_id)
+    }
+
+    /// Stores a tombstone for the IDFV. IDFVs are not used for matching, so
+    /// there is no mapping to remove.
+    pub fn erase_idfv(&self, app_id: Uuid, idfv: &str) -> Result<(), io::Error> {
+        let key = self.identifier_key(idfv);
+        self.put_tombstone(app_id, "idfv", key)
+    }
+
+    /// Removes all the IFA mappings pointing to the entity and stores
+    /// tombstones for the entity and its IFAs. Returns the number of removed
+    /// mappings.
+    pub fn erase_entity(&self, app_id: Uuid, entity_id: Uuid) -> Result<usize, io::Error> {
+        let values = vec![
+            app_id.into(),
+            entity_id.into(),
+        ];
+
+        let query = QueryBuilder::new(
+            format!(
+                "SELECT ifa FROM {}.gw_known_ifas WHERE app_id=? AND entity_id=?",
+                CONFIG.cassandra.keyspace
+            )
+        ).values(values).finalize();
+
+        let keys: Vec<Uuid> = self.run_query(query)?
+            .get_body()
+            .ok()
+            .and_then(|body| body.into_rows())
+            .map(|rows| {
+                rows.iter().filter_map(|row| row.r_by_name("ifa").ok()).collect()
+            })
+            .unwrap_or_else(Vec::new);
+
+        for key in keys.iter() {
+            self.delete_key(app_id, *key)?;
+            self.put_tombstone(app_id, "ifa", key.hyphenated().to_string())?;
+        }
+
+        self.put_tombstone(app_id, "device_id", entity_id.hyphenated().to_string())?;
+        self.remember_entity((app_id, entity_id), true);
+
+        Ok(keys.len())
+    }
+
+    /// The keys the IFA might be stored with, the preferred key first.
+    fn storage_keys(&self, ifa: Uuid) -> Vec<Uuid> {
+        match self.pepper {
+            Some(ref pepper) => vec![Self::hash_ifa(pepper, &ifa), ifa],
+            None => vec![ifa],
+        }
+    }
+
+    fn identifier_key(&self, identifier: &str) -> String {
+        match self.pepper {
+            Some(ref pepper) => {
+                let signature = hmac::sign(pepper, identifier.as_bytes());
+                base64::encode(signature.as_ref())
+            },
+            None => identifier.to_string(),
+        }
+    }
+
+    fn delete_key(&self, app_id: Uuid, ifa: Uuid) -> Result<(), io::Error> {
+        let values = vec![
+            app_id.into(),
+            ifa.into(),
+        ];
+
+        let query = QueryBuilder::new(
+            format!(
+                "DELETE FROM {}.gw_known_ifas WHERE app_id=? AND ifa=?",
+                CONFIG.cassandra.keyspace
+            )
+        ).values(values).finalize();
+
+        self.run_query(query)?;
+        SCYLLADB_REQUEST_COUNTER.with_label_values(&["delete", "ok"]).inc();
+
+        Ok(())
+    }
+
+    fn put_tombstone(
+        &self,
+        app_id: Uuid,
+        kind: &'static str,
+        identifier: String,
+    ) -> Result<(), io::Error>
+    {
+        let values = vec![
+            app_id.into(),
+            kind.to_string().into(),
+            identifier.into(),
+        ];
+
+        let query = QueryBuilder::new(
+            format!(
+                "INSERT INTO {}.gw_erased_identities (app_id, kind, identifier, erased_at) VALUES (?, ?, ?, toTimestamp(now()))",
+                CONFIG.cassandra.keyspace
+            )
+        ).values(values).finalize();
+
+        self.run_query(query)?;
+        SCYLLADB_REQUEST_COUNTER.with_label_values(&["tombstone", "ok"]).inc();
+
+        Ok(())
+    }
+
+    /// True if any of the identifiers has a tombstone of any of the kinds.
+    /// A failed read is an error, never `false`: the caller must not link
+    /// identifiers it couldn't check.
+    fn has_tombstone(
+        &self,
+        app_id: Uuid,
+        kinds: &[&'static str],
+        identifiers: Vec<String>,
+    ) -> Result<bool, io::Error>
+    {
+        let placeholders = |count: usize| vec!["?"; count].join(", ");
+
+        let query = format!(
+            "SELECT kind FROM {}.gw_erased_identities WHERE app_id=? AND kind IN ({}) AND identifier IN ({})",
+            CONFIG.cassandra.keyspace,
+            placeholders(kinds.len()),
+            placeholders(identifiers.len()),
+        );
+
+        let mut values = vec![app_id.into()];
+
+        for kind in kinds {
+            values.push(kind.to_string().into());
+        }
+
+        for identifier in identifiers {
+            values.push(identifier.into());
+        }
+
+        let query = QueryBuilder::new(query).values(values).finalize();
+
+        match self.run_query(query) {
+            Ok(frame) => {
+                let found = frame.get_body().ok()
+                    .and_then(|body| body.into_rows())
+                    .map(|rows| !rows.is_empty())
+                    .unwrap_or(false);
+
+                Ok(found)
+            },
+            Err(e) => {
+                error!(*GLOG, "Could not read tombstones from ScyllaDB: {:?}", e);
+                SCYLLADB_REQUEST_COUNTER.with_label_values(&["tombstone", "error"]).inc();
+                Err(e)
+            }
+        }
+    }
+
+    /// `None` if the query failed, `Some(None)` if the IFA was not found.
+    fn get_entity_id(&self, app_id: Uuid, ifa: Uuid) -> Option<Option<Uuid>> {
+        let values = vec![
+            app_id.into(),
+            ifa.into(),
+        ];
+
+        let query = QueryBuilder::new(
+            format!(
+                "SELECT entity_id FROM {}.gw_known_ifas WHERE app_id=? AND ifa=?",
+                CONFIG.cassandra.keyspace
+            )
+        ).values(values).finalize();
+
+        let frame = self.run_query(query)
+            .or_else(|e| {
+                error!(*GLOG, "Could not read IFA from ScyllaDB: {:?}", e);
+                SCYLLADB_REQUEST_COUNTER.with_label_values(&["get", "error"]).inc();
+                Err(e)
+            })
+            .ok()?;
+
+        let body = frame.get_body().ok()?;
+        let rows = body.into_rows()?;
+
+        Some(rows.first().and_then(|row| row.r_by_name("entity_id").ok()))
+    }
+
+    fn rehash_ifa(
+        &self,
+        app_id: Uuid,
+        raw_ifa: Uuid,
+        hashed_ifa: Uuid,
+        entity_id: Uuid,
+    ) -> Result<(), io::Error>
+    {
+        self.run_query(Self::insert_query(app_id, hashed_ifa, entity_id))?;
+
+        let values = vec![
+            app_id.into(),
+            raw_ifa.into(),
+        ];
+
+        let query = QueryBuilder::new(
+            format!(
+                "DELETE FROM {}.gw_known_ifas WHERE app_id=? AND ifa=?",
+                CONFIG.cassandra.keyspace
+            )
+        ).values(values).finalize();
+
+        self.run_query(query)?;
+        SCYLLADB_REQUEST_COUNTER.with_label_values(&["rehash", "ok"]).inc();
+
+        Ok(())
+    }
+
+    fn insert_query(app_id: Uuid, ifa: Uuid, entity_id: Uuid) -> Query {
+        let values = vec![
+            app_id.into(),
+            ifa.into(),
+            entity_id.into()
+        ];
+
+        QueryBuilder::new(
+            format!(
+                "INSERT INTO {}.gw_known_ifas (app_id, ifa, entity_id) VALUES (?, ?, ?)",
+                CONFIG.cassandra.keyspace
+            )
+        ).values(values).finalize()
+    }
+
+    /// The first 16 bytes of HMAC-SHA256, so the hashed key fits to the same
+    /// `uuid` column as the raw IFAs.
+    fn hash_ifa(pepper: &hmac::SigningKey, ifa: &Uuid) -> Uuid {
+        let signature = hmac::sign(pepper, ifa.as_bytes());
+        Uuid::from_bytes(&signature.as_ref()[0..16]).unwrap()
+    }
+
     fn run_query(&self, query: Query) -> Result<Frame, io::Error> {
         let conn = self.pool.get()
             .map_err(|e| {
@@ -192,7 +560,7 @@ impl IfaMatching {
         })
     }
 
-    fn parse_ifa(ifa: &Option<String>, ifa_tracking_enabled: bool) -> Option<Uuid> {
+    pub fn parse_ifa(ifa: &Option<String>, ifa_tracking_enabled: bool) -> Option<Uuid> {
         ifa.as_ref()
             .and_then(|ref ifa| Uuid::parse_str(ifa).ok())
             .and_then(|ifa| {
@@ -203,5 +571,101 @@ impl IfaMatching {
                 }
             })
     }
+}
+
+impl IfaStore for IfaMatching {
+    fn is_erased(
+        &self,
+        app_id: &str,
+        ifa: &Option<String>,
+        idfv: &Option<String>,
+    ) -> Result<bool, io::Error>
+    {
+        IfaMatching::is_erased(self, app_id, ifa, idfv)
+    }
+
+    fn is_entity_erased(&self, app_id: &str, entity_id: &str) -> Result<bool, io::Error> {
+        IfaMatching::is_entity_erased(self, app_id, entity_id)
+    }
+
+    fn erase_ifa(&self, app_id: Uuid, ifa: Uuid) -> Result<Option<Uuid>, io::Error> {
+        IfaMatching::erase_ifa(self, app_id, ifa)
+    }
+
+    fn erase_idfv(&self, app_id: Uuid, idfv: &str) -> Result<(), io::Error> {
+        IfaMatching::erase_idfv(self, app_id, idfv)
+    }
+
+    fn erase_entity(&self, app_id: Uuid, entity_id: Uuid) -> Result<usize, io::Error> {
+        IfaMatching::erase_entity(self, app_id, entity_id)
+    }
 
+    fn get_id_for_ifa(
+        &self,
+        app_id: &str,
+        ifa: &Option<String>,
+        ifa_tracking_enabled: bool,
+    ) -> Option<String>
+    {
+        IfaMatching::get_id_for_ifa(self, app_id, ifa, ifa_tracking_enabled)
+    }
+
+    fn put_id_for_ifa(
+        &self,
+        app_id: &str,
+        device_id: &Cleartext,
+        ifa: &Option<String>,
+        ifa_tracking_enabled: bool,
+    ) -> Result<(), io::Error>
+    {
+        IfaMatching::put_id_for_ifa(self, app_id, device_id, ifa, ifa_tracking_enabled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IFA: &'static str = "f3b7e2a0-3e5c-4c8e-9d3a-5b1f1c6e9a42";
+
+    #[test]
+    fn test_decode_pepper() {
+        let pepper = base64::encode_config(&DEVELOPMENT_PEPPER, base64::URL_SAFE_NO_PAD);
+        let short = base64::encode_config(&DEVELOPMENT_PEPPER[..16], base64::URL_SAFE_NO_PAD);
+
+        assert_eq!(Ok(DEVELOPMENT_PEPPER.to_vec()), decode_pepper(&pepper));
+        assert!(decode_pepper(&short).is_err());
+        assert!(decode_pepper("!!!").is_err());
+    }
+
+    #[test]
+    fn test_hashed_ifa_is_stable() {
+        let pepper = hmac::SigningKey::new(&digest::SHA256, b"pepper");
+        let ifa = Uuid::parse_str(IFA).unwrap();
+
+        assert_eq!(
+            IfaMatching::hash_ifa(&pepper, &ifa),
+            IfaMatching::hash_ifa(&pepper, &ifa),
+        );
+    }
+
+    #[test]
+    fn test_hashed_ifa_differs_from_raw() {
+        let pepper = hmac::SigningKey::new(&digest::SHA256, b"pepper");
+        let ifa = Uuid::parse_str(IFA).unwrap();
+
+        assert_ne!(ifa, IfaMatching::hash_ifa(&pepper, &ifa));
+    }
+
+    #[test]
+    fn test_hashed_ifa_depends_on_pepper() {
+        let pepper1 = hmac::SigningKey::new(&digest::SHA256, b"pepper");
+        let pepper2 = hmac::SigningKey::new(&digest::SHA256, b"salt");
+        let ifa = Uuid::parse_str(IFA).unwrap();
+
+        assert_ne!(
+            IfaMatching::hash_ifa(&pepper1, &ifa),
+            IfaMatching::hash_ifa(&pepper2, &ifa),
+        );
+    }
 }
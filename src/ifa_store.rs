@@ -0,0 +1,230 @@
+use std::{
+    io,
+    sync::RwLock,
+    collections::{HashMap, HashSet},
+};
+
+use uuid::Uuid;
+use encryption::Cleartext;
+use ifa_matching::IfaMatching;
+
+/// Storage for matching the IFA of a device to an entity id, and erasing
+/// the mappings. Implemented by `IfaMatching` with ScyllaDB, and in memory
+/// for load tests.
+pub trait IfaStore: Send + Sync {
+    /// True if the identifiers were erased and must not be linked again. An
+    /// error means the erasures couldn't be checked.
+    fn is_erased(
+        &self,
+        app_id: &str,
+        ifa: &Option<String>,
+        idfv: &Option<String>,
+    ) -> Result<bool, io::Error>;
+
+    /// True if the entity was erased and must not get events again.
+    fn is_entity_erased(&self, app_id: &str, entity_id: &str) -> Result<bool, io::Error>;
+
+    fn get_id_for_ifa(
+        &self,
+        app_id: &str,
+        ifa: &Option<String>,
+        ifa_tracking_enabled: bool,
+    ) -> Option<String>;
+
+    fn put_id_for_ifa(
+        &self,
+        app_id: &str,
+        device_id: &Cleartext,
+        ifa: &Option<String>,
+        ifa_tracking_enabled: bool,
+    ) -> Result<(), io::Error>;
+
+    /// Removes the mapping of the IFA and marks it erased. Returns the
+    /// entity it pointed to.
+    fn erase_ifa(&self, app_id: Uuid, ifa: Uuid) -> Result<Option<Uuid>, io::Error>;
+
+    /// Marks the IDFV erased.
+    fn erase_idfv(&self, app_id: Uuid, idfv: &str) -> Result<(), io::Error>;
+
+    /// Removes the mappings of the entity and marks it and its IFAs erased.
+    /// Returns the number of removed mappings.
+    fn erase_entity(&self, app_id: Uuid, entity_id: Uuid) -> Result<usize, io::Error>;
+}
+
+/// Keeps the IFA mappings in a map, following the same rules as
+/// `IfaMatching`.
+#[derive(Default)]
+pub struct MemoryIfaStore {
+    mappings: RwLock<HashMap<(String, String), String>>,
+    erased: RwLock<HashSet<(String, String)>>,
+}
+
+impl MemoryIfaStore {
+    pub fn new() -> MemoryIfaStore {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.mappings.read().unwrap().len()
+    }
+
+    fn key(app_id: &str, ifa: &Option<String>, ifa_tracking_enabled: bool) -> Option<(String, String)> {
+        IfaMatching::parse_ifa(ifa, ifa_tracking_enabled)
+            .map(|ifa| (app_id.to_string(), ifa.hyphenated().to_string()))
+    }
+}
+
+impl IfaStore for MemoryIfaStore {
+    fn is_erased(
+        &self,
+        app_id: &str,
+        ifa: &Option<String>,
+        idfv: &Option<String>,
+    ) -> Result<bool, io::Error>
+    {
+        let erased = self.erased.read().unwrap();
+
+        let found = [ifa, idfv].iter().any(|identifier| {
+            identifier
+                .as_ref()
+                .map(|identifier| erased.contains(&(app_id.to_string(), identifier.clone())))
+                .unwrap_or(false)
+        });
+
+        Ok(found)
+    }
+
+    fn is_entity_erased(&self, app_id: &str, entity_id: &str) -> Result<bool, io::Error> {
+        let key = (app_id.to_string(), entity_id.to_string());
+        Ok(self.erased.read().unwrap().contains(&key))
+    }
+
+    fn get_id_for_ifa(
+        &self,
+        app_id: &str,
+        ifa: &Option<String>,
+        ifa_tracking_enabled: bool,
+    ) -> Option<String>
+    {
+        let key = Self::key(app_id, ifa, ifa_tracking_enabled)?;
+        self.mappings.read().unwrap().get(&key).cloned()
+    }
+
+    fn put_id_for_ifa(
+        &self,
+        app_id: &str,
+        device_id: &Cleartext,
+        ifa: &Option<String>,
+        ifa_tracking_enabled: bool,
+    ) -> Result<(), io::Error>
+    {
+        let key = Self::key(app_id, ifa, ifa_tracking_enabled)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "IFA storage is not allowed or ifa was faulty"
+                )
+            })?;
+
+        self.mappings.write().unwrap().insert(key, device_id.as_ref().to_string());
+
+        Ok(())
+    }
+
+    fn erase_ifa(&self, app_id: Uuid, ifa: Uuid) -> Result<Option<Uuid>, io::Error> {
+        let key = (app_id.hyphenated().to_string(), ifa.hyphenated().to_string());
+        let entity_id = self.mappings.write().unwrap().remove(&key);
+
+        self.erased.write().unwrap().insert(key);
+
+        Ok(entity_id.and_then(|entity_id| Uuid::parse_str(&entity_id).ok()))
+    }
+
+    fn erase_idfv(&self, app_id: Uuid, idfv: &str) -> Result<(), io::Error> {
+        let key = (app_id.hyphenated().to_string(), idfv.to_string());
+        self.erased.write().unwrap().insert(key);
+
+        Ok(())
+    }
+
+    fn erase_entity(&self, app_id: Uuid, entity_id: Uuid) -> Result<usize, io::Error> {
+        let app_id = app_id.hyphenated().to_string();
+        let entity_id = entity_id.hyphenated().to_string();
+
+        let mut mappings = self.mappings.write().unwrap();
+        let mut erased = self.erased.write().unwrap();
+
+        let keys: Vec<(String, String)> = mappings
+            .iter()
+            .filter(|((app, _), entity)| *app == app_id && **entity == entity_id)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in keys.iter() {
+            mappings.remove(key);
+            erased.insert(key.clone());
+        }
+
+        erased.insert((app_id, entity_id));
+
+        Ok(keys.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APP_ID: &'static str = "22222222-0000-0000-0000-000000000000";
+    const IFA: &'static str = "f3b7e2a0-3e5c-4c8e-9d3a-5b1f1c6e9a42";
+    const ENTITY_ID: &'static str = "8f7f5c07-5eb2-4695-870c-065d886cdc9e";
+
+    fn ifa() -> Option<String> {
+        Some(IFA.to_string())
+    }
+
+    fn uuid(value: &str) -> Uuid {
+        Uuid::parse_str(value).unwrap()
+    }
+
+    #[test]
+    fn test_put_and_get() {
+        let store = MemoryIfaStore::new();
+
+        store.put_id_for_ifa(APP_ID, &Cleartext::from(ENTITY_ID), &ifa(), true).unwrap();
+
+        assert_eq!(Some(ENTITY_ID.to_string()), store.get_id_for_ifa(APP_ID, &ifa(), true));
+        assert_eq!(None, store.get_id_for_ifa("kulli", &ifa(), true));
+    }
+
+    #[test]
+    fn test_tracking_disabled() {
+        let store = MemoryIfaStore::new();
+
+        assert!(store.put_id_for_ifa(APP_ID, &Cleartext::from(ENTITY_ID), &ifa(), false).is_err());
+        assert_eq!(0, store.len());
+    }
+
+    #[test]
+    fn test_erase() {
+        let store = MemoryIfaStore::new();
+
+        store.put_id_for_ifa(APP_ID, &Cleartext::from(ENTITY_ID), &ifa(), true).unwrap();
+        assert_eq!(Some(uuid(ENTITY_ID)), store.erase_ifa(uuid(APP_ID), uuid(IFA)).unwrap());
+
+        assert!(store.is_erased(APP_ID, &ifa(), &None).unwrap());
+        assert_eq!(None, store.get_id_for_ifa(APP_ID, &ifa(), true));
+    }
+
+    #[test]
+    fn test_erase_entity() {
+        let store = MemoryIfaStore::new();
+
+        store.put_id_for_ifa(APP_ID, &Cleartext::from(ENTITY_ID), &ifa(), true).unwrap();
+        assert_eq!(1, store.erase_entity(uuid(APP_ID), uuid(ENTITY_ID)).unwrap());
+
+        assert!(store.is_entity_erased(APP_ID, ENTITY_ID).unwrap());
+        assert!(store.is_erased(APP_ID, &ifa(), &None).unwrap());
+        assert_eq!(None, store.get_id_for_ifa(APP_ID, &ifa(), true));
+    }
+}
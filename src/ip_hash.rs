@@ -0,0 +1,270 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64;
+use blake2::{Blake2b, Digest};
+use crossbeam::sync::ArcCell;
+use hex;
+use ring::{digest, hmac};
+use access_log::truncate_ip;
+use config::{Config, IpHashConfig};
+
+use ::{CONFIG, RUST_ENV};
+
+/// Seconds a salt is used for, if not set in `[ip_hash.<environment>]`.
+pub const DEFAULT_ROTATION: u64 = 86_400;
+
+/// The shortest secret accepted for the keyed hash, in bytes.
+pub const MIN_SECRET_LENGTH: usize = 32;
+
+/// How the client address is pseudonymized.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IpHashMode {
+    /// A BLAKE2b digest without a key. Every IPv4 address can be hashed in
+    /// minutes, so it's not allowed outside of development.
+    Digest,
+    /// HMAC-SHA256 with a salt derived from the secret, changing every
+    /// rotation period.
+    Keyed,
+    /// The /24 network of an IPv4 and the /48 network of an IPv6 address,
+    /// without a hash.
+    Truncate,
+}
+
+/// The pseudonymized client address.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HashedIp {
+    Digest(String),
+    /// The hash with the salt epoch: the seconds since the Unix epoch
+    /// divided by the rotation period.
+    Keyed { hash: String, epoch: u64 },
+    Truncated(IpAddr),
+}
+
+impl HashedIp {
+    /// The value of the `ip_hashed_blake2` device field.
+    pub fn hash(&self) -> Option<String> {
+        match self {
+            HashedIp::Digest(hash) => Some(hash.clone()),
+            HashedIp::Keyed { hash, .. } => Some(hash.clone()),
+            HashedIp::Truncated(_) => None,
+        }
+    }
+
+    /// How the hash was made, as message headers.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        match self {
+            HashedIp::Digest(_) => vec![("ip_hash_mode", String::from("digest"))],
+            HashedIp::Keyed { epoch, .. } => vec![
+                ("ip_hash_mode", String::from("keyed")),
+                ("ip_hash_epoch", epoch.to_string()),
+            ],
+            HashedIp::Truncated(network) => vec![
+                ("ip_hash_mode", String::from("truncate")),
+                ("ip_network", network.to_string()),
+            ],
+        }
+    }
+}
+
+/// The pseudonymization of one environment.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpHashPolicy {
+    Digest,
+    Keyed { secret: Vec<u8>, rotation: u64 },
+    Truncate,
+}
+
+impl IpHashPolicy {
+    /// The policy from the `[ip_hash.<environment>]` section, the unkeyed
+    /// digest if not set.
+    pub fn new(
+        environment: &str,
+        config: Option<&HashMap<String, IpHashConfig>>,
+    ) -> IpHashPolicy
+    {
+        let config = match config.and_then(|environments| environments.get(environment)) {
+            Some(config) => config,
+            None => return IpHashPolicy::Digest,
+        };
+
+        match config.mode {
+            IpHashMode::Digest => IpHashPolicy::Digest,
+            IpHashMode::Keyed => IpHashPolicy::Keyed {
+                secret: config.secret
+                    .as_ref()
+                    .and_then(|secret| hex::decode(secret).ok())
+                    .expect("Invalid ip_hash secret"),
+                rotation: config.rotation.unwrap_or(DEFAULT_ROTATION),
+            },
+            IpHashMode::Truncate => IpHashPolicy::Truncate,
+        }
+    }
+
+    pub fn hash(&self, ip: &IpAddr, now: SystemTime) -> HashedIp {
+        match self {
+            IpHashPolicy::Digest => {
+                let mut hasher = Blake2b::new();
+                hasher.input(ip.to_string().as_bytes());
+
+                HashedIp::Digest(base64::encode(hasher.result().as_slice()))
+            },
+            IpHashPolicy::Keyed { secret, rotation } => {
+                let seconds = now.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                let epoch = seconds / rotation;
+
+                // The salt of the epoch is derived from the secret, so every
+                // instance has the same salt without coordinating, and an old
+                // salt can't be recovered from the hashes.
+                let master = hmac::SigningKey::new(&digest::SHA256, secret);
+                let salt = hmac::sign(&master, format!("ip-salt:{}", epoch).as_bytes());
+
+                let key = hmac::SigningKey::new(&digest::SHA256, salt.as_ref());
+                let hash = hmac::sign(&key, ip.to_string().as_bytes());
+
+                HashedIp::Keyed { hash: base64::encode(hash.as_ref()), epoch }
+            },
+            IpHashPolicy::Truncate => HashedIp::Truncated(truncate_ip(ip)),
+        }
+    }
+}
+
+/// The IP hash policy of the running environment, swapped when the
+/// configuration is reloaded.
+pub struct IpHashing {
+    policy: ArcCell<IpHashPolicy>,
+}
+
+impl IpHashing {
+    pub fn new() -> IpHashing {
+        Self::from_config(&CONFIG)
+    }
+
+    pub fn from_config(config: &Config) -> IpHashing {
+        Self::with_policy(Self::policy_for(config))
+    }
+
+    pub fn with_policy(policy: IpHashPolicy) -> IpHashing {
+        IpHashing { policy: ArcCell::new(Arc::new(policy)) }
+    }
+
+    pub fn reload(&self, config: &Config) {
+        self.policy.set(Arc::new(Self::policy_for(config)));
+    }
+
+    pub fn hash(&self, ip: &IpAddr) -> HashedIp {
+        self.policy.get().hash(ip, SystemTime::now())
+    }
+
+    fn policy_for(config: &Config) -> IpHashPolicy {
+        IpHashPolicy::new(&RUST_ENV, config.ip_hash.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const SECRET: &'static str = "8d9d3c1bb24bf4c61e2f0a3b7e6f5a4d3c2b1a0f9e8d7c6b5a4f3e2d1c0b9a8f";
+
+    fn keyed(secret: &str) -> IpHashPolicy {
+        IpHashPolicy::Keyed { secret: hex::decode(secret).unwrap(), rotation: DEFAULT_ROTATION }
+    }
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    fn ip() -> IpAddr {
+        "109.68.226.154".parse().unwrap()
+    }
+
+    #[test]
+    fn test_digest() {
+        let hashed = IpHashPolicy::Digest.hash(&ip(), SystemTime::now());
+
+        assert_eq!(
+            Some(String::from("KOx83wHFu0JCTyitEEfU0+J0GWN5OxtXgMeIzDUinonr8ya0IY5VyYtrbDu8tRlBSo/a1T70lQ3uYcnSRYiR8w==")),
+            hashed.hash()
+        );
+    }
+
+    #[test]
+    fn test_keyed_within_epoch() {
+        let policy = keyed(SECRET);
+
+        let first = policy.hash(&ip(), at(DEFAULT_ROTATION * 100));
+        let second = policy.hash(&ip(), at(DEFAULT_ROTATION * 100 + 3600));
+
+        assert_eq!(first, second);
+        assert_eq!(
+            vec![("ip_hash_mode", String::from("keyed")), ("ip_hash_epoch", String::from("100"))],
+            first.headers()
+        );
+    }
+
+    #[test]
+    fn test_keyed_rotates() {
+        let policy = keyed(SECRET);
+
+        let today = policy.hash(&ip(), at(DEFAULT_ROTATION * 100));
+        let tomorrow = policy.hash(&ip(), at(DEFAULT_ROTATION * 101));
+
+        assert_ne!(today.hash(), tomorrow.hash());
+    }
+
+    #[test]
+    fn test_keyed_depends_on_the_secret() {
+        let other = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+
+        let hashed = keyed(SECRET).hash(&ip(), at(0));
+        let with_other = keyed(other).hash(&ip(), at(0));
+        let digest = IpHashPolicy::Digest.hash(&ip(), at(0));
+
+        assert_ne!(hashed.hash(), with_other.hash());
+        assert_ne!(hashed.hash(), digest.hash());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let hashed = IpHashPolicy::Truncate.hash(&ip(), SystemTime::now());
+
+        assert_eq!(None, hashed.hash());
+        assert_eq!(
+            vec![("ip_hash_mode", String::from("truncate")), ("ip_network", String::from("109.68.226.0"))],
+            hashed.headers()
+        );
+    }
+
+    #[test]
+    fn test_policy_per_environment() {
+        let mut environments = HashMap::new();
+
+        environments.insert(String::from("production"), IpHashConfig {
+            mode: IpHashMode::Keyed,
+            secret: Some(String::from(SECRET)),
+            rotation: Some(3600),
+        });
+
+        environments.insert(String::from("staging"), IpHashConfig {
+            mode: IpHashMode::Truncate,
+            secret: None,
+            rotation: None,
+        });
+
+        assert_eq!(
+            IpHashPolicy::Keyed { secret: hex::decode(SECRET).unwrap(), rotation: 3600 },
+            IpHashPolicy::new("production", Some(&environments))
+        );
+
+        assert_eq!(IpHashPolicy::Truncate, IpHashPolicy::new("staging", Some(&environments)));
+        assert_eq!(IpHashPolicy::Digest, IpHashPolicy::new("development", Some(&environments)));
+        assert_eq!(IpHashPolicy::Digest, IpHashPolicy::new("production", None));
+    }
+}
@@ -0,0 +1,192 @@
+//! The SDK event gateway as a library, so the fuzz targets in `fuzz/` can
+//! reach the parsing code. The `xorc-gateway` binary calls [`run`](fn.run.html).
+
+#[allow(unused_imports)]
+#[macro_use] extern crate serde_json;
+
+#[macro_use] extern crate serde_derive;
+#[macro_use] extern crate lazy_static;
+#[macro_use] extern crate prost_derive;
+#[macro_use] extern crate prometheus;
+#[macro_use] extern crate slog;
+
+extern crate slog_json;
+extern crate slog_async;
+extern crate slog_term;
+extern crate cdrs;
+extern crate hex;
+extern crate crossbeam;
+extern crate hyper;
+extern crate ring;
+extern crate serde;
+extern crate chrono;
+extern crate prost;
+extern crate bytes;
+extern crate toml;
+extern crate futures;
+extern crate base64;
+extern crate uuid;
+extern crate rand;
+extern crate http;
+extern crate tokio;
+extern crate tokio_threadpool;
+extern crate r2d2;
+extern crate blake2;
+extern crate rdkafka;
+extern crate tokio_signal;
+extern crate maxminddb;
+extern crate rustracing;
+extern crate rustracing_jaeger;
+extern crate uaparser;
+
+mod ifa_matching;
+mod ifa_store;
+mod error;
+pub mod context;
+pub mod events;
+mod gateway;
+mod config;
+mod logger;
+mod cors;
+mod app_registry;
+pub mod encryption;
+mod erasure;
+mod bus;
+mod consent;
+mod policy;
+mod metrics;
+mod reload;
+mod cli;
+mod bench;
+mod archive;
+mod replay;
+mod redaction;
+mod tracing;
+mod access_log;
+mod geoip;
+mod client_ip;
+mod ip_hash;
+mod user_agent;
+
+use gateway::Gateway;
+use ifa_matching::IfaMatching;
+use app_registry::AppRegistry;
+use config::Config;
+use futures::{sync::oneshot, Future, Stream};
+use cors::Cors;
+use consent::Consent;
+use archive::Archive;
+use redaction::LogRedaction;
+use metrics::SdkMetrics;
+use tracing::Tracing;
+use access_log::AccessLog;
+use geoip::GeoIp;
+use client_ip::ClientIpResolver;
+use ip_hash::IpHashing;
+use user_agent::UserAgents;
+use tokio_signal::unix::{Signal, SIGINT, SIGHUP};
+use logger::Logger;
+use cli::Command;
+use slog::Level;
+use std::str::FromStr;
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    env,
+    process,
+};
+
+/// Global non-IO services that can be raced from all the threads.
+lazy_static! {
+    pub static ref RUST_ENV: String =
+        env::var("RUST_ENV").unwrap_or_else(|_| {
+            String::from("development")
+        });
+
+    pub static ref GEOIP: Arc<GeoIp> = Arc::new(GeoIp::new());
+
+    pub static ref IFA_MATCHING: Arc<IfaMatching> = Arc::new(IfaMatching::new());
+    pub static ref GLOG: slog::Logger = logger::Logger::new();
+    pub static ref LOG_REDACTION: Arc<LogRedaction> = Arc::new(LogRedaction::new());
+    pub static ref IP_HASHING: Arc<IpHashing> = Arc::new(IpHashing::new());
+
+    pub static ref CONFIG: Arc<Config> = Arc::new(Config::parse(&Config::path()));
+
+    pub static ref APP_REGISTRY: Arc<AppRegistry> = Arc::new(AppRegistry::new());
+    pub static ref CORS: Option<Arc<Cors>> = Cors::new().map(Arc::new);
+    pub static ref CONSENT: Arc<Consent> = Arc::new(Consent::new());
+    pub static ref ARCHIVE: Option<Arc<Archive>> = Archive::new().map(Arc::new);
+    pub static ref SDK_METRICS: Arc<SdkMetrics> = Arc::new(SdkMetrics::new());
+    pub static ref TRACING: Arc<Tracing> = Arc::new(Tracing::new());
+    pub static ref ACCESS_LOG: Option<Arc<AccessLog>> = AccessLog::new().map(Arc::new);
+    pub static ref CLIENT_IP: Arc<ClientIpResolver> = Arc::new(ClientIpResolver::new());
+    pub static ref USER_AGENTS: Option<Arc<UserAgents>> = UserAgents::new().map(Arc::new);
+}
+
+/// Runs an operator command, or the gateway until SIGINT.
+pub fn run() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match Command::parse(&args) {
+        Ok(Command::Serve) => (),
+        Ok(command) => process::exit(command.run()),
+        Err(error) => process::exit(Command::usage(&error)),
+    }
+
+    if let Some(level) = CONFIG.gateway.log_level.as_ref().and_then(|l| Level::from_str(l).ok()) {
+        Logger::set_level(level);
+    }
+
+    let control = Arc::new(AtomicBool::new(true));
+
+    let mut threads: Vec<JoinHandle<_>> = Vec::new();
+    let (server_tx, server_rx) = oneshot::channel();
+
+    threads.push({
+        let control = control.clone();
+        thread::spawn(move || {
+            info!(*GLOG, "Starting the app registry thread...");
+            APP_REGISTRY.run_updater(control);
+            info!(*GLOG, "Exiting the app registry thread...");
+        })
+    });
+
+    threads.push({
+        thread::spawn(move || {
+            info!(*GLOG, "Starting the SDK gateway thread...");
+            Gateway::run(server_rx);
+            info!(*GLOG, "Exiting the SDK gateway thread...");
+        })
+    });
+
+    let sigint = Signal::new(SIGINT).flatten_stream();
+    let sighup = Signal::new(SIGHUP).flatten_stream();
+
+    // Reload the configuration on SIGHUP until SIGINT
+    let _ = sigint.select(sighup)
+        .take_while(|signal| Ok(*signal == SIGHUP))
+        .for_each(|_| {
+            info!(*GLOG, "Reloading configuration...");
+            reload::reload_config();
+            Ok(())
+        }).wait();
+
+    if let Err(error) = server_tx.send(()) {
+        error!(
+            *GLOG,
+            "There was an error sending the server shutdown signal: [{:?}]",
+            error
+        );
+    };
+
+    control.store(false, Ordering::Relaxed);
+
+    for thread in threads {
+        thread.thread().unpark();
+        thread.join().unwrap();
+    }
+}
@@ -1,10 +1,14 @@
-use slog::{self, Drain};
+use slog::{self, Drain, Level, Record, OwnedKVList};
 use slog_term::{TermDecorator, CompactFormat};
 use slog_async::Async;
 use slog_json::Json;
-use std::{env, io};
+use std::{env, io, sync::atomic::{AtomicUsize, Ordering}};
 use ::RUST_ENV;
 
+/// The minimum level to log, changeable at runtime with `set_level`. Zero
+/// logs everything.
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(0);
+
 pub struct Logger;
 
 impl Logger {
@@ -12,12 +16,12 @@ impl Logger {
         let drain = match env::var("LOG_FORMAT") {
             Ok(ref val) if val == "json" => {
                 let drain = Json::new(io::stderr()).add_default_keys().build().fuse();
-                Async::new(drain).build().fuse()
+                Async::new(RuntimeLevel { drain }).build().fuse()
             }
             _ => {
                 let decorator = TermDecorator::new().stderr().build();
                 let drain = CompactFormat::new(decorator).build().fuse();
-                Async::new(drain).build().fuse()
+                Async::new(RuntimeLevel { drain }).build().fuse()
             }
         };
 
@@ -26,4 +30,28 @@ impl Logger {
             o!("application_name" => "xorc-gateway", "environment" => &*RUST_ENV)
         )
     }
+
+    pub fn set_level(level: Level) {
+        LOG_LEVEL.store(level.as_usize(), Ordering::Relaxed);
+    }
+}
+
+struct RuntimeLevel<D> {
+    drain: D,
+}
+
+impl<D: Drain<Ok=()>> Drain for RuntimeLevel<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        let level = Level::from_usize(LOG_LEVEL.load(Ordering::Relaxed))
+            .unwrap_or(Level::Trace);
+
+        if record.level().is_at_least(level) {
+            self.drain.log(record, values)
+        } else {
+            Ok(())
+        }
+    }
 }
@@ -1,4 +1,23 @@
+use std::{collections::HashSet, sync::RwLock};
 use prometheus::{CounterVec, Counter, Histogram};
+use config::Config;
+use context::Context;
+use events::input::Platform;
+use error::GatewayError;
+
+use ::CONFIG;
+
+/// Label value for everything over the limit or not in the allowlist.
+pub const OTHER: &'static str = "other";
+
+/// Label value if the request doesn't tell, e.g. the payload is invalid.
+pub const UNKNOWN: &'static str = "unknown";
+
+/// Distinct app ids in the labels, if not set in `[metrics]`.
+const DEFAULT_MAX_APP_IDS: usize = 100;
+
+/// Distinct SDK versions in the labels, if not set in `[metrics]`.
+const DEFAULT_MAX_SDK_VERSIONS: usize = 20;
 
 lazy_static! {
     pub static ref APP_UPDATE_COUNTER: Counter = register_counter!(
@@ -6,11 +25,34 @@ lazy_static! {
         "Total number of application updates"
     ).unwrap();
 
+    pub static ref ORIGINS_UPDATE_COUNTER: Counter = register_counter!(
+        "gateway_origins_updates",
+        "Total number of CORS origin updates"
+    ).unwrap();
+
+    pub static ref CONFIG_RELOAD_COUNTER: CounterVec = register_counter_vec!(
+        "gateway_config_reloads_total",
+        "Total number of configuration reloads",
+        &["status"]
+    ).unwrap();
+
     pub static ref EVENTS_COUNTER: Counter = register_counter!(
         "events_total",
         "Total number of SDK events sent"
     ).unwrap();
 
+    pub static ref SDK_EVENTS_COUNTER: CounterVec = register_counter_vec!(
+        "sdk_events_total",
+        "Total number of SDK events sent by app, platform and SDK version",
+        &["app_id", "platform", "sdk_version"]
+    ).unwrap();
+
+    pub static ref SDK_REQUEST_COUNTER: CounterVec = register_counter_vec!(
+        "sdk_requests_total",
+        "Total number of SDK event requests by app, platform, SDK version and error",
+        &["app_id", "platform", "sdk_version", "status", "error"]
+    ).unwrap();
+
     pub static ref REQUEST_COUNTER: CounterVec = register_counter_vec!(
         "http_requests_total",
         "Total number of HTTP requests made.",
@@ -23,6 +65,12 @@ lazy_static! {
         &["method", "status"]
     ).unwrap();
 
+    pub static ref ARCHIVE_COUNTER: CounterVec = register_counter_vec!(
+        "gateway_archived_requests_total",
+        "Total number of requests sent to the archive",
+        &["status"]
+    ).unwrap();
+
     pub static ref RESPONSE_TIMES_HISTOGRAM: Histogram = register_histogram!(
         "http_request_latency_seconds",
         "The HTTP request latencies in seconds",
@@ -47,3 +95,190 @@ lazy_static! {
              0.075, 1.0, 2.0, 4.0, 5.0, 10.0]
     ).unwrap();
 }
+
+/// Caps the distinct values of a label to protect Prometheus. With an
+/// allowlist only the listed values are used, otherwise the first
+/// `max_values` distinct values seen. Everything else is labeled `other`.
+pub struct LabelLimit {
+    allowed: Option<HashSet<String>>,
+    max_values: usize,
+    seen: RwLock<HashSet<String>>,
+}
+
+impl LabelLimit {
+    pub fn new(allowed: Option<&Vec<String>>, max_values: usize) -> LabelLimit {
+        LabelLimit {
+            allowed: allowed.map(|values| values.iter().cloned().collect()),
+            max_values,
+            seen: RwLock::new(HashSet::new()),
+        }
+    }
+
+    pub fn value<'a>(&self, value: &'a str) -> &'a str {
+        if let Some(ref allowed) = self.allowed {
+            return if allowed.contains(value) { value } else { OTHER }
+        }
+
+        if self.seen.read().unwrap().contains(value) {
+            return value
+        }
+
+        let mut seen = self.seen.write().unwrap();
+
+        if seen.contains(value) || seen.len() < self.max_values {
+            seen.insert(value.to_string());
+            value
+        } else {
+            OTHER
+        }
+    }
+}
+
+/// The app, platform and SDK version labels of the SDK metrics, with the
+/// app ids and SDK versions capped from the `[metrics]` configuration.
+pub struct SdkMetrics {
+    app_ids: LabelLimit,
+    sdk_versions: LabelLimit,
+}
+
+impl SdkMetrics {
+    pub fn new() -> SdkMetrics {
+        Self::from_config(&CONFIG)
+    }
+
+    pub fn from_config(config: &Config) -> SdkMetrics {
+        let metrics = config.metrics.as_ref();
+
+        SdkMetrics {
+            app_ids: LabelLimit::new(
+                metrics.and_then(|m| m.app_ids.as_ref()),
+                metrics.and_then(|m| m.max_app_ids).unwrap_or(DEFAULT_MAX_APP_IDS),
+            ),
+            sdk_versions: LabelLimit::new(
+                metrics.and_then(|m| m.sdk_versions.as_ref()),
+                metrics.and_then(|m| m.max_sdk_versions).unwrap_or(DEFAULT_MAX_SDK_VERSIONS),
+            ),
+        }
+    }
+
+    /// The `app_id`, `platform` and `sdk_version` label values. Only app ids
+    /// found from the registry and SDK versions of validated requests take
+    /// a place under the cap, so unauthenticated clients can't fill it.
+    pub fn labels<'a>(&self, context: Option<&'a Context>, known_app: bool) -> [&'a str; 3] {
+        match context {
+            Some(context) => {
+                let platform = match context.platform {
+                    Platform::Ios => "ios",
+                    Platform::Android => "android",
+                    Platform::Web => "web",
+                    Platform::Unknown => UNKNOWN,
+                };
+
+                let sdk_version = match context.sdk_version {
+                    Some(ref version) if context.validated => self.sdk_versions.value(version),
+                    Some(_) => OTHER,
+                    None => UNKNOWN,
+                };
+
+                let app_id = if known_app || context.validated {
+                    self.app_ids.value(&context.app_id)
+                } else {
+                    OTHER
+                };
+
+                [app_id, platform, sdk_version]
+            },
+            None => [UNKNOWN, UNKNOWN, UNKNOWN],
+        }
+    }
+
+    pub fn count_request(
+        &self,
+        context: Option<&Context>,
+        known_app: bool,
+        status: &str,
+        error: Option<&GatewayError>,
+    )
+    {
+        let [app_id, platform, sdk_version] = self.labels(context, known_app);
+        let error = error.map(|e| e.kind()).unwrap_or("none");
+
+        SDK_REQUEST_COUNTER
+            .with_label_values(&[app_id, platform, sdk_version, status, error])
+            .inc();
+    }
+
+    pub fn count_events(&self, context: &Context, count: usize) {
+        SDK_EVENTS_COUNTER
+            .with_label_values(&self.labels(Some(context), true))
+            .inc_by(count as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::HeaderMap;
+
+    fn values(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let limit = LabelLimit::new(Some(&values(&["1.0.0", "1.1.0"])), 100);
+
+        assert_eq!("1.0.0", limit.value("1.0.0"));
+        assert_eq!(OTHER, limit.value("0.9.0"));
+    }
+
+    #[test]
+    fn test_max_values() {
+        let limit = LabelLimit::new(None, 2);
+
+        assert_eq!("a", limit.value("a"));
+        assert_eq!("b", limit.value("b"));
+        assert_eq!(OTHER, limit.value("c"));
+        assert_eq!("a", limit.value("a"));
+    }
+
+    #[test]
+    fn test_labels() {
+        let metrics = SdkMetrics {
+            app_ids: LabelLimit::new(None, 1),
+            sdk_versions: LabelLimit::new(Some(&values(&["1.0.0"])), 10),
+        };
+
+        let mut context = Context::new(&HeaderMap::new(), "app-1", Platform::Android);
+        context.sdk_version = Some(String::from("1.0.0"));
+        context.validated = true;
+
+        assert_eq!(["app-1", "android", "1.0.0"], metrics.labels(Some(&context), true));
+
+        let mut context = Context::new(&HeaderMap::new(), "app-2", Platform::Unknown);
+        context.sdk_version = Some(String::from("0.1.0"));
+        context.validated = true;
+
+        assert_eq!([OTHER, UNKNOWN, OTHER], metrics.labels(Some(&context), true));
+        assert_eq!([UNKNOWN, UNKNOWN, UNKNOWN], metrics.labels(None, false));
+    }
+
+    #[test]
+    fn test_unvalidated_labels() {
+        let metrics = SdkMetrics {
+            app_ids: LabelLimit::new(None, 1),
+            sdk_versions: LabelLimit::new(None, 1),
+        };
+
+        let mut context = Context::new(&HeaderMap::new(), "random", Platform::Ios);
+        context.sdk_version = Some(String::from("6.6.6"));
+
+        assert_eq!([OTHER, "ios", OTHER], metrics.labels(Some(&context), false));
+
+        let mut context = Context::new(&HeaderMap::new(), "app-1", Platform::Ios);
+        context.sdk_version = Some(String::from("1.0.0"));
+        context.validated = true;
+
+        assert_eq!(["app-1", "ios", "1.0.0"], metrics.labels(Some(&context), true));
+    }
+}
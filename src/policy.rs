@@ -0,0 +1,297 @@
+use base64;
+use config::Config;
+use encryption::DEVELOPMENT_SECRET;
+use redaction::{Redaction, RedactionPolicy};
+use ip_hash::IpHashPolicy;
+
+/// The `default_token` from the example configurations, known publicly and
+/// never to be used outside of development.
+pub const SAMPLE_DEFAULT_TOKEN: &'static str =
+    "T0BdxB0O/+eRZhN6REPmbYpTqjxCTtUgirbW7ABJ8renKaxoGbHFsOX2iLFhuWaJbZHzmTnvKv15fv/87ceIQQ==";
+
+/// Safety rules for running outside of the development environment. In
+/// development everything is allowed.
+pub struct EnvironmentPolicy<'a> {
+    environment: &'a str,
+    secret: Option<&'a str>,
+    ifa_pepper: Option<&'a str>,
+}
+
+impl<'a> EnvironmentPolicy<'a> {
+    /// The policy for the `RUST_ENV` environment with the `SECRET` and
+    /// `IFA_PEPPER` environment variables.
+    pub fn new(
+        environment: &'a str,
+        secret: Option<&'a str>,
+        ifa_pepper: Option<&'a str>,
+    ) -> EnvironmentPolicy<'a>
+    {
+        EnvironmentPolicy { environment, secret, ifa_pepper }
+    }
+
+    pub fn is_development(&self) -> bool {
+        self.environment == "development"
+    }
+
+    /// Checks the configuration against every rule, returning all the
+    /// violations with their key paths.
+    pub fn check(&self, config: &Config) -> Vec<String> {
+        if self.is_development() {
+            return Vec::new()
+        }
+
+        let rules = [
+            Self::check_empty_signature(config),
+            Self::check_manage_apps(config),
+            Self::check_test_apps(config),
+            Self::check_default_token(config),
+            self.check_secret(),
+            self.check_ifa_pepper(config),
+            self.check_log_redaction(config),
+            self.check_ip_hash(config),
+        ];
+
+        rules.iter().filter_map(|rule| rule.clone()).collect()
+    }
+
+    fn check_empty_signature(config: &Config) -> Option<String> {
+        if config.gateway.allow_empty_signature {
+            Some(String::from("gateway.allow_empty_signature: Cannot allow empty signatures outside of development environment."))
+        } else {
+            None
+        }
+    }
+
+    fn check_manage_apps(config: &Config) -> Option<String> {
+        if !config.cassandra.manage_apps {
+            Some(String::from("cassandra.manage_apps: Cannot allow manage_apps to be false outside of development environment."))
+        } else {
+            None
+        }
+    }
+
+    fn check_test_apps(config: &Config) -> Option<String> {
+        if config.test_apps.as_ref().map(|apps| !apps.is_empty()).unwrap_or(false) {
+            Some(String::from("test_apps: Cannot use static test apps outside of development environment."))
+        } else {
+            None
+        }
+    }
+
+    fn check_default_token(config: &Config) -> Option<String> {
+        if config.gateway.default_token == SAMPLE_DEFAULT_TOKEN {
+            Some(String::from("gateway.default_token: Cannot use the sample token outside of development environment."))
+        } else {
+            None
+        }
+    }
+
+    fn check_log_redaction(&self, config: &Config) -> Option<String> {
+        let policy = RedactionPolicy::new(self.environment, config.log_redaction.as_ref());
+
+        if policy.api_token == Redaction::Keep || policy.signature == Redaction::Keep {
+            Some(format!(
+                "log_redaction.{}: Cannot log the api_token or signature outside of development environment.",
+                self.environment
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn check_ip_hash(&self, config: &Config) -> Option<String> {
+        let policy = IpHashPolicy::new(self.environment, config.ip_hash.as_ref());
+
+        if policy == IpHashPolicy::Digest {
+            Some(format!(
+                "ip_hash.{}: Cannot use the unkeyed IP digest outside of development environment.",
+                self.environment
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn check_ifa_pepper(&self, config: &Config) -> Option<String> {
+        if config.cassandra.hash_ifas && self.ifa_pepper.is_none() {
+            Some(String::from("IFA_PEPPER: Please set IFA_PEPPER environment variable when cassandra.hash_ifas is set."))
+        } else {
+            None
+        }
+    }
+
+    fn check_secret(&self) -> Option<String> {
+        let secret = match self.secret {
+            Some(secret) => secret,
+            None => return Some(String::from("SECRET: Please set SECRET environment variable.")),
+        };
+
+        match base64::decode_config(secret, base64::URL_SAFE_NO_PAD) {
+            Ok(ref key) if key.len() != 32 => {
+                Some(String::from("SECRET: Must be 32 bytes of url-safe base64 without padding."))
+            },
+            Ok(ref key) if &key[..] == &DEVELOPMENT_SECRET[..] => {
+                Some(String::from("SECRET: Cannot use the development secret outside of development environment."))
+            },
+            Ok(_) => None,
+            Err(_) => {
+                Some(String::from("SECRET: Not url-safe base64 without padding."))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{TestAppConfig, LogRedactionConfig, IpHashConfig};
+    use ip_hash::IpHashMode;
+    use std::collections::HashMap;
+
+    const SECRET: &'static str = "mlJ7tuWdj3rC9WwvZgtU2CBTjrN9PnB0tWrPqOm2Hhw";
+
+    fn production(secret: Option<&str>) -> EnvironmentPolicy {
+        EnvironmentPolicy::new("production", secret, None)
+    }
+
+    fn safe_config() -> Config {
+        let mut config = Config::parse("./config/config.toml.tests");
+
+        config.gateway.allow_empty_signature = false;
+        config.gateway.default_token = String::from("b3RoZXIgdG9rZW4=");
+        config.cassandra.manage_apps = true;
+        config.test_apps = None;
+
+        let mut ip_hash = HashMap::new();
+
+        ip_hash.insert(String::from("production"), IpHashConfig {
+            mode: IpHashMode::Truncate,
+            secret: None,
+            rotation: None,
+        });
+
+        config.ip_hash = Some(ip_hash);
+
+        config
+    }
+
+    fn violations(policy: &EnvironmentPolicy, config: &Config, key: &str) -> usize {
+        policy.check(config).iter().filter(|e| e.starts_with(key)).count()
+    }
+
+    #[test]
+    fn test_safe_config_in_production() {
+        assert!(production(Some(SECRET)).check(&safe_config()).is_empty());
+    }
+
+    #[test]
+    fn test_development_allows_everything() {
+        let mut config = safe_config();
+        config.gateway.allow_empty_signature = true;
+        config.cassandra.manage_apps = false;
+        config.gateway.default_token = String::from(SAMPLE_DEFAULT_TOKEN);
+
+        let policy = EnvironmentPolicy::new("development", None, None);
+
+        assert!(policy.check(&config).is_empty());
+    }
+
+    #[test]
+    fn test_empty_signature_forbidden() {
+        let mut config = safe_config();
+        config.gateway.allow_empty_signature = true;
+
+        assert_eq!(1, violations(&production(Some(SECRET)), &config, "gateway.allow_empty_signature"));
+    }
+
+    #[test]
+    fn test_manage_apps_required() {
+        let mut config = safe_config();
+        config.cassandra.manage_apps = false;
+
+        assert_eq!(1, violations(&production(Some(SECRET)), &config, "cassandra.manage_apps"));
+    }
+
+    #[test]
+    fn test_static_test_apps_forbidden() {
+        let mut config = safe_config();
+
+        config.test_apps = Some(vec![TestAppConfig {
+            app_id: String::from("22222222-0000-0000-0000-000000000000"),
+            token: None,
+            secret_ios: None,
+            secret_android: None,
+            secret_web: None,
+        }]);
+
+        assert_eq!(1, violations(&production(Some(SECRET)), &config, "test_apps"));
+    }
+
+    #[test]
+    fn test_sample_default_token_rejected() {
+        let mut config = safe_config();
+        config.gateway.default_token = String::from(SAMPLE_DEFAULT_TOKEN);
+
+        assert_eq!(1, violations(&production(Some(SECRET)), &config, "gateway.default_token"));
+    }
+
+    #[test]
+    fn test_secret_required() {
+        assert_eq!(1, violations(&production(None), &safe_config(), "SECRET"));
+    }
+
+    #[test]
+    fn test_development_secret_rejected() {
+        let secret = base64::encode_config(&DEVELOPMENT_SECRET, base64::URL_SAFE_NO_PAD);
+        let policy = production(Some(&secret));
+
+        assert_eq!(1, violations(&policy, &safe_config(), "SECRET"));
+    }
+
+    #[test]
+    fn test_invalid_secret_rejected() {
+        assert_eq!(1, violations(&production(Some("kulli")), &safe_config(), "SECRET"));
+        assert_eq!(1, violations(&production(Some("!!!")), &safe_config(), "SECRET"));
+    }
+
+    #[test]
+    fn test_logging_secrets_forbidden() {
+        let mut config = safe_config();
+        let mut environments = HashMap::new();
+
+        environments.insert(String::from("production"), LogRedactionConfig {
+            api_token: Some(Redaction::Keep),
+            signature: None,
+            device_id: Some(Redaction::Keep),
+            encrypted_device_id: None,
+        });
+
+        config.log_redaction = Some(environments);
+
+        assert_eq!(1, violations(&production(Some(SECRET)), &config, "log_redaction.production"));
+    }
+
+    #[test]
+    fn test_ifa_pepper_required_with_hashing() {
+        let mut config = safe_config();
+
+        assert_eq!(0, violations(&production(Some(SECRET)), &config, "IFA_PEPPER"));
+
+        config.cassandra.hash_ifas = true;
+
+        assert_eq!(1, violations(&production(Some(SECRET)), &config, "IFA_PEPPER"));
+
+        let pepper = base64::encode_config(&[7; 32], base64::URL_SAFE_NO_PAD);
+        let policy = EnvironmentPolicy::new("production", Some(SECRET), Some(&pepper));
+
+        assert!(policy.check(&config).is_empty());
+    }
+
+    #[test]
+    fn test_unkeyed_ip_digest_forbidden() {
+        let mut config = safe_config();
+        config.ip_hash = None;
+
+        assert_eq!(1, violations(&production(Some(SECRET)), &config, "ip_hash.production"));
+    }
+}
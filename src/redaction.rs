@@ -0,0 +1,196 @@
+use std::{collections::HashMap, sync::Arc};
+use blake2::{Blake2b, Digest};
+use crossbeam::sync::ArcCell;
+use hex;
+use config::{Config, LogRedactionConfig};
+
+use ::{CONFIG, RUST_ENV};
+
+/// Characters kept of a truncated value.
+const TRUNCATED_LENGTH: usize = 8;
+
+/// Bytes of the hash logged, as hex.
+const HASH_LENGTH: usize = 8;
+
+/// What to log of a secret or an identifier.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Redaction {
+    /// The value as is.
+    Keep,
+    /// Nothing.
+    Drop,
+    /// A short hash, the same for the same value so the log lines can still
+    /// be correlated.
+    Hash,
+    /// The first characters of the value.
+    Truncate,
+}
+
+impl Redaction {
+    /// The value to log, `None` if the field should be left out.
+    pub fn apply(&self, value: &str) -> Option<String> {
+        match self {
+            Redaction::Keep => Some(value.to_string()),
+            Redaction::Drop => None,
+            Redaction::Hash => {
+                let mut hasher = Blake2b::new();
+                hasher.input(value.as_bytes());
+
+                Some(hex::encode(&hasher.result()[..HASH_LENGTH]))
+            },
+            Redaction::Truncate => {
+                let truncated: String = value.chars().take(TRUNCATED_LENGTH).collect();
+
+                if truncated.len() < value.len() {
+                    Some(format!("{}...", truncated))
+                } else {
+                    Some(truncated)
+                }
+            },
+        }
+    }
+}
+
+/// The redaction of every sensitive field of the request context.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedactionPolicy {
+    pub api_token: Redaction,
+    pub signature: Redaction,
+    pub device_id: Redaction,
+    pub encrypted_device_id: Redaction,
+}
+
+impl RedactionPolicy {
+    /// Everything is logged in development. Elsewhere the credentials are
+    /// dropped and the device id is hashed.
+    pub fn defaults(environment: &str) -> RedactionPolicy {
+        if environment == "development" {
+            RedactionPolicy {
+                api_token: Redaction::Keep,
+                signature: Redaction::Keep,
+                device_id: Redaction::Keep,
+                encrypted_device_id: Redaction::Keep,
+            }
+        } else {
+            RedactionPolicy {
+                api_token: Redaction::Drop,
+                signature: Redaction::Drop,
+                device_id: Redaction::Hash,
+                encrypted_device_id: Redaction::Drop,
+            }
+        }
+    }
+
+    /// The defaults of the environment, with the fields set in the
+    /// `[log_redaction.<environment>]` section overriding them.
+    pub fn new(
+        environment: &str,
+        config: Option<&HashMap<String, LogRedactionConfig>>,
+    ) -> RedactionPolicy
+    {
+        let defaults = Self::defaults(environment);
+
+        match config.and_then(|environments| environments.get(environment)) {
+            Some(config) => RedactionPolicy {
+                api_token: config.api_token.unwrap_or(defaults.api_token),
+                signature: config.signature.unwrap_or(defaults.signature),
+                device_id: config.device_id.unwrap_or(defaults.device_id),
+                encrypted_device_id: config.encrypted_device_id.unwrap_or(defaults.encrypted_device_id),
+            },
+            None => defaults,
+        }
+    }
+}
+
+/// The redaction policy of the running environment for the log lines,
+/// swapped when the configuration is reloaded.
+pub struct LogRedaction {
+    policy: ArcCell<RedactionPolicy>,
+}
+
+impl LogRedaction {
+    pub fn new() -> LogRedaction {
+        Self::from_config(&CONFIG)
+    }
+
+    pub fn from_config(config: &Config) -> LogRedaction {
+        LogRedaction { policy: ArcCell::new(Arc::new(Self::policy_for(config))) }
+    }
+
+    pub fn reload(&self, config: &Config) {
+        self.policy.set(Arc::new(Self::policy_for(config)));
+    }
+
+    pub fn policy(&self) -> Arc<RedactionPolicy> {
+        self.policy.get()
+    }
+
+    fn policy_for(config: &Config) -> RedactionPolicy {
+        RedactionPolicy::new(&RUST_ENV, config.log_redaction.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &'static str = "46732a28cd445366c6c8dcbd57500af4e69597c8ebe224634d6ccab812275c9c";
+
+    #[test]
+    fn test_keep_and_drop() {
+        assert_eq!(Some(String::from(TOKEN)), Redaction::Keep.apply(TOKEN));
+        assert_eq!(None, Redaction::Drop.apply(TOKEN));
+    }
+
+    #[test]
+    fn test_hash() {
+        let hash = Redaction::Hash.apply(TOKEN).unwrap();
+
+        assert_eq!(HASH_LENGTH * 2, hash.len());
+        assert!(!TOKEN.contains(&hash));
+        assert_eq!(Some(hash), Redaction::Hash.apply(TOKEN));
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(Some(String::from("46732a28...")), Redaction::Truncate.apply(TOKEN));
+        assert_eq!(Some(String::from("short")), Redaction::Truncate.apply("short"));
+        assert_eq!(Some(String::from("ääääääää...")), Redaction::Truncate.apply("äääääääääää"));
+    }
+
+    #[test]
+    fn test_no_secrets_by_default_outside_development() {
+        for environment in &["staging", "production"] {
+            let policy = RedactionPolicy::new(environment, None);
+
+            assert_eq!(Redaction::Drop, policy.api_token);
+            assert_eq!(Redaction::Drop, policy.signature);
+            assert_eq!(Redaction::Hash, policy.device_id);
+            assert_eq!(Redaction::Drop, policy.encrypted_device_id);
+        }
+
+        assert_eq!(Redaction::Keep, RedactionPolicy::new("development", None).api_token);
+    }
+
+    #[test]
+    fn test_environment_overrides() {
+        let mut environments = HashMap::new();
+
+        environments.insert(String::from("staging"), LogRedactionConfig {
+            api_token: None,
+            signature: None,
+            device_id: Some(Redaction::Truncate),
+            encrypted_device_id: None,
+        });
+
+        let staging = RedactionPolicy::new("staging", Some(&environments));
+
+        assert_eq!(Redaction::Truncate, staging.device_id);
+        assert_eq!(Redaction::Drop, staging.api_token);
+
+        let production = RedactionPolicy::new("production", Some(&environments));
+
+        assert_eq!(Redaction::Hash, production.device_id);
+    }
+}
@@ -0,0 +1,61 @@
+use std::str::FromStr;
+use slog::Level;
+use config::Config;
+use logger::Logger;
+use metrics::CONFIG_RELOAD_COUNTER;
+
+use ::{GLOG, APP_REGISTRY, CORS, CONSENT, ARCHIVE, LOG_REDACTION, IP_HASHING};
+
+/// Reloads the configuration file, triggered with `SIGHUP`. The new
+/// configuration is fully validated before anything is swapped, and on
+/// errors the old configuration stays in use.
+///
+/// Reloaded at runtime:
+///
+/// - `[[test_apps]]`, if apps are not managed in ScyllaDB
+/// - `[cors]` headers and `[[origins]]`
+/// - `[[consent_policies]]`
+/// - `[archive]` sampling rates
+/// - `[gateway]` `log_level`, if set. Without it the current level stays.
+/// - `[log_redaction]`
+/// - `[ip_hash]`, including the secret
+///
+/// Everything else needs a restart, such as the listen address, threads,
+/// Kafka and ScyllaDB settings, the `[admin]` token and audit topic and the
+/// `[metrics]` label allowlists and caps.
+pub fn reload_config() {
+    let path = Config::path();
+
+    match Config::load(&path) {
+        Ok(config) => {
+            APP_REGISTRY.reload(&config);
+
+            if let Some(ref cors) = *CORS {
+                cors.reload(&config);
+            }
+
+            CONSENT.reload(&config);
+
+            if let Some(ref archive) = *ARCHIVE {
+                archive.reload(&config);
+            }
+
+            let level = config.gateway.log_level.as_ref()
+                .and_then(|level| Level::from_str(level).ok());
+
+            if let Some(level) = level {
+                Logger::set_level(level);
+            }
+
+            LOG_REDACTION.reload(&config);
+            IP_HASHING.reload(&config);
+
+            CONFIG_RELOAD_COUNTER.with_label_values(&["ok"]).inc();
+            info!(*GLOG, "Reloaded configuration from {}", path);
+        },
+        Err(err) => {
+            CONFIG_RELOAD_COUNTER.with_label_values(&["error"]).inc();
+            error!(*GLOG, "Could not reload configuration, keeping the old one: {}", err);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::{error::Error, sync::Arc};
+use tokio::runtime::Runtime;
+use prost::Message;
+use archive::Entry;
+use bus;
+use gateway::{Dependencies, Gateway};
+use ifa_store::MemoryIfaStore;
+use events::output;
+
+use ::IFA_MATCHING;
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub path: String,
+    pub app_id: Option<String>,
+    pub dry_run: bool,
+}
+
+/// Sends the requests from an archive file through the pipeline again,
+/// optionally only for one app. Only the requests accepted when archived
+/// are replayed, the rejected ones were never authenticated.
+///
+/// A dry run uses an in-memory bus and IFA store, printing the events that
+/// would have been sent instead of sending them to Kafka.
+pub fn run(options: Options) -> Result<String, String> {
+    let entries = Entry::read(&options.path)?;
+    let memory = Arc::new(bus::Memory::new());
+
+    let mut deps = if options.dry_run {
+        Dependencies::new(memory.clone(), Arc::new(MemoryIfaStore::new()))
+    } else {
+        Dependencies::new(Arc::new(bus::Kafka::new()), IFA_MATCHING.clone())
+    };
+
+    // Not archiving the replayed requests again
+    deps.archive = None;
+
+    let deps = Arc::new(deps);
+    let mut runtime = Runtime::new().map_err(|e| format!("{}", e))?;
+    let (mut replayed, mut failed, mut skipped) = (0, 0, 0);
+
+    for (i, entry) in entries.iter().enumerate() {
+        let selected = options.app_id
+            .as_ref()
+            .map(|app_id| entry.app_id.as_ref() == Some(app_id))
+            .unwrap_or(true);
+
+        if !selected || !entry.is_success() {
+            skipped += 1;
+            continue
+        }
+
+        let result = entry.body().and_then(|body| {
+            let replay = Gateway::replay(&body, &entry.headers(), deps.clone());
+            runtime.block_on(replay).map_err(|e| e.description().to_string())
+        });
+
+        match result {
+            Ok(_) => replayed += 1,
+            Err(error) => {
+                failed += 1;
+                eprintln!("Entry {} failed: {}", i + 1, error);
+            },
+        }
+    }
+
+    let mut output: Vec<String> = memory
+        .records()
+        .iter()
+        .map(|record| {
+            output::events::SdkEventBatch::decode(&record.payload[..])
+                .map(|batch| format!("{:?}", batch))
+                .unwrap_or_else(|e| format!("Invalid protobuf: {}", e))
+        })
+        .collect();
+
+    output.push(format!(
+        "Replayed {} request(s), {} failed, {} skipped",
+        replayed,
+        failed,
+        skipped
+    ));
+
+    Ok(output.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs::{self, File}, io::Write};
+    use chrono::offset::Utc;
+    use http::HeaderMap;
+    use uuid::Uuid;
+    use archive::Outcome;
+    use serde_json;
+
+    const APP_ID: &'static str = "22222222-0000-0000-0000-000000000000";
+
+    fn entry(app_id: &str, status: u16) -> Entry {
+        let body = json!({
+            "environment": { "app_id": app_id },
+            "device": { "platform": "ios" },
+            "events": [
+                { "id": "1", "timestamp": "1527092525607", "name": "replayed", "properties": {} }
+            ]
+        });
+
+        Entry::new(
+            Utc::now(),
+            Some(app_id.to_string()),
+            &HeaderMap::new(),
+            body.to_string().as_bytes(),
+            Outcome { status, error: None },
+        )
+    }
+
+    fn archive_file(entries: &[Entry]) -> String {
+        let path = env::temp_dir().join(format!("xorc-replay-{}.jsonl", Uuid::new_v4()));
+        let mut file = File::create(&path).unwrap();
+
+        for entry in entries {
+            writeln!(file, "{}", serde_json::to_string(entry).unwrap()).unwrap();
+        }
+
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let path = archive_file(&[
+            entry(APP_ID, 200),
+            entry(APP_ID, 412),
+            entry("33333333-0000-0000-0000-000000000000", 200),
+        ]);
+
+        let output = run(Options { path: path.clone(), app_id: None, dry_run: true }).unwrap();
+
+        assert!(output.contains("replayed"));
+        assert!(output.ends_with("Replayed 1 request(s), 1 failed, 1 skipped"), "{}", output);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_dry_run_for_an_app() {
+        let path = archive_file(&[
+            entry(APP_ID, 200),
+            entry("33333333-0000-0000-0000-000000000000", 200),
+        ]);
+
+        let options = Options {
+            path: path.clone(),
+            app_id: Some(String::from("33333333-0000-0000-0000-000000000000")),
+            dry_run: true,
+        };
+
+        let output = run(options).unwrap();
+
+        assert_eq!("Replayed 0 request(s), 1 failed, 1 skipped", output);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
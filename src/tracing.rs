@@ -0,0 +1,388 @@
+use std::{net::SocketAddr, str::FromStr, thread};
+use http::HeaderMap;
+use rand::{thread_rng, Rng};
+use rustracing::{sampler::AllSampler, span::SpanContext, tag::Tag};
+use rustracing_jaeger::{
+    self,
+    Tracer,
+    reporter::JaegerCompactReporter,
+    span::{SpanContextState, SpanReceiver},
+};
+use config::Config;
+
+use ::{CONFIG, GLOG};
+
+/// The W3C trace context header.
+pub const TRACEPARENT: &'static str = "traceparent";
+
+/// The service name of the spans, if not set in `[tracing]`.
+const DEFAULT_SERVICE_NAME: &'static str = "xorc-gateway";
+
+/// Spans sent to the agent in one UDP packet.
+const REPORT_BATCH_SIZE: usize = 50;
+
+/// The trace and span of a request, as in the W3C `traceparent` header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// The context from the `traceparent` header, `None` if missing or
+    /// invalid.
+    pub fn from_headers(headers: &HeaderMap) -> Option<TraceContext> {
+        headers
+            .get(TRACEPARENT)
+            .and_then(|h| h.to_str().ok())
+            .and_then(Self::parse)
+    }
+
+    /// Parses `version-trace_id-parent_id-flags`. Later versions may add
+    /// fields after the flags, version `00` may not.
+    pub fn parse(value: &str) -> Option<TraceContext> {
+        let mut parts = value.trim().split('-');
+
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+
+        if version.len() != 2 || version == "ff" || !is_hex(version) {
+            return None
+        }
+
+        if version == "00" && parts.next().is_some() {
+            return None
+        }
+
+        if trace_id.len() != 32 || !is_hex(trace_id) {
+            return None
+        }
+
+        if span_id.len() != 16 || !is_hex(span_id) {
+            return None
+        }
+
+        if flags.len() != 2 || !is_hex(flags) {
+            return None
+        }
+
+        let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+        let span_id = u64::from_str_radix(span_id, 16).ok()?;
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+
+        if trace_id == 0 || span_id == 0 {
+            return None
+        }
+
+        Some(TraceContext { trace_id, span_id, sampled: flags & 1 == 1 })
+    }
+
+    /// The context as a version `00` `traceparent` header value.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id,
+            self.span_id,
+            if self.sampled { 1 } else { 0 }
+        )
+    }
+
+    pub fn trace_id_hex(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+
+    fn random(parent: Option<&TraceContext>, sampled: bool) -> TraceContext {
+        let mut rng = thread_rng();
+
+        let trace_id = parent.map(|p| p.trace_id).unwrap_or_else(|| {
+            (u128::from(rng.gen::<u64>()) << 64) | u128::from(rng.gen_range(1, u64::max_value()))
+        });
+
+        TraceContext {
+            trace_id,
+            span_id: rng.gen_range(1, u64::max_value()),
+            sampled,
+        }
+    }
+
+    fn jaeger_context(&self) -> Option<SpanContext<SpanContextState>> {
+        let state = format!(
+            "{:x}:{:x}:0:{:x}",
+            self.trace_id,
+            self.span_id,
+            if self.sampled { 1 } else { 0 }
+        );
+
+        SpanContextState::from_str(&state)
+            .ok()
+            .map(|state| SpanContext::new(state, Vec::new()))
+    }
+}
+
+fn is_hex(value: &str) -> bool {
+    value.bytes().all(|b| b.is_ascii_digit() || (b >= b'a' && b <= b'f'))
+}
+
+/// A stage of the request. Reported to the agent when dropped, if sampled
+/// and tracing is enabled.
+pub struct Span {
+    context: TraceContext,
+    inner: Option<rustracing_jaeger::Span>,
+}
+
+impl Span {
+    pub fn context(&self) -> TraceContext {
+        self.context
+    }
+
+    pub fn set_tag(&mut self, key: &'static str, value: String) {
+        if let Some(ref mut span) = self.inner {
+            span.set_tag(|| Tag::new(key, value));
+        }
+    }
+
+    pub fn set_error(&mut self) {
+        if let Some(ref mut span) = self.inner {
+            span.set_tag(|| Tag::new("error", true));
+        }
+    }
+}
+
+/// Starts the spans of the requests, sending the sampled ones to a Jaeger
+/// agent if configured in `[tracing]`. Without it the trace context is still
+/// propagated, but nothing is reported.
+pub struct Tracing {
+    tracer: Option<Tracer>,
+    sample_rate: f64,
+}
+
+impl Tracing {
+    pub fn new() -> Tracing {
+        Self::from_config(&CONFIG)
+    }
+
+    pub fn from_config(config: &Config) -> Tracing {
+        match config.tracing {
+            Some(ref tracing) => {
+                let (tracer, spans) = Tracer::new(AllSampler);
+
+                let service_name = tracing.service_name
+                    .clone()
+                    .unwrap_or_else(|| String::from(DEFAULT_SERVICE_NAME));
+
+                // Validated with the configuration, so only reached with a
+                // configuration that wasn't loaded from a file
+                let agent = match tracing.agent_address.parse() {
+                    Ok(agent) => agent,
+                    Err(_) => {
+                        error!(*GLOG, "Invalid tracing agent address {}", tracing.agent_address);
+                        return Tracing { tracer: None, sample_rate: tracing.sample_rate }
+                    }
+                };
+
+                thread::Builder::new()
+                    .name(String::from("trace-reporter"))
+                    .spawn(move || Self::report(&service_name, agent, spans))
+                    .expect("Couldn't start the trace reporter thread");
+
+                Tracing { tracer: Some(tracer), sample_rate: tracing.sample_rate }
+            },
+            None => Tracing { tracer: None, sample_rate: 0.0 },
+        }
+    }
+
+    /// Starts the span of an incoming request. The caller's trace is
+    /// continued, but its sampled flag is only followed from a trusted proxy.
+    /// Other callers get the configured sample rate, so a client can't make
+    /// every request of it traced.
+    pub fn request_span(
+        &self,
+        name: &'static str,
+        parent: Option<&TraceContext>,
+        trusted: bool,
+    ) -> Span
+    {
+        let parent = parent.map(|parent| {
+            if trusted {
+                *parent
+            } else {
+                TraceContext { sampled: self.sample(), ..*parent }
+            }
+        });
+
+        self.span(name, parent.as_ref())
+    }
+
+    /// Starts a span as a child of the parent, or a new trace without one.
+    /// A child is sampled if the parent was, a new trace with the configured
+    /// sample rate.
+    pub fn span(&self, name: &'static str, parent: Option<&TraceContext>) -> Span {
+        let sampled = match parent {
+            Some(parent) => parent.sampled,
+            None => self.sample(),
+        };
+
+        let inner = if sampled { self.start(name, parent) } else { None };
+
+        let context = inner
+            .as_ref()
+            .and_then(|span| span.context())
+            .map(|context| {
+                let state = context.state();
+                let trace_id = state.trace_id();
+
+                TraceContext {
+                    trace_id: (u128::from(trace_id.high) << 64) | u128::from(trace_id.low),
+                    span_id: state.span_id(),
+                    sampled: true,
+                }
+            })
+            .unwrap_or_else(|| TraceContext::random(parent, sampled));
+
+        Span { context, inner }
+    }
+
+    fn sample(&self) -> bool {
+        thread_rng().gen::<f64>() < self.sample_rate
+    }
+
+    fn start(
+        &self,
+        name: &'static str,
+        parent: Option<&TraceContext>,
+    ) -> Option<rustracing_jaeger::Span>
+    {
+        let parent = parent.and_then(|p| p.jaeger_context());
+        let tracer = self.tracer.as_ref()?;
+
+        let span = match parent {
+            Some(ref parent) => tracer.span(name).child_of(parent).start(),
+            None => tracer.span(name).start(),
+        };
+
+        Some(span)
+    }
+
+    fn report(service_name: &str, agent: SocketAddr, spans: SpanReceiver) {
+        let mut reporter = match JaegerCompactReporter::new(service_name) {
+            Ok(reporter) => reporter,
+            Err(e) => {
+                error!(*GLOG, "Couldn't start the trace reporter: {}", e);
+                return
+            }
+        };
+
+        reporter.set_agent_addr(agent);
+
+        while let Ok(span) = spans.recv() {
+            let mut batch = vec![span];
+
+            while batch.len() < REPORT_BATCH_SIZE {
+                match spans.try_recv() {
+                    Ok(span) => batch.push(span),
+                    Err(_) => break,
+                }
+            }
+
+            if let Err(e) = reporter.report(&batch) {
+                warn!(*GLOG, "Couldn't report {} span(s): {}", batch.len(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::HeaderValue;
+
+    const TRACEPARENT_VALUE: &'static str =
+        "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn test_parse_and_format() {
+        let context = TraceContext::parse(TRACEPARENT_VALUE).unwrap();
+
+        assert_eq!(0x0af7651916cd43dd8448eb211c80319c, context.trace_id);
+        assert_eq!(0xb7ad6b7169203331, context.span_id);
+        assert!(context.sampled);
+        assert_eq!(TRACEPARENT_VALUE, context.traceparent());
+        assert_eq!("0af7651916cd43dd8448eb211c80319c", context.trace_id_hex());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let invalid = [
+            "",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+            "00-0af7651916cd43dd8448eb211c8031-b7ad6b7169203331-01",
+        ];
+
+        for value in invalid.iter() {
+            assert_eq!(None, TraceContext::parse(value), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_parse_future_version() {
+        let value = "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00-extra";
+        let context = TraceContext::parse(value).unwrap();
+
+        assert!(!context.sampled);
+    }
+
+    #[test]
+    fn test_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, TraceContext::from_headers(&headers));
+
+        headers.insert(TRACEPARENT, HeaderValue::from_static(TRACEPARENT_VALUE));
+        assert!(TraceContext::from_headers(&headers).is_some());
+    }
+
+    #[test]
+    fn test_child_span_without_agent() {
+        let tracing = Tracing { tracer: None, sample_rate: 0.0 };
+        let parent = TraceContext::parse(TRACEPARENT_VALUE).unwrap();
+        let span = tracing.span("validate", Some(&parent));
+
+        assert_eq!(parent.trace_id, span.context().trace_id);
+        assert_ne!(parent.span_id, span.context().span_id);
+        assert!(span.context().sampled);
+    }
+
+    #[test]
+    fn test_untrusted_sampled_flag() {
+        let tracing = Tracing { tracer: None, sample_rate: 0.0 };
+        let parent = TraceContext::parse(TRACEPARENT_VALUE).unwrap();
+
+        let untrusted = tracing.request_span("sdk_request", Some(&parent), false).context();
+        let trusted = tracing.request_span("sdk_request", Some(&parent), true).context();
+
+        assert_eq!(parent.trace_id, untrusted.trace_id);
+        assert!(!untrusted.sampled);
+        assert!(trusted.sampled);
+    }
+
+    #[test]
+    fn test_new_trace_sampling() {
+        let never = Tracing { tracer: None, sample_rate: 0.0 };
+        let always = Tracing { tracer: None, sample_rate: 1.0 };
+
+        assert!(!never.span("sdk_request", None).context().sampled);
+        assert!(always.span("sdk_request", None).context().sampled);
+
+        let first = never.span("sdk_request", None).context();
+        let second = never.span("sdk_request", None).context();
+
+        assert_ne!(first.trace_id, second.trace_id);
+    }
+}
@@ -0,0 +1,298 @@
+use std::{collections::HashMap, sync::RwLock};
+use uaparser::{self, Parser};
+use config::Config;
+
+use ::CONFIG;
+
+/// User agents kept parsed, if not set in `[user_agent]`.
+const DEFAULT_CACHE_SIZE: usize = 10_000;
+
+/// Longer user agents are parsed every time, not cached.
+const MAX_CACHED_LENGTH: usize = 512;
+
+/// The family the database gives when nothing matches.
+const OTHER: &'static str = "Other";
+
+/// The device family of the crawlers in the database.
+const SPIDER: &'static str = "Spider";
+
+/// Browser families of automation, flagged as bots with the crawlers.
+const AUTOMATED_BROWSERS: [&'static str; 2] = ["HeadlessChrome", "PhantomJS"];
+
+/// Operating systems of phones and tablets.
+const MOBILE_OS: [&'static str; 3] = ["iOS", "Android", "Windows Phone"];
+
+/// What kind of a device the user agent is from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceType {
+    Desktop,
+    Mobile,
+    Tablet,
+    Bot,
+}
+
+impl DeviceType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceType::Desktop => "desktop",
+            DeviceType::Mobile => "mobile",
+            DeviceType::Tablet => "tablet",
+            DeviceType::Bot => "bot",
+        }
+    }
+}
+
+/// A parsed user agent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserAgent {
+    /// The user agent as sent.
+    pub raw: String,
+    pub browser_name: Option<String>,
+    pub browser_version: Option<String>,
+    pub os_name: Option<String>,
+    pub os_version: Option<String>,
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    pub device_type: DeviceType,
+    /// A known crawler or an automated browser.
+    pub bot: bool,
+}
+
+impl UserAgent {
+    /// The fields without a place in the device proto, as message headers.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("ua_device_type", String::from(self.device_type.as_str())),
+            ("ua_bot", self.bot.to_string()),
+        ]
+    }
+}
+
+/// The family, `None` if the database didn't know it.
+fn family(family: String) -> Option<String> {
+    if family == OTHER || family.is_empty() { None } else { Some(family) }
+}
+
+/// The version from its parts, as far as they're known.
+fn version(parts: &[Option<String>]) -> Option<String> {
+    let parts: Vec<&str> = parts
+        .iter()
+        .take_while(|part| part.is_some())
+        .filter_map(|part| part.as_ref().map(|p| p.as_str()))
+        .collect();
+
+    if parts.is_empty() { None } else { Some(parts.join(".")) }
+}
+
+fn device_type(raw: &str, os_name: Option<&str>, model: Option<&str>, bot: bool) -> DeviceType {
+    let mobile_os = os_name.map(|os| MOBILE_OS.contains(&os)).unwrap_or(false);
+    let android_tablet = os_name == Some("Android") && !raw.contains("Mobile");
+
+    if bot {
+        DeviceType::Bot
+    } else if model == Some("iPad") || raw.contains("Tablet") || android_tablet {
+        DeviceType::Tablet
+    } else if mobile_os || raw.contains("Mobile") {
+        DeviceType::Mobile
+    } else {
+        DeviceType::Desktop
+    }
+}
+
+/// Parses the user agents of web devices with a uap-core `regexes.yaml`
+/// database, configured in `[user_agent]`. The results are cached; when the
+/// cache is full it's emptied.
+pub struct UserAgents {
+    parser: uaparser::UserAgentParser,
+    cache: RwLock<HashMap<String, UserAgent>>,
+    cache_size: usize,
+}
+
+impl UserAgents {
+    pub fn new() -> Option<UserAgents> {
+        Self::from_config(&CONFIG)
+    }
+
+    pub fn from_config(config: &Config) -> Option<UserAgents> {
+        config.user_agent.as_ref().map(|user_agent| {
+            Self::open(&user_agent.database, user_agent.cache_size.unwrap_or(DEFAULT_CACHE_SIZE))
+                .unwrap_or_else(|e| panic!("{}", e))
+        })
+    }
+
+    fn open(path: &str, cache_size: usize) -> Result<UserAgents, String> {
+        let parser = uaparser::UserAgentParser::from_yaml(path)
+            .map_err(|e| format!("Couldn't open user agent database {}: {:?}", path, e))?;
+
+        Ok(UserAgents {
+            parser,
+            cache: RwLock::new(HashMap::new()),
+            cache_size,
+        })
+    }
+
+    pub fn parse(&self, raw: &str) -> UserAgent {
+        if let Some(parsed) = self.cache.read().unwrap().get(raw) {
+            return parsed.clone()
+        }
+
+        let parsed = self.parse_uncached(raw);
+
+        if raw.len() <= MAX_CACHED_LENGTH {
+            let mut cache = self.cache.write().unwrap();
+
+            if cache.len() >= self.cache_size {
+                cache.clear();
+            }
+
+            cache.insert(raw.to_string(), parsed.clone());
+        }
+
+        parsed
+    }
+
+    fn parse_uncached(&self, raw: &str) -> UserAgent {
+        let client = self.parser.parse(raw);
+        let user_agent = client.user_agent;
+        let os = client.os;
+        let device = client.device;
+
+        let browser_name = family(user_agent.family.to_string());
+        let os_name = family(os.family.to_string());
+        let device_family = family(device.family.to_string());
+
+        let bot = device_family.as_ref().map(|f| f == SPIDER).unwrap_or(false)
+            || browser_name.as_ref().map(|b| AUTOMATED_BROWSERS.contains(&b.as_str())).unwrap_or(false);
+
+        let (vendor, model) = if bot {
+            (None, None)
+        } else {
+            (
+                device.brand.as_ref().map(|b| b.to_string()),
+                device.model.as_ref().map(|m| m.to_string()),
+            )
+        };
+
+        let browser_version = version(&[
+            user_agent.major.as_ref().map(|v| v.to_string()),
+            user_agent.minor.as_ref().map(|v| v.to_string()),
+            user_agent.patch.as_ref().map(|v| v.to_string()),
+        ]);
+
+        let os_version = version(&[
+            os.major.as_ref().map(|v| v.to_string()),
+            os.minor.as_ref().map(|v| v.to_string()),
+            os.patch.as_ref().map(|v| v.to_string()),
+        ]);
+
+        let device_type = device_type(
+            raw,
+            os_name.as_ref().map(|o| o.as_str()),
+            model.as_ref().map(|m| m.as_str()),
+            bot,
+        );
+
+        UserAgent {
+            raw: raw.to_string(),
+            browser_name,
+            browser_version,
+            os_name,
+            os_version,
+            vendor,
+            model,
+            device_type,
+            bot,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATABASE: &'static str = "./resources/test/user_agents.yaml";
+
+    const CHROME_WINDOWS: &'static str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+        (KHTML, like Gecko) Chrome/74.0.3729.169 Safari/537.36";
+
+    const SAFARI_IPHONE: &'static str = "Mozilla/5.0 (iPhone; CPU iPhone OS 12_1 like Mac OS X) \
+        AppleWebKit/605.1.15 (KHTML, like Gecko) Version/12.0 Mobile/15E148 Safari/604.1";
+
+    const CHROME_SAMSUNG: &'static str = "Mozilla/5.0 (Linux; Android 9; SM-G960F) AppleWebKit/537.36 \
+        (KHTML, like Gecko) Chrome/74.0.3729.157 Mobile Safari/537.36";
+
+    const GOOGLEBOT: &'static str = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+
+    fn user_agents() -> UserAgents {
+        UserAgents::open(DATABASE, 2).unwrap()
+    }
+
+    #[test]
+    fn test_desktop() {
+        let parsed = user_agents().parse(CHROME_WINDOWS);
+
+        assert_eq!(Some(String::from("Chrome")), parsed.browser_name);
+        assert_eq!(Some(String::from("74.0.3729")), parsed.browser_version);
+        assert_eq!(Some(String::from("Windows")), parsed.os_name);
+        assert_eq!(Some(String::from("10")), parsed.os_version);
+        assert_eq!(None, parsed.vendor);
+        assert_eq!(DeviceType::Desktop, parsed.device_type);
+        assert!(!parsed.bot);
+    }
+
+    #[test]
+    fn test_mobile() {
+        let user_agents = user_agents();
+
+        let iphone = user_agents.parse(SAFARI_IPHONE);
+
+        assert_eq!(Some(String::from("Mobile Safari")), iphone.browser_name);
+        assert_eq!(Some(String::from("12.0")), iphone.browser_version);
+        assert_eq!(Some(String::from("iOS")), iphone.os_name);
+        assert_eq!(Some(String::from("Apple")), iphone.vendor);
+        assert_eq!(DeviceType::Mobile, iphone.device_type);
+
+        let samsung = user_agents.parse(CHROME_SAMSUNG);
+
+        assert_eq!(Some(String::from("Android")), samsung.os_name);
+        assert_eq!(Some(String::from("9")), samsung.os_version);
+        assert_eq!(Some(String::from("Samsung")), samsung.vendor);
+        assert_eq!(Some(String::from("SM-G960F")), samsung.model);
+        assert_eq!(DeviceType::Mobile, samsung.device_type);
+    }
+
+    #[test]
+    fn test_bot() {
+        let parsed = user_agents().parse(GOOGLEBOT);
+
+        assert!(parsed.bot);
+        assert_eq!(DeviceType::Bot, parsed.device_type);
+        assert_eq!(None, parsed.vendor);
+        assert_eq!(
+            vec![("ua_device_type", String::from("bot")), ("ua_bot", String::from("true"))],
+            parsed.headers()
+        );
+    }
+
+    #[test]
+    fn test_unknown() {
+        let parsed = user_agents().parse("kulli");
+
+        assert_eq!(None, parsed.browser_name);
+        assert_eq!(None, parsed.os_name);
+        assert_eq!(DeviceType::Desktop, parsed.device_type);
+        assert!(!parsed.bot);
+    }
+
+    #[test]
+    fn test_cache_is_bounded() {
+        let user_agents = user_agents();
+
+        for raw in [CHROME_WINDOWS, SAFARI_IPHONE, GOOGLEBOT].iter() {
+            user_agents.parse(raw);
+        }
+
+        assert!(user_agents.cache.read().unwrap().len() <= 2);
+        assert_eq!(user_agents.parse_uncached(GOOGLEBOT), user_agents.parse(GOOGLEBOT));
+    }
+}